@@ -1,4 +1,6 @@
+use dasp_sample::ToSample;
 use std;
+use std::iter::StepBy;
 use std::ops::{Deref, DerefMut};
 
 /// An interleaved PCM buffer yielded by either an input or output stream processing function.
@@ -9,6 +11,28 @@ pub struct Buffer<S = f32> {
     pub(crate) sample_rate: u32,
 }
 
+/// A borrowed slice of interleaved samples, with the helpers `Buffer` has for working with its
+/// channels.
+///
+/// Any samples after the last whole frame are ignored.
+#[derive(Clone, Copy, Debug)]
+pub struct InterleavedSlice<'a, S = f32> {
+    samples: &'a [S],
+    channels: usize,
+}
+
+/// An iterator yielding each sample of one channel, without copying them out of the buffer.
+#[derive(Clone, Debug)]
+pub struct Channel<'a, S: 'a> {
+    samples: StepBy<std::slice::Iter<'a, S>>,
+}
+
+/// An iterator yielding a mutable reference to each sample of one channel.
+#[derive(Debug)]
+pub struct ChannelMut<'a, S: 'a> {
+    samples: StepBy<std::slice::IterMut<'a, S>>,
+}
+
 /// An iterator yielding each frame in some Buffer.
 ///
 /// A "frame" is a sample from each channel of audio at a single moment in time.
@@ -57,6 +81,234 @@ impl<S> Buffer<S> {
         let chunks = self.interleaved_samples.chunks_mut(self.channels);
         FramesMut { chunks }
     }
+
+    /// The buffer's samples as an `InterleavedSlice`.
+    pub fn interleaved(&self) -> InterleavedSlice<'_, S> {
+        InterleavedSlice::new(&self.interleaved_samples, self.channels)
+    }
+
+    /// Each sample of the given channel in order. See `InterleavedSlice::channel`.
+    pub fn channel(&self, channel: usize) -> Channel<'_, S> {
+        self.interleaved().channel(channel)
+    }
+
+    /// A mutable reference to each sample of the given channel in order.
+    ///
+    /// **Panics** if `channel` is not less than the number of channels.
+    pub fn channel_mut(&mut self, channel: usize) -> ChannelMut<'_, S> {
+        assert!(channel < self.channels, "channel {} out of range", channel);
+        let samples = match self.interleaved_samples.get_mut(channel..) {
+            Some(samples) => samples.iter_mut(),
+            None => [].iter_mut(),
+        };
+        ChannelMut {
+            samples: samples.step_by(self.channels),
+        }
+    }
+
+    /// Copy each channel into its own buffer. See `InterleavedSlice::deinterleave_into`.
+    pub fn deinterleave_into<P>(&self, planar: &mut [P]) -> usize
+    where
+        S: Copy,
+        P: AsMut<[S]>,
+    {
+        self.interleaved().deinterleave_into(planar)
+    }
+
+    /// Mix all channels down to one. See `InterleavedSlice::mix_mono_into`.
+    pub fn mix_mono_into(&self, mono: &mut [f32]) -> usize
+    where
+        S: Copy + ToSample<f32>,
+    {
+        self.interleaved().mix_mono_into(mono)
+    }
+
+    /// Mix the first two channels down to mid and side. See `InterleavedSlice::mix_mid_side_into`.
+    pub fn mix_mid_side_into(&self, mid: &mut [f32], side: &mut [f32]) -> usize
+    where
+        S: Copy + ToSample<f32>,
+    {
+        self.interleaved().mix_mid_side_into(mid, side)
+    }
+
+    /// The largest absolute value in the given channel. See `InterleavedSlice::peak`.
+    pub fn peak(&self, channel: usize) -> f32
+    where
+        S: Copy + ToSample<f32>,
+    {
+        self.interleaved().peak(channel)
+    }
+
+    /// The root mean square of the given channel. See `InterleavedSlice::rms`.
+    pub fn rms(&self, channel: usize) -> f32
+    where
+        S: Copy + ToSample<f32>,
+    {
+        self.interleaved().rms(channel)
+    }
+
+    /// Convert the samples to another format. See `InterleavedSlice::convert_into`.
+    pub fn convert_into<T>(&self, out: &mut [T]) -> usize
+    where
+        S: Copy + ToSample<T>,
+    {
+        self.interleaved().convert_into(out)
+    }
+}
+
+impl<'a, S> InterleavedSlice<'a, S> {
+    /// Treat `samples` as frames of `channels` interleaved samples.
+    ///
+    /// **Panics** if `channels` is `0`.
+    pub fn new(samples: &'a [S], channels: usize) -> Self {
+        assert!(channels > 0);
+        let whole_frames = samples.len() / channels * channels;
+        InterleavedSlice {
+            samples: &samples[..whole_frames],
+            channels,
+        }
+    }
+
+    /// The interleaved samples, whole frames only.
+    pub fn samples(&self) -> &'a [S] {
+        self.samples
+    }
+
+    /// The number of channels of audio per-frame.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// The length of the slice as a number of audio frames.
+    pub fn len_frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    /// Produce an iterator yielding each frame in order.
+    pub fn frames(&self) -> Frames<'a, S> {
+        let chunks = self.samples.chunks(self.channels);
+        Frames { chunks }
+    }
+
+    /// Each sample of the given channel in order, read in place by stepping over the others.
+    ///
+    /// **Panics** if `channel` is not less than the number of channels.
+    pub fn channel(&self, channel: usize) -> Channel<'a, S> {
+        assert!(channel < self.channels, "channel {} out of range", channel);
+        let samples = self.samples.get(channel..).unwrap_or(&[]);
+        Channel {
+            samples: samples.iter().step_by(self.channels),
+        }
+    }
+
+    /// Copy each channel into its own buffer, e.g. a `Vec` per channel allocated up front.
+    ///
+    /// Copies as many frames as fit in the shortest of the `planar` buffers and returns that
+    /// number. The rest of each buffer is left as it was.
+    ///
+    /// **Panics** if there isn't exactly one buffer per channel.
+    pub fn deinterleave_into<P>(&self, planar: &mut [P]) -> usize
+    where
+        S: Copy,
+        P: AsMut<[S]>,
+    {
+        assert_eq!(planar.len(), self.channels, "one buffer per channel");
+        let len_frames = planar
+            .iter_mut()
+            .map(|channel| channel.as_mut().len())
+            .fold(self.len_frames(), usize::min);
+        for (channel, out) in planar.iter_mut().enumerate() {
+            let out = &mut out.as_mut()[..len_frames];
+            for (out, &sample) in out.iter_mut().zip(self.channel(channel)) {
+                *out = sample;
+            }
+        }
+        len_frames
+    }
+
+    /// Mix all channels down to one by averaging each frame.
+    ///
+    /// Writes as many frames as fit in `mono` and returns that number.
+    pub fn mix_mono_into(&self, mono: &mut [f32]) -> usize
+    where
+        S: Copy + ToSample<f32>,
+    {
+        let scale = 1.0 / self.channels as f32;
+        let mut len_frames = 0;
+        for (out, frame) in mono.iter_mut().zip(self.frames()) {
+            *out = frame.iter().map(|&s| s.to_sample_()).sum::<f32>() * scale;
+            len_frames += 1;
+        }
+        len_frames
+    }
+
+    /// Mix the first two channels, left and right, down to mid `(l + r) / 2` and side
+    /// `(l - r) / 2`. Any other channels are ignored. With a single channel it's all mid and the
+    /// side is silent.
+    ///
+    /// Writes as many frames as fit in both `mid` and `side` and returns that number.
+    pub fn mix_mid_side_into(&self, mid: &mut [f32], side: &mut [f32]) -> usize
+    where
+        S: Copy + ToSample<f32>,
+    {
+        let mut len_frames = 0;
+        let outs = mid.iter_mut().zip(side.iter_mut());
+        for ((mid, side), frame) in outs.zip(self.frames()) {
+            let left: f32 = frame[0].to_sample_();
+            let right: f32 = frame.get(1).map_or(left, |&s| s.to_sample_());
+            *mid = (left + right) * 0.5;
+            *side = (left - right) * 0.5;
+            len_frames += 1;
+        }
+        len_frames
+    }
+
+    /// The largest absolute value in the given channel, `1.0` being full scale. `0.0` if empty.
+    ///
+    /// **Panics** if `channel` is not less than the number of channels.
+    pub fn peak(&self, channel: usize) -> f32
+    where
+        S: Copy + ToSample<f32>,
+    {
+        self.channel(channel)
+            .map(|&s| s.to_sample_().abs())
+            .fold(0.0, f32::max)
+    }
+
+    /// The root mean square of the given channel, `1.0` being full scale. `0.0` if empty.
+    ///
+    /// **Panics** if `channel` is not less than the number of channels.
+    pub fn rms(&self, channel: usize) -> f32
+    where
+        S: Copy + ToSample<f32>,
+    {
+        let len_frames = self.len_frames();
+        if len_frames == 0 {
+            return 0.0;
+        }
+        let sum_squares: f64 = self
+            .channel(channel)
+            .map(|&s| {
+                let s = s.to_sample_() as f64;
+                s * s
+            })
+            .sum();
+        (sum_squares / len_frames as f64).sqrt() as f32
+    }
+
+    /// Convert the samples to another format, e.g. `i16` to `f32`, keeping them interleaved.
+    ///
+    /// Converts as many samples as fit in `out` and returns that number.
+    pub fn convert_into<T>(&self, out: &mut [T]) -> usize
+    where
+        S: Copy + ToSample<T>,
+    {
+        let len = out.len().min(self.samples.len());
+        for (out, &sample) in out.iter_mut().zip(self.samples) {
+            *out = sample.to_sample_();
+        }
+        len
+    }
 }
 
 impl<S> Deref for Buffer<S> {
@@ -115,3 +367,205 @@ impl<'a, S> DoubleEndedIterator for FramesMut<'a, S> {
         self.chunks.next_back()
     }
 }
+
+impl<'a, S> Iterator for Channel<'a, S> {
+    type Item = &'a S;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.samples.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.samples.size_hint()
+    }
+}
+
+impl<'a, S> Iterator for ChannelMut<'a, S> {
+    type Item = &'a mut S;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.samples.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.samples.size_hint()
+    }
+}
+
+impl<'a, S> ExactSizeIterator for Channel<'a, S> {
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+impl<'a, S> ExactSizeIterator for ChannelMut<'a, S> {
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+impl<'a, S> DoubleEndedIterator for Channel<'a, S> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.samples.next_back()
+    }
+}
+
+impl<'a, S> DoubleEndedIterator for ChannelMut<'a, S> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.samples.next_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_of_one() {
+        let samples = [1, 2, 3];
+        let slice = InterleavedSlice::new(&samples, 1);
+        assert_eq!(slice.len_frames(), 3);
+        assert_eq!(slice.channel(0).copied().collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(slice.frames().collect::<Vec<_>>(), [[1], [2], [3]]);
+    }
+
+    #[test]
+    fn channels_of_three() {
+        let samples = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let slice = InterleavedSlice::new(&samples, 3);
+        assert_eq!(slice.len_frames(), 3);
+        assert_eq!(slice.channel(0).copied().collect::<Vec<_>>(), [1, 4, 7]);
+        assert_eq!(slice.channel(1).copied().collect::<Vec<_>>(), [2, 5, 8]);
+        assert_eq!(slice.channel(2).copied().collect::<Vec<_>>(), [3, 6, 9]);
+        assert_eq!(
+            slice.channel(2).rev().copied().collect::<Vec<_>>(),
+            [9, 6, 3]
+        );
+        assert_eq!(slice.channel(1).len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "channel 3 out of range")]
+    fn channel_out_of_range() {
+        InterleavedSlice::new(&[0.0; 6], 3).channel(3);
+    }
+
+    #[test]
+    fn ignores_a_trailing_partial_frame() {
+        let samples = [1, 2, 3, 4, 5, 6, 7, 8];
+        let slice = InterleavedSlice::new(&samples, 3);
+        assert_eq!(slice.len_frames(), 2);
+        assert_eq!(slice.samples(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(slice.channel(1).copied().collect::<Vec<_>>(), [2, 5]);
+        assert_eq!(slice.frames().count(), 2);
+
+        let mut out = [0; 8];
+        assert_eq!(slice.convert_into(&mut out), 6);
+        assert_eq!(out, [1, 2, 3, 4, 5, 6, 0, 0]);
+        let mut mono = [0.0f32; 4];
+        let floats = [0.5f32, 0.5, 0.5, 1.0];
+        assert_eq!(
+            InterleavedSlice::new(&floats, 3).mix_mono_into(&mut mono),
+            1
+        );
+        // only the whole frame counts towards the level
+        assert_eq!(InterleavedSlice::new(&floats, 3).peak(0), 0.5);
+        assert_eq!(InterleavedSlice::new(&floats, 3).rms(0), 0.5);
+    }
+
+    #[test]
+    fn deinterleaves_up_to_the_shortest_buffer() {
+        let samples = [1, 10, 2, 20, 3, 30, 4, 40];
+        let slice = InterleavedSlice::new(&samples, 2);
+        let mut planar = [vec![0; 4], vec![0; 2]];
+        assert_eq!(slice.deinterleave_into(&mut planar), 2);
+        assert_eq!(planar[0], [1, 2, 0, 0]);
+        assert_eq!(planar[1], [10, 20]);
+
+        // and up to the frames there are, when that's fewer
+        let mut planar = [vec![0; 6], vec![0; 6]];
+        assert_eq!(slice.deinterleave_into(&mut planar), 4);
+        assert_eq!(planar[1], [10, 20, 30, 40, 0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "one buffer per channel")]
+    fn deinterleave_needs_a_buffer_per_channel() {
+        let mut planar = [vec![0.0f32; 4]];
+        InterleavedSlice::new(&[0.0f32; 4], 2).deinterleave_into(&mut planar);
+    }
+
+    #[test]
+    fn mixes_down() {
+        let samples = [1.0f32, 0.0, 0.5, -0.5, -1.0, -1.0];
+        let slice = InterleavedSlice::new(&samples, 2);
+        let mut mono = [9.0; 4];
+        assert_eq!(slice.mix_mono_into(&mut mono), 3);
+        assert_eq!(mono, [0.5, 0.0, -1.0, 9.0]);
+
+        let (mut mid, mut side) = ([0.0; 3], [0.0; 2]);
+        assert_eq!(slice.mix_mid_side_into(&mut mid, &mut side), 2);
+        assert_eq!(mid, [0.5, 0.0, 0.0]);
+        assert_eq!(side, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn mono_is_all_mid() {
+        let samples = [0.25f32, -0.5, 1.0];
+        let slice = InterleavedSlice::new(&samples, 1);
+        let (mut mid, mut side) = ([9.0; 3], [9.0; 3]);
+        assert_eq!(slice.mix_mid_side_into(&mut mid, &mut side), 3);
+        assert_eq!(mid, samples);
+        assert_eq!(side, [0.0; 3]);
+    }
+
+    #[test]
+    fn levels_of_nothing() {
+        let empty: [f32; 0] = [];
+        let slice = InterleavedSlice::new(&empty, 2);
+        assert_eq!(slice.len_frames(), 0);
+        assert_eq!(slice.peak(1), 0.0);
+        assert_eq!(slice.rms(1), 0.0);
+        // a partial frame is as good as empty
+        let slice = InterleavedSlice::new(&[1.0f32], 2);
+        assert_eq!(slice.peak(0), 0.0);
+        assert_eq!(slice.rms(0), 0.0);
+    }
+
+    #[test]
+    fn levels() {
+        let samples = [0.5f32, -1.0, -0.5, 0.0];
+        let slice = InterleavedSlice::new(&samples, 2);
+        assert_eq!(slice.peak(0), 0.5);
+        assert_eq!(slice.peak(1), 1.0);
+        assert_eq!(slice.rms(0), 0.5);
+        assert!((slice.rms(1) - 0.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn i16_to_f32_at_the_extremes() {
+        let samples = [i16::MIN, i16::MAX, 0, -1];
+        let mut out = [0.0f32; 4];
+        assert_eq!(InterleavedSlice::new(&samples, 2).convert_into(&mut out), 4);
+        assert_eq!(out[0], -1.0);
+        assert_eq!(out[1], i16::MAX as f32 / 32_768.0);
+        assert_eq!(out[2], 0.0);
+        assert_eq!(out[3], -1.0 / 32_768.0);
+        // full scale i16 is full scale f32 for the levels
+        assert_eq!(InterleavedSlice::new(&samples, 2).peak(0), 1.0);
+    }
+
+    #[test]
+    fn f32_to_i16_at_the_extremes() {
+        let samples = [-1.0f32, 1.0, 0.0, 1.5];
+        let mut out = [0i16; 4];
+        assert_eq!(InterleavedSlice::new(&samples, 1).convert_into(&mut out), 4);
+        assert_eq!(out[0], i16::MIN);
+        assert_eq!(out[1], i16::MAX);
+        assert_eq!(out[2], 0);
+        // out of range clips rather than wrapping
+        assert_eq!(out[3], i16::MAX);
+
+        // and back again
+        let mut back = [0.0f32; 4];
+        InterleavedSlice::new(&out, 1).convert_into(&mut back);
+        assert_eq!(back[0], -1.0);
+        assert!((back[1] - 1.0).abs() < 1.0 / 16_384.0);
+    }
+}
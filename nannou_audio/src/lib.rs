@@ -4,19 +4,23 @@
 //! - [**Stream**](./stream/struct.Stream.html) - for managing an input/output audio stream. This may be
 //!   created via the **App**'s **Audio** API.
 //! - [**Buffer**](./buffer/struct.Buffer.html) - contains audio data, either for reading or writing.
-//!   This is passed to the `capture` or `render` function for each stream.
+//!   This is passed to the `capture` or `render` function for each stream. An
+//!   [**InterleavedSlice**](./buffer/struct.InterleavedSlice.html) has the same per-channel helpers
+//!   for any other interleaved samples.
 //! - [**Devices**](./device/struct.Devices.html) - for enumerating all audio devices on the system.
 //! - [**Device**](./device/struct.Device.html) - for querying information about supported stream
 //!   formats or for creating a stream targeted towards a specific audio device.
 //! - [**Receiver**](./receiver/struct.Receiver.html) and
 //!   [**Requester**](./requester/struct.Requester.html) for buffering input and output streams that
 //!   may deliver buffers of inconsistent sizes into a stream of consistently sized buffers.
+//! - [**ring_buffer**](./ring_buffer/index.html) - for getting captured audio to another thread
+//!   without the capture function ever waiting on it.
 
 use cpal::traits::HostTrait;
 use std::marker::PhantomData;
 use std::sync::Arc;
 
-pub use self::buffer::Buffer;
+pub use self::buffer::{Buffer, InterleavedSlice};
 pub use self::device::{Device, Devices};
 pub use self::receiver::Receiver;
 pub use self::requester::Requester;
@@ -36,6 +40,7 @@ pub mod buffer;
 pub mod device;
 pub mod receiver;
 pub mod requester;
+pub mod ring_buffer;
 pub mod stream;
 
 /// The top-level audio API, for enumerating devices and spawning input/output streams.
@@ -126,6 +131,21 @@ impl Host {
         }
     }
 
+    /// Begin building a new duplex audio stream, capturing from an input device and rendering
+    /// to an output device with a single function.
+    ///
+    /// If this is the first time a stream has been created, this method will spawn the
+    /// `cpal::EventLoop::run` method on its own thread, ready to run built streams.
+    pub fn new_duplex_stream<M, S>(&self, model: M) -> stream::duplex::BuilderInit<M, S> {
+        stream::duplex::Builder {
+            duplex: stream::duplex::default_duplex_fn,
+            error: stream::default_error_fn,
+            input_device: None,
+            input_channels: None,
+            builder: self.new_stream(model),
+        }
+    }
+
     // Builder initialisation shared between input and output streams.
     //
     // If this is the first time a stream has been created, this method will spawn the
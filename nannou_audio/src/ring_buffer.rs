@@ -0,0 +1,401 @@
+//! A wait-free single-producer single-consumer ring buffer for getting captured audio out of an
+//! input stream's capture function without locking.
+//!
+//! The `CaptureWriter` is the input stream's model and the `CaptureReader` lives wherever the
+//! audio is analysed. The capture function never waits on the reader: if the ring is full because
+//! the reader has fallen behind, the newest frames are dropped and counted as an overrun.
+//!
+//! ```no_run
+//! use nannou_audio::ring_buffer::{self, CaptureWriter};
+//!
+//! let host = nannou_audio::Host::new();
+//! let (writer, mut reader) = ring_buffer::capture::<f32>(44_100, 2);
+//! let stream = host
+//!     .new_input_stream(writer)
+//!     .channels(2)
+//!     .capture(CaptureWriter::capture)
+//!     .build()
+//!     .unwrap();
+//! stream.play().unwrap();
+//!
+//! // On another thread: take what's been captured, then look at the last 1024 frames.
+//! reader.set_history(1024);
+//! reader.read(|_frame| {});
+//! let left = reader.latest(0, 1024);
+//! ```
+
+use crate::Buffer;
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// The ring, shared by the writer and the reader.
+//
+// `head` and `tail` count samples ever written and read, wrapping around. The writer only writes
+// the slots in `head..tail + capacity` and the reader only reads `tail..head`, so they never
+// touch the same slot at the same time. The capacity is a power of two so the slot a count
+// points to stays the same when it wraps.
+struct Shared<S> {
+    samples: Box<[UnsafeCell<S>]>,
+    channels: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    // Frames dropped because the ring was full.
+    overruns: AtomicUsize,
+}
+
+// Safe as the writer and the reader only ever access disjoint slots, see `Shared`.
+unsafe impl<S: Send> Sync for Shared<S> {}
+
+/// The capture end of the ring. Use it as the input stream's model, with `CaptureWriter::capture`
+/// as the capture function.
+pub struct CaptureWriter<S> {
+    shared: Arc<Shared<S>>,
+}
+
+/// The reading end of the ring. Keeps the most recent frames it has read, per channel.
+pub struct CaptureReader<S> {
+    shared: Arc<Shared<S>>,
+    history: Vec<VecDeque<S>>,
+    history_frames: usize,
+}
+
+/// Create a ring that holds at least `capacity_frames` frames of `channels` channels.
+///
+/// The capacity only needs to cover the longest the reader might go between reads, the history
+/// the reader keeps is sized separately with `CaptureReader::set_history`.
+pub fn capture<S>(capacity_frames: usize, channels: usize) -> (CaptureWriter<S>, CaptureReader<S>)
+where
+    S: Copy + Default,
+{
+    assert!(capacity_frames > 0);
+    assert!(channels > 0);
+    let samples = (0..(capacity_frames * channels).next_power_of_two())
+        .map(|_| UnsafeCell::new(S::default()))
+        .collect();
+    let shared = Arc::new(Shared {
+        samples,
+        channels,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        overruns: AtomicUsize::new(0),
+    });
+    let writer = CaptureWriter {
+        shared: shared.clone(),
+    };
+    let reader = CaptureReader {
+        shared,
+        history: vec![VecDeque::new(); channels],
+        history_frames: 0,
+    };
+    (writer, reader)
+}
+
+impl<S> CaptureWriter<S>
+where
+    S: Copy,
+{
+    /// A capture function that writes each buffer into the ring.
+    ///
+    /// The buffer must have the ring's number of channels, so set the stream's `channels` to
+    /// match. Buffers with any other number are dropped and counted as overruns.
+    pub fn capture(writer: &mut Self, buffer: &Buffer<S>) {
+        if buffer.channels() != writer.shared.channels {
+            writer
+                .shared
+                .overruns
+                .fetch_add(buffer.len_frames(), Ordering::Relaxed);
+            return;
+        }
+        writer.push(buffer);
+    }
+
+    /// Write interleaved samples into the ring, never waiting for the reader. Whatever doesn't
+    /// fit is dropped and counted as an overrun. Returns the number of frames written.
+    pub fn push(&mut self, samples: &[S]) -> usize {
+        let shared = &*self.shared;
+        let capacity = shared.samples.len();
+        let head = shared.head.load(Ordering::Relaxed);
+        let tail = shared.tail.load(Ordering::Acquire);
+        let free = capacity - head.wrapping_sub(tail);
+
+        // Whole frames only, so the reader never sees half of one.
+        let frames = samples.len() / shared.channels;
+        let written_frames = frames.min(free / shared.channels);
+        let written = written_frames * shared.channels;
+        for (i, &sample) in samples[..written].iter().enumerate() {
+            // Not visible to the reader until `head` is moved past it.
+            let slot = head.wrapping_add(i) & (capacity - 1);
+            unsafe { *shared.samples[slot].get() = sample };
+        }
+        shared
+            .head
+            .store(head.wrapping_add(written), Ordering::Release);
+
+        if written_frames < frames {
+            shared
+                .overruns
+                .fetch_add(frames - written_frames, Ordering::Relaxed);
+        }
+        written_frames
+    }
+}
+
+impl<S> CaptureReader<S>
+where
+    S: Copy,
+{
+    /// The number of channels in each frame.
+    pub fn channels(&self) -> usize {
+        self.shared.channels
+    }
+
+    /// The number of frames dropped so far because the ring was full when they were captured.
+    pub fn overruns(&self) -> usize {
+        self.shared.overruns.load(Ordering::Relaxed)
+    }
+
+    /// Keep the most recent `frames` frames of each channel for `latest`.
+    pub fn set_history(&mut self, frames: usize) {
+        self.history_frames = frames;
+        for channel in &mut self.history {
+            let excess = channel.len().saturating_sub(frames);
+            channel.drain(..excess);
+        }
+    }
+
+    /// Take everything captured since the last read, calling `each_frame` with every frame in
+    /// order and adding it to the history. Returns the number of frames read.
+    pub fn read<F>(&mut self, mut each_frame: F) -> usize
+    where
+        F: FnMut(&[S]),
+    {
+        let shared = &*self.shared;
+        let capacity = shared.samples.len();
+        let channels = shared.channels;
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let head = shared.head.load(Ordering::Acquire);
+
+        let available = head.wrapping_sub(tail);
+
+        let mut frame = Vec::with_capacity(channels);
+        for offset in (0..available).step_by(channels) {
+            let start = tail.wrapping_add(offset);
+            frame.clear();
+            // Published by the writer's store to `head`.
+            frame.extend((0..channels).map(|i| {
+                let slot = start.wrapping_add(i) & (capacity - 1);
+                unsafe { *shared.samples[slot].get() }
+            }));
+            each_frame(&frame);
+            for (history, &sample) in self.history.iter_mut().zip(&frame) {
+                if history.len() >= self.history_frames {
+                    history.pop_front();
+                }
+                if self.history_frames > 0 {
+                    history.push_back(sample);
+                }
+            }
+        }
+
+        // Hand the slots back to the writer.
+        shared.tail.store(head, Ordering::Release);
+        available / channels
+    }
+
+    /// The number of whole frames waiting to be read.
+    pub fn available_frames(&self) -> usize {
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let head = self.shared.head.load(Ordering::Acquire);
+        head.wrapping_sub(tail) / self.shared.channels
+    }
+
+    /// Sample `channel` of waiting frame `frame`, the oldest being 0, without taking it. For
+    /// readers that go through the frames at their own pace, like a resampler. `None` if there
+    /// aren't that many frames waiting.
+    pub fn peek(&self, frame: usize, channel: usize) -> Option<S> {
+        let shared = &*self.shared;
+        if frame >= self.available_frames() || channel >= shared.channels {
+            return None;
+        }
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let slot =
+            tail.wrapping_add(frame * shared.channels + channel) & (shared.samples.len() - 1);
+        // Published by the writer's store to `head`, which `available_frames` loaded.
+        Some(unsafe { *shared.samples[slot].get() })
+    }
+
+    /// Drop up to `frames` of the oldest waiting frames, without adding them to the history.
+    /// Returns the number of frames dropped.
+    pub fn skip(&mut self, frames: usize) -> usize {
+        let frames = frames.min(self.available_frames());
+        let shared = &*self.shared;
+        let tail = shared.tail.load(Ordering::Relaxed);
+        shared.tail.store(
+            tail.wrapping_add(frames * shared.channels),
+            Ordering::Release,
+        );
+        frames
+    }
+
+    /// The most recent `len` frames of `channel` as of the last `read`, oldest first. `None`
+    /// until that many have been read, or if `len` is more than the history kept.
+    pub fn latest(&self, channel: usize, len: usize) -> Option<Vec<S>> {
+        let history = self.history.get(channel)?;
+        let start = history.len().checked_sub(len)?;
+        Some(history.range(start..).copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Read everything waiting, as one interleaved Vec.
+    fn read_all<S: Copy>(reader: &mut CaptureReader<S>) -> Vec<S> {
+        let mut samples = vec![];
+        reader.read(|frame| samples.extend_from_slice(frame));
+        samples
+    }
+
+    #[test]
+    fn wraps_around_the_ring_and_the_counters() {
+        let (mut writer, mut reader) = capture::<u32>(4, 2);
+        // start the counts just short of overflowing
+        let start = usize::MAX - 4;
+        reader.shared.head.store(start, Ordering::Relaxed);
+        reader.shared.tail.store(start, Ordering::Relaxed);
+
+        let mut next = 0;
+        for _ in 0..10 {
+            let samples: Vec<u32> = (next..next + 6).collect();
+            assert_eq!(writer.push(&samples), 3);
+            assert_eq!(read_all(&mut reader), samples);
+            next += 6;
+        }
+        assert!(reader.shared.head.load(Ordering::Relaxed) < start);
+        assert_eq!(reader.overruns(), 0);
+    }
+
+    #[test]
+    fn counts_overruns_when_full() {
+        let (mut writer, mut reader) = capture::<f32>(4, 2);
+        assert_eq!(writer.push(&[1.0; 6]), 3);
+        // one frame of room left, the other two are dropped
+        assert_eq!(writer.push(&[2.0; 6]), 1);
+        assert_eq!(reader.overruns(), 2);
+        assert_eq!(writer.push(&[3.0; 2]), 0);
+        assert_eq!(reader.overruns(), 3);
+
+        // the newest frames are the ones that were dropped
+        assert_eq!(
+            read_all(&mut reader),
+            [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 2.0, 2.0]
+        );
+        assert_eq!(writer.push(&[4.0; 2]), 1);
+        assert_eq!(read_all(&mut reader), [4.0, 4.0]);
+        assert_eq!(reader.overruns(), 3);
+    }
+
+    #[test]
+    fn drops_a_partial_frame() {
+        let (mut writer, mut reader) = capture::<i16>(8, 2);
+        assert_eq!(writer.push(&[1, 2, 3, 4, 5]), 2);
+        assert_eq!(reader.available_frames(), 2);
+        assert_eq!(read_all(&mut reader), [1, 2, 3, 4]);
+        // the half frame isn't carried over into the next push
+        assert_eq!(writer.push(&[6, 7]), 1);
+        assert_eq!(read_all(&mut reader), [6, 7]);
+        assert_eq!(reader.overruns(), 0);
+    }
+
+    #[test]
+    fn three_channels_in_a_power_of_two_ring() {
+        // 5 frames of 3 channels rounds up to 16 samples, which isn't a whole number of frames
+        let (mut writer, mut reader) = capture::<u32>(5, 3);
+        assert_eq!(reader.shared.samples.len(), 16);
+
+        // frames end up straddling the end of the ring
+        let mut next = 0;
+        for _ in 0..20 {
+            let samples: Vec<u32> = (next..next + 12).collect();
+            assert_eq!(writer.push(&samples), 4);
+            let mut frames = vec![];
+            reader.read(|frame| frames.push(frame.to_vec()));
+            let expected: Vec<Vec<u32>> = samples.chunks(3).map(|f| f.to_vec()).collect();
+            assert_eq!(frames, expected);
+            next += 12;
+        }
+
+        // 16 samples only hold 5 whole frames
+        assert_eq!(writer.push(&[0; 18]), 5);
+        assert_eq!(reader.overruns(), 1);
+    }
+
+    #[test]
+    fn latest_after_shrinking_the_history() {
+        let (mut writer, mut reader) = capture::<u32>(16, 2);
+        reader.set_history(8);
+        let samples: Vec<u32> = (0..16).collect();
+        writer.push(&samples);
+        reader.read(|_| {});
+        assert_eq!(reader.latest(0, 8), Some(vec![0, 2, 4, 6, 8, 10, 12, 14]));
+        assert_eq!(reader.latest(1, 3), Some(vec![11, 13, 15]));
+
+        reader.set_history(3);
+        assert_eq!(reader.latest(0, 3), Some(vec![10, 12, 14]));
+        assert_eq!(reader.latest(0, 4), None);
+
+        // new frames push the oldest out of the shorter history
+        writer.push(&[16, 17]);
+        reader.read(|_| {});
+        assert_eq!(reader.latest(1, 3), Some(vec![13, 15, 17]));
+    }
+
+    #[test]
+    fn peek_and_skip() {
+        let (mut writer, mut reader) = capture::<u32>(8, 2);
+        writer.push(&[0, 1, 2, 3, 4, 5]);
+        assert_eq!(reader.peek(0, 1), Some(1));
+        assert_eq!(reader.peek(2, 0), Some(4));
+        assert_eq!(reader.peek(3, 0), None);
+        assert_eq!(reader.peek(0, 2), None);
+
+        assert_eq!(reader.skip(2), 2);
+        assert_eq!(reader.peek(0, 0), Some(4));
+        assert_eq!(reader.skip(5), 1);
+        assert_eq!(reader.available_frames(), 0);
+        // skipped frames don't go into the history
+        reader.set_history(4);
+        assert_eq!(reader.latest(0, 1), None);
+    }
+
+    #[test]
+    fn keeps_order_across_threads() {
+        const FRAMES: u64 = 100_000;
+        let (mut writer, mut reader) = capture::<u64>(64, 2);
+        let writing = thread::spawn(move || {
+            let mut next = 0;
+            while next < FRAMES {
+                // bursts of up to 7 frames, retrying whatever didn't fit
+                let frames = (FRAMES - next).min(next % 7 + 1);
+                let samples: Vec<u64> = (next..next + frames).flat_map(|f| [f, !f]).collect();
+                next += writer.push(&samples) as u64;
+                thread::yield_now();
+            }
+        });
+
+        let mut expected = 0;
+        while expected < FRAMES {
+            reader.read(|frame| {
+                assert_eq!(frame, [expected, !expected]);
+                expected += 1;
+            });
+            thread::yield_now();
+        }
+        writing.join().unwrap();
+        assert_eq!(reader.available_frames(), 0);
+    }
+}
@@ -0,0 +1,664 @@
+use crate::{
+    ring_buffer::{self, CaptureReader, CaptureWriter},
+    stream::{self, DefaultErrorFn, ErrorFn},
+    Buffer, Device, Requester, Stream,
+};
+use cpal::traits::{DeviceTrait, HostTrait};
+use dasp_sample::{FromSample, Sample, ToSample};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+
+/// The function that will be called with each captured `Buffer` and the `Buffer` to render in
+/// response. Both have the same number of frames, and the input is the audio captured at the
+/// time the output will be heard (minus the latency of the two devices).
+pub trait DuplexFn<M, S>: Fn(&mut M, &Buffer<S>, &mut Buffer<S>) {}
+
+/// The default duplex function type used when unspecified.
+pub type DefaultDuplexFn<M, S> = fn(&mut M, &Buffer<S>, &mut Buffer<S>);
+
+// The default duplex function used when unspecified.
+pub(crate) fn default_duplex_fn<M, S>(_: &mut M, _: &Buffer<S>, _: &mut Buffer<S>) {}
+
+/// A type used for building a duplex stream.
+///
+/// CPAL has no duplex streams, so this opens an input and an output stream at the same sample
+/// rate. The output stream drives the duplex function, taking the input from a buffer that the
+/// input stream fills. The two devices' clocks never quite agree, so the input is resampled
+/// very slightly faster or slower to keep that buffer from running dry or filling up.
+pub struct Builder<M, FD, FE, S = f32> {
+    /// Parameters of the output stream. The input uses the same sample rate, buffer sizes and
+    /// sample format.
+    pub builder: super::Builder<M, S>,
+    pub input_device: Option<Device>,
+    pub input_channels: Option<usize>,
+    pub duplex: FD,
+    pub error: FE,
+}
+
+/// The builder when first initialised.
+pub type BuilderInit<M, S = f32> = Builder<M, DefaultDuplexFn<M, S>, DefaultErrorFn<M>, S>;
+
+impl<M, S, F> DuplexFn<M, S> for F where F: Fn(&mut M, &Buffer<S>, &mut Buffer<S>) {}
+
+impl<M, FD, FE, S> Builder<M, FD, FE, S> {
+    /// Specify the function to use for rendering the output from the model and the input.
+    pub fn duplex<GD>(self, duplex: GD) -> Builder<M, GD, FE, S> {
+        let Builder {
+            builder,
+            input_device,
+            input_channels,
+            error,
+            ..
+        } = self;
+        Builder {
+            builder,
+            input_device,
+            input_channels,
+            duplex,
+            error,
+        }
+    }
+
+    /// Specify a function for handling errors on either stream.
+    pub fn error<GE>(self, error: GE) -> Builder<M, FD, GE, S> {
+        let Builder {
+            builder,
+            input_device,
+            input_channels,
+            duplex,
+            ..
+        } = self;
+        Builder {
+            builder,
+            input_device,
+            input_channels,
+            duplex,
+            error,
+        }
+    }
+
+    /// The sample rate of both streams.
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        assert!(sample_rate > 0);
+        self.builder.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// The number of output channels.
+    pub fn channels(mut self, channels: usize) -> Self {
+        assert!(channels > 0);
+        self.builder.channels = Some(channels);
+        self
+    }
+
+    /// The number of input channels.
+    pub fn input_channels(mut self, channels: usize) -> Self {
+        assert!(channels > 0);
+        self.input_channels = Some(channels);
+        self
+    }
+
+    /// The output device.
+    pub fn device(mut self, device: Device) -> Self {
+        self.builder.device = Some(device);
+        self
+    }
+
+    /// The input device.
+    pub fn input_device(mut self, device: Device) -> Self {
+        self.input_device = Some(device);
+        self
+    }
+
+    /// The number of frames in both buffers passed to the duplex function.
+    pub fn frames_per_buffer(mut self, frames_per_buffer: usize) -> Self {
+        assert!(frames_per_buffer > 0);
+        self.builder.frames_per_buffer = Some(frames_per_buffer);
+        self
+    }
+
+    /// The buffer size requested from both devices.
+    pub fn device_buffer_size(mut self, buffer_size: cpal::BufferSize) -> Self {
+        self.builder.device_buffer_size = Some(buffer_size);
+        self
+    }
+
+    pub fn build(self) -> std::result::Result<Stream<M>, super::BuildError>
+    where
+        S: 'static
+            + Send
+            + Sample
+            + ToSample<u16>
+            + ToSample<i16>
+            + ToSample<f32>
+            + FromSample<f32>,
+        M: 'static + Send,
+        FD: 'static + DuplexFn<M, S> + Send,
+        FE: 'static + ErrorFn<M> + Send + Sync,
+    {
+        let Builder {
+            duplex,
+            error,
+            input_device,
+            input_channels,
+            builder:
+                stream::Builder {
+                    host,
+                    model,
+                    sample_rate,
+                    channels,
+                    frames_per_buffer,
+                    device_buffer_size,
+                    device,
+                    ..
+                },
+        } = self;
+
+        let output_device = match device {
+            None => host
+                .default_output_device()
+                .ok_or(super::BuildError::DefaultDevice)?,
+            Some(Device { device }) => device,
+        };
+        let input_device = match input_device {
+            None => host
+                .default_input_device()
+                .ok_or(super::BuildError::DefaultDevice)?,
+            Some(Device { device }) => device,
+        };
+
+        // Find the best matching output config, then an input config at the same sample rate.
+        let desired = super::DesiredStreamConfig {
+            sample_format: super::cpal_sample_format::<S>(),
+            channels,
+            sample_rate: sample_rate.map(cpal::SampleRate),
+            device_buffer_size: device_buffer_size.clone(),
+        };
+        let output_matching = super::find_best_matching_config(
+            &output_device,
+            desired,
+            output_device.default_output_config().ok(),
+            |device| device.supported_output_configs().map(|fs| fs.collect()),
+        )?
+        .expect("no matching supported audio output formats for the target device");
+        let sample_rate = output_matching.config.sample_rate;
+        let desired = super::DesiredStreamConfig {
+            sample_format: super::cpal_sample_format::<S>(),
+            channels: input_channels,
+            sample_rate: Some(sample_rate),
+            device_buffer_size,
+        };
+        let input_matching = super::find_best_matching_config(
+            &input_device,
+            desired,
+            input_device.default_input_config().ok(),
+            |device| device.supported_input_configs().map(|fs| fs.collect()),
+        )?
+        .filter(|matching| matching.config.sample_rate == sample_rate)
+        .ok_or(super::BuildError::SampleRateMismatch {
+            sample_rate: sample_rate.0,
+        })?;
+
+        let (update_tx, update_rx) = mpsc::channel();
+        let model = Arc::new(Mutex::new(Some(model)));
+        let model_render = model.clone();
+        let error = Arc::new(error);
+        let num_channels = output_matching.config.channels as usize;
+        let num_input_channels = input_matching.config.channels as usize;
+        let sample_rate = sample_rate.0;
+        let output_sample_format = output_matching.sample_format;
+        let input_sample_format = input_matching.sample_format;
+        let stream_config: cpal::StreamConfig = output_matching.config.into();
+        let input_stream_config: cpal::StreamConfig = input_matching.config.into();
+
+        // A buffer for collecting model updates.
+        let mut pending_updates: Vec<Box<dyn FnMut(&mut M) + 'static + Send>> = Vec::new();
+
+        // Get the specified frames_per_buffer or fall back to a default.
+        let frames_per_buffer = frames_per_buffer.unwrap_or(Buffer::<S>::DEFAULT_LEN_FRAMES);
+
+        // Captured audio on its way from the input stream to the output stream. Neither side
+        // ever waits for the other.
+        let (mut drift_writer, drift) = drift_buffer(num_input_channels, frames_per_buffer);
+        let drift = RefCell::new(drift);
+
+        // The input stream only fills the drift buffer, converting to f32 for the resampler.
+        let mut captured: Vec<f32> = Vec::with_capacity(MAX_BURST * num_input_channels);
+        let capture_fn = move |data: &cpal::Data, _info: &cpal::InputCallbackInfo| {
+            fn read_input<I>(input: &[I], buffer: &mut Vec<f32>)
+            where
+                I: Sample + ToSample<f32>,
+            {
+                buffer.clear();
+                buffer.extend(input.iter().map(|sample| sample.to_sample::<f32>()));
+            }
+
+            match input_sample_format {
+                cpal::SampleFormat::U16 => {
+                    read_input(data.as_slice::<u16>().expect("expected u16 data"), &mut captured)
+                }
+                cpal::SampleFormat::I16 => {
+                    read_input(data.as_slice::<i16>().expect("expected i16 data"), &mut captured)
+                }
+                cpal::SampleFormat::F32 => {
+                    read_input(data.as_slice::<f32>().expect("expected f32 data"), &mut captured)
+                }
+            }
+
+            drift_writer.push(&captured);
+        };
+
+        // An audio requester which requests frames from the model+duplex pair with a
+        // specific buffer size, regardless of the buffer size requested by the OS.
+        let mut requester = Requester::new(frames_per_buffer, num_channels);
+
+        // An intermediary buffer for converting the samples to the output's format.
+        let mut samples = vec![S::EQUILIBRIUM; frames_per_buffer * num_channels];
+
+        // Scratch buffers for the input side of each duplex call, reused between calls.
+        let resampled = RefCell::new(vec![0.0f32; frames_per_buffer * num_input_channels]);
+        let input_samples = RefCell::new(Some(
+            vec![S::EQUILIBRIUM; frames_per_buffer * num_input_channels].into_boxed_slice(),
+        ));
+
+        // The output stream drives the duplex function.
+        let render_fn = move |data: &mut cpal::Data, _info: &cpal::OutputCallbackInfo| {
+            // Collect and process any pending updates.
+            pending_updates.extend(update_rx.try_iter());
+            if !pending_updates.is_empty() {
+                if let Ok(mut guard) = model_render.lock() {
+                    let mut model = guard.take().unwrap();
+                    for mut update in pending_updates.drain(..) {
+                        update(&mut model);
+                    }
+                    *guard = Some(model);
+                }
+            }
+
+            samples.clear();
+            samples.resize(data.len(), S::EQUILIBRIUM);
+
+            // Each output buffer gets the same number of frames of input.
+            let render = |model: &mut M, output: &mut Buffer<S>| {
+                let mut resampled = resampled.borrow_mut();
+                resampled.resize(output.len_frames() * num_input_channels, 0.0);
+                drift.borrow_mut().pull(&mut resampled);
+                let mut interleaved_samples = input_samples.borrow_mut().take().unwrap();
+                for (sample, &input) in interleaved_samples.iter_mut().zip(resampled.iter()) {
+                    *sample = S::from_sample(input);
+                }
+                let input = Buffer {
+                    interleaved_samples,
+                    channels: num_input_channels,
+                    sample_rate,
+                };
+                duplex(model, &input, output);
+                *input_samples.borrow_mut() = Some(input.interleaved_samples);
+            };
+
+            if let Ok(mut guard) = model_render.lock() {
+                let mut m = guard.take().unwrap();
+                m = requester.fill_buffer(m, &render, &mut samples, num_channels, sample_rate);
+                *guard = Some(m);
+            }
+
+            // A function to simplify filling the unknown buffer type.
+            fn fill_output<O, S>(output: &mut [O], buffer: &[S])
+            where
+                O: Sample,
+                S: Sample + ToSample<O>,
+            {
+                for (out_sample, sample) in output.iter_mut().zip(buffer) {
+                    *out_sample = sample.to_sample();
+                }
+            }
+
+            // Process the given buffer.
+            match output_sample_format {
+                cpal::SampleFormat::U16 => {
+                    let output = data.as_slice_mut::<u16>().expect("expected u16 data");
+                    fill_output(output, &samples);
+                }
+                cpal::SampleFormat::I16 => {
+                    let output = data.as_slice_mut::<i16>().expect("expected i16 data");
+                    fill_output(output, &samples);
+                }
+                cpal::SampleFormat::F32 => {
+                    let output = data.as_slice_mut::<f32>().expect("expected f32 data");
+                    fill_output(output, &samples);
+                }
+            }
+        };
+
+        // Wrap the user's error function, for both streams.
+        let err_fn = |model: Arc<Mutex<Option<M>>>, error: Arc<FE>| {
+            move |err: cpal::StreamError| {
+                if let Ok(mut guard) = model.lock() {
+                    if let Some(ref mut model) = *guard {
+                        error(model, err);
+                    }
+                }
+            }
+        };
+
+        let input_stream = input_device.build_input_stream_raw(
+            &input_stream_config,
+            input_sample_format,
+            capture_fn,
+            err_fn(model.clone(), error.clone()),
+        )?;
+        let stream = output_device.build_output_stream_raw(
+            &stream_config,
+            output_sample_format,
+            render_fn,
+            err_fn(model.clone(), error),
+        )?;
+
+        let shared = Arc::new(super::Shared {
+            stream,
+            input_stream: Some(input_stream),
+            model,
+            is_paused: AtomicBool::new(false),
+        });
+
+        let stream = Stream {
+            shared,
+            update_tx,
+            cpal_config: stream_config,
+        };
+        Ok(stream)
+    }
+}
+
+// How far from the input's own rate the resampler will go, as a fraction. Sound card clocks
+// are usually within a few hundred parts per million of each other.
+const MAX_DRIFT: f64 = 0.002;
+// How strongly the resampler steers the buffer towards its target fill: the change in rate for
+// each frame the buffer is off by. At MAX_DRIFT when it's 100 frames off.
+const DRIFT_GAIN: f64 = 0.00002;
+// How quickly the measured fill follows the actual one, per callback. The fill jumps by a whole
+// device buffer at every callback, so it's only useful averaged over many of them.
+const FILL_SMOOTHING: f64 = 0.01;
+// The largest burst the buffer is sized for, in frames. The ring is allocated up front for
+// this, so a larger device buffer is treated as this size and may run the buffer dry.
+const MAX_BURST: usize = 4096;
+// The ring's size, in frames: room for the most the buffer is let fill to (four times the
+// target of twice the burst), twice over so the reader's trim gets to it before the writer
+// has to drop anything.
+const RING_FRAMES: usize = MAX_BURST * 16;
+
+// Create the two ends of a drift buffer for `channels` channels, whose output is taken
+// `frames_per_buffer` frames at a time.
+fn drift_buffer(channels: usize, frames_per_buffer: usize) -> (DriftWriter, DriftBuffer) {
+    let (writer, reader) = ring_buffer::capture(RING_FRAMES, channels);
+    let input_burst = Arc::new(AtomicUsize::new(0));
+    let writer = DriftWriter {
+        writer,
+        channels,
+        input_burst: input_burst.clone(),
+    };
+    let buffer = DriftBuffer {
+        reader,
+        channels,
+        input_burst,
+        burst: frames_per_buffer.min(MAX_BURST),
+        average_fill: 0.0,
+        phase: 0.0,
+        primed: false,
+    };
+    (writer, buffer)
+}
+
+// The input stream's end of the drift buffer.
+struct DriftWriter {
+    writer: CaptureWriter<f32>,
+    channels: usize,
+    // The largest burst pushed, for the reading end to size its target by.
+    input_burst: Arc<AtomicUsize>,
+}
+
+impl DriftWriter {
+    // Add the interleaved samples captured by the input stream. If the output has stalled and
+    // the ring is full, the newest are dropped.
+    fn push(&mut self, samples: &[f32]) {
+        let frames = samples.len() / self.channels;
+        self.input_burst.fetch_max(frames, Ordering::Relaxed);
+        self.writer.push(samples);
+    }
+}
+
+// Captured frames waiting to be played, read back at a rate that keeps it about as full as it
+// needs to be to never run dry. This is the output stream's end, the input stream writes with
+// a `DriftWriter`.
+//
+// The input arrives in bursts of one device buffer, and is taken in bursts of the output's, so
+// the buffer needs to hold at least the larger of the two. The target fill is twice that.
+struct DriftBuffer {
+    reader: CaptureReader<f32>,
+    channels: usize,
+    // The largest burst pushed by the writer, in frames.
+    input_burst: Arc<AtomicUsize>,
+    // The largest burst seen on either side, in frames, up to MAX_BURST.
+    burst: usize,
+    // The fill level averaged over many callbacks, in frames.
+    average_fill: f64,
+    // How far the next frame to read is past the oldest one waiting, between 0 and 1.
+    phase: f64,
+    // Whether the buffer has filled up to the target since it last ran dry.
+    primed: bool,
+}
+
+impl DriftBuffer {
+    fn len_frames(&self) -> usize {
+        self.reader.available_frames()
+    }
+
+    fn target_fill(&self) -> usize {
+        self.burst * 2
+    }
+
+    // The output has stalled or is far behind: drop the oldest audio rather than let the delay
+    // grow.
+    fn trim(&mut self) {
+        let max_fill = self.target_fill() * 4;
+        if self.len_frames() > max_fill {
+            self.reader.skip(self.len_frames() - self.target_fill());
+            self.average_fill = self.target_fill() as f64;
+        }
+    }
+
+    // Fill `output` with interleaved frames resampled from the buffer, or silence where it's
+    // run dry.
+    fn pull(&mut self, output: &mut [f32]) {
+        let channels = self.channels;
+        let frames = output.len() / channels;
+        let input_burst = self.input_burst.load(Ordering::Relaxed);
+        self.burst = self.burst.max(frames).max(input_burst).min(MAX_BURST);
+        self.trim();
+        let target = self.target_fill() as f64;
+        self.average_fill += (self.len_frames() as f64 - self.average_fill) * FILL_SMOOTHING;
+
+        // Wait until there's enough to ride out the bursts before starting.
+        if !self.primed && self.len_frames() >= self.target_fill() {
+            self.primed = true;
+            self.average_fill = self.len_frames() as f64;
+        }
+        if !self.primed {
+            output.iter_mut().for_each(|sample| *sample = 0.0);
+            return;
+        }
+
+        // Read a little faster when the buffer is fuller than it needs to be, and a little
+        // slower when it's emptier.
+        let error = self.average_fill - target;
+        let step = 1.0 + (error * DRIFT_GAIN).clamp(-MAX_DRIFT, MAX_DRIFT);
+
+        for frame in output.chunks_mut(channels) {
+            // Interpolating needs the frame after the one being read too.
+            if self.len_frames() < 2 {
+                self.primed = false;
+                frame.iter_mut().for_each(|sample| *sample = 0.0);
+                continue;
+            }
+            let t = self.phase as f32;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let a = self.reader.peek(0, channel).unwrap_or(0.0);
+                let b = self.reader.peek(1, channel).unwrap_or(a);
+                *sample = a + (b - a) * t;
+            }
+            self.phase += step;
+            while self.phase >= 1.0 && self.len_frames() > 0 {
+                self.reader.skip(1);
+                self.phase -= 1.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNELS: usize = 2;
+    const BURST: usize = 64;
+
+    // A burst of `frames` frames, every sample `value`.
+    fn burst(frames: usize, value: f32) -> Vec<f32> {
+        vec![value; frames * CHANNELS]
+    }
+
+    // Pull one output burst, returning it.
+    fn pull(buffer: &mut DriftBuffer) -> Vec<f32> {
+        let mut output = vec![f32::NAN; BURST * CHANNELS];
+        buffer.pull(&mut output);
+        output
+    }
+
+    // Input and output bursts of BURST frames, except every `every`th input burst has `extra`
+    // more, like an input clock running slightly fast (or slow, for a negative `extra`).
+    // Returns the fill after each callback, once the buffer has primed.
+    fn run_with_drift(every: usize, extra: isize, callbacks: usize) -> Vec<usize> {
+        let (mut writer, mut buffer) = drift_buffer(CHANNELS, BURST);
+        let mut fills = vec![];
+        for i in 0..callbacks {
+            let frames = if i % every == 0 {
+                (BURST as isize + extra) as usize
+            } else {
+                BURST
+            };
+            writer.push(&burst(frames, 1.0));
+            let was_primed = buffer.primed;
+            let output = pull(&mut buffer);
+            if was_primed {
+                assert!(
+                    output.iter().all(|&sample| sample == 1.0),
+                    "ran dry after {} callbacks",
+                    i
+                );
+                fills.push(buffer.len_frames());
+            }
+        }
+        fills
+    }
+
+    #[test]
+    fn silent_until_primed() {
+        let (mut writer, mut buffer) = drift_buffer(CHANNELS, BURST);
+        writer.push(&burst(BURST, 0.5));
+        assert!(pull(&mut buffer).iter().all(|&sample| sample == 0.0));
+        // nothing was taken while priming
+        assert_eq!(buffer.len_frames(), BURST);
+
+        writer.push(&burst(BURST, 0.5));
+        assert_eq!(buffer.len_frames(), buffer.target_fill());
+        assert!(pull(&mut buffer).iter().all(|&sample| sample == 0.5));
+        assert!(buffer.primed);
+    }
+
+    #[test]
+    fn follows_a_fast_input() {
+        // one frame in 16 * 64 too many, about 0.1% fast
+        let fills = run_with_drift(16, 1, 20_000);
+        let target = 2 * (BURST + 1);
+        let settled = &fills[fills.len() / 2..];
+        // well short of where it would be trimmed
+        let max = *settled.iter().max().unwrap();
+        assert!(max < target * 2, "fill grew to {}", max);
+        // the fill stays put rather than creeping up
+        let (first, last) = (settled[0], settled[settled.len() - 1]);
+        assert!(
+            last.abs_diff(first) < BURST,
+            "fill went from {} to {}",
+            first,
+            last
+        );
+    }
+
+    #[test]
+    fn follows_a_slow_input() {
+        let fills = run_with_drift(16, -1, 20_000);
+        let settled = &fills[fills.len() / 2..];
+        let min = *settled.iter().min().unwrap();
+        assert!(min >= 2, "fill fell to {}", min);
+        let (first, last) = (settled[0], settled[settled.len() - 1]);
+        assert!(
+            last.abs_diff(first) < BURST,
+            "fill went from {} to {}",
+            first,
+            last
+        );
+    }
+
+    #[test]
+    fn runs_dry_and_primes_again() {
+        let (mut writer, mut buffer) = drift_buffer(CHANNELS, BURST);
+        writer.push(&burst(BURST, 1.0));
+        writer.push(&burst(BURST, 1.0));
+        assert!(pull(&mut buffer).iter().all(|&sample| sample == 1.0));
+
+        // the input stops, and it runs out part way through the next burst or the one after,
+        // depending on how far it's slowed down to wait for more
+        let mut output = pull(&mut buffer);
+        if buffer.primed {
+            output = pull(&mut buffer);
+        }
+        assert!(!buffer.primed);
+        assert_eq!(output[output.len() - 1], 0.0);
+
+        // and it waits for the target fill again before playing
+        writer.push(&burst(BURST, 1.0));
+        assert!(pull(&mut buffer).iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn trims_a_stalled_output() {
+        let (mut writer, mut buffer) = drift_buffer(CHANNELS, BURST);
+        let target = buffer.target_fill();
+        for i in 0..target * 4 / BURST + 1 {
+            writer.push(&burst(BURST, i as f32));
+        }
+        assert!(buffer.len_frames() > target * 4);
+
+        let output = pull(&mut buffer);
+        // the oldest audio is dropped, down to the target less what was just played
+        assert_eq!(buffer.len_frames(), target - BURST);
+        let newest = (target * 4 / BURST) as f32;
+        assert_eq!(output[0], newest - 1.0);
+    }
+
+    #[test]
+    fn a_full_ring_drops_the_newest() {
+        let (mut writer, mut buffer) = drift_buffer(CHANNELS, BURST);
+        for _ in 0..RING_FRAMES / MAX_BURST {
+            writer.push(&burst(MAX_BURST, 1.0));
+        }
+        assert_eq!(buffer.len_frames(), RING_FRAMES);
+        writer.push(&burst(MAX_BURST, 2.0));
+        assert_eq!(buffer.len_frames(), RING_FRAMES);
+        pull(&mut buffer);
+        assert!(buffer.len_frames() <= buffer.target_fill());
+        assert_eq!(buffer.reader.peek(0, 0), Some(1.0));
+    }
+}
@@ -231,6 +231,7 @@ impl<M, FC, FE, S> Builder<M, FC, FE, S> {
 
         let shared = Arc::new(super::Shared {
             stream,
+            input_stream: None,
             model,
             is_paused: AtomicBool::new(false),
         });
@@ -13,8 +13,9 @@ pub mod input;
 pub mod output;
 /// Items related to duplex (synchronised input/output) audio streams.
 ///
-/// *Progress is currently pending implementation of duplex audio streams in CPAL.*
-pub mod duplex {}
+/// CPAL doesn't have duplex streams yet, so these are an input and an output stream kept in step
+/// by nannou_audio.
+pub mod duplex;
 
 /// Called by the audio host in the case that an error occurs on an audio stream thread.
 pub trait ErrorFn<M>: Fn(&mut M, cpal::StreamError) {}
@@ -38,6 +39,8 @@ pub struct Stream<M> {
 struct Shared<M> {
     // The CPAL stream handle.
     stream: cpal::Stream,
+    // The input half of a duplex stream, played and paused along with `stream`.
+    input_stream: Option<cpal::Stream>,
     // The user's audio model
     model: Arc<Mutex<Option<M>>>,
     // Whether or not the stream is currently paused.
@@ -67,6 +70,8 @@ pub enum BuildError {
     },
     #[error("failed to build stream: {err}")]
     BuildStream { err: cpal::BuildStreamError },
+    #[error("the input device does not support the output's sample rate of {sample_rate} Hz")]
+    SampleRateMismatch { sample_rate: u32 },
 }
 
 #[derive(Debug)]
@@ -178,6 +183,10 @@ impl<M> Stream<M> {
 
 impl<M> Shared<M> {
     fn play(&self) -> Result<(), cpal::PlayStreamError> {
+        // Input first, so there's something captured by the time the output asks for it.
+        if let Some(ref input_stream) = self.input_stream {
+            input_stream.play()?;
+        }
         self.stream.play()?;
         self.is_paused.store(false, atomic::Ordering::Relaxed);
         Ok(())
@@ -185,6 +194,9 @@ impl<M> Shared<M> {
 
     fn pause(&self) -> Result<(), cpal::PauseStreamError> {
         self.stream.pause()?;
+        if let Some(ref input_stream) = self.input_stream {
+            input_stream.pause()?;
+        }
         self.is_paused.store(true, atomic::Ordering::Relaxed);
         Ok(())
     }
@@ -230,6 +230,7 @@ impl<M, FR, FE, S> Builder<M, FR, FE, S> {
 
         let shared = Arc::new(super::Shared {
             stream,
+            input_stream: None,
             model,
             is_paused: AtomicBool::new(false),
         });
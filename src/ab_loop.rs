@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+/// How far the loop nudge keys move an edge, and with shift held
+pub const NUDGE_STEP: Duration = Duration::from_millis(10);
+pub const NUDGE_STEP_LARGE: Duration = Duration::from_millis(100);
+/// The shortest loop the nudge keys leave
+const MIN_LOOP: Duration = Duration::from_millis(50);
+
+/// The A-B loop as the UI sets it up. The audio thread does the looping, see `SampleStore::set_loop`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AbLoop {
+    #[default]
+    Off,
+    /// A is set, waiting for B
+    Started(Duration),
+    Looping {
+        start: Duration,
+        end: Duration,
+    },
+}
+
+/// Which end of the loop to nudge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopEdge {
+    Start,
+    End,
+}
+
+impl AbLoop {
+    /// The L key: the first press sets A, the second sets B and starts looping, the third
+    /// stops. B before A (after seeking back) swaps them.
+    pub fn mark(self, position: Duration) -> Self {
+        match self {
+            AbLoop::Off => AbLoop::Started(position),
+            AbLoop::Started(a) if a == position => self,
+            AbLoop::Started(a) => AbLoop::Looping {
+                start: a.min(position),
+                end: a.max(position),
+            },
+            AbLoop::Looping { .. } => AbLoop::Off,
+        }
+    }
+
+    /// Move one end of the loop, keeping it inside the track and at least MIN_LOOP long.
+    /// Before B is set there's only A to move. `track_length` is None when it isn't known,
+    /// and then the end isn't limited.
+    pub fn nudge(
+        self,
+        edge: LoopEdge,
+        by: Duration,
+        later: bool,
+        track_length: Option<Duration>,
+    ) -> Self {
+        let shift = |t: Duration| {
+            if later {
+                t + by
+            } else {
+                t.saturating_sub(by)
+            }
+        };
+        let clamp = |t: Duration| track_length.map_or(t, |length| t.min(length));
+        match (self, edge) {
+            (AbLoop::Off, _) => self,
+            (AbLoop::Started(a), _) => AbLoop::Started(clamp(shift(a))),
+            (AbLoop::Looping { start, end }, LoopEdge::Start) => AbLoop::Looping {
+                start: shift(start).min(end.saturating_sub(MIN_LOOP)),
+                end,
+            },
+            (AbLoop::Looping { start, end }, LoopEdge::End) => AbLoop::Looping {
+                start,
+                end: clamp(shift(end).max(start + MIN_LOOP)),
+            },
+        }
+    }
+
+    /// what the audio thread should loop
+    pub fn region(&self) -> Option<(Duration, Duration)> {
+        match *self {
+            AbLoop::Looping { start, end } => Some((start, end)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: f64) -> Duration {
+        Duration::from_secs_f64(s)
+    }
+
+    #[test]
+    fn nudge_stays_inside_a_known_track() {
+        let ab_loop = AbLoop::Looping {
+            start: secs(1.0),
+            end: secs(9.95),
+        };
+        let nudged = ab_loop.nudge(LoopEdge::End, NUDGE_STEP_LARGE, true, Some(secs(10.0)));
+        assert_eq!(nudged.region(), Some((secs(1.0), secs(10.0))));
+        let started = AbLoop::Started(secs(9.95));
+        let nudged = started.nudge(LoopEdge::Start, NUDGE_STEP_LARGE, true, Some(secs(10.0)));
+        assert_eq!(nudged, AbLoop::Started(secs(10.0)));
+    }
+
+    #[test]
+    fn nudge_later_with_unknown_length() {
+        let ab_loop = AbLoop::Looping {
+            start: secs(1.0),
+            end: secs(2.0),
+        };
+        let nudged = ab_loop.nudge(LoopEdge::End, NUDGE_STEP_LARGE, true, None);
+        assert_eq!(
+            nudged.region(),
+            Some((secs(1.0), secs(2.0) + NUDGE_STEP_LARGE))
+        );
+        let started = AbLoop::Started(secs(1.0));
+        let nudged = started.nudge(LoopEdge::Start, NUDGE_STEP, true, None);
+        assert_eq!(nudged, AbLoop::Started(secs(1.0) + NUDGE_STEP));
+    }
+
+    #[test]
+    fn nudge_keeps_the_loop_long_enough() {
+        let ab_loop = AbLoop::Looping {
+            start: secs(1.0),
+            end: secs(1.1),
+        };
+        let nudged = ab_loop.nudge(LoopEdge::Start, NUDGE_STEP_LARGE, true, None);
+        assert_eq!(nudged.region(), Some((secs(1.1) - MIN_LOOP, secs(1.1))));
+        let nudged = ab_loop.nudge(LoopEdge::End, NUDGE_STEP_LARGE, false, None);
+        assert_eq!(nudged.region(), Some((secs(1.0), secs(1.0) + MIN_LOOP)));
+    }
+}
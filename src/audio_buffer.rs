@@ -0,0 +1,526 @@
+use nannou_audio::InterleavedSlice;
+use rodio::source::SeekError;
+use rodio::Source;
+use std::collections::BTreeMap;
+use std::f32::consts::FRAC_PI_2;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, TryLockError, Weak};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Number of frames (samples per channel) in one chunk, ~0.37s at 44.1kHz
+pub const CHUNK_FRAMES: usize = 16384;
+/// How many chunks to keep decoded ahead of the playhead
+pub const CHUNKS_AHEAD: usize = 32;
+/// How many already played chunks to keep around, so a short seek back doesn't re-decode
+pub const CHUNKS_BEHIND: usize = 8;
+/// How often the prefetch thread tops up the window
+const PREFETCH_INTERVAL: Duration = Duration::from_millis(50);
+/// Length of the crossfade from the end of a loop into its start, so the jump doesn't click
+const LOOP_FADE: Duration = Duration::from_millis(5);
+/// How many chunks playback has finished with can wait to be dropped by the prefetch thread
+const RETIRED_CHUNKS: usize = 8;
+
+/// One decoded chunk, stored per channel (de-interleaved).
+/// Every chunk has CHUNK_FRAMES frames, except the last one of the track.
+pub struct Chunk {
+    channels: Vec<Vec<f32>>,
+}
+
+type BoxedSource = Box<dyn Source<Item = f32> + Send>;
+
+struct StoreState {
+    /// None while a thread has it out to decode a chunk
+    source: Option<BoxedSource>,
+    /// decoded chunks by index, only a window around the playhead is kept
+    chunks: BTreeMap<usize, Arc<Chunk>>,
+    /// the chunk `source` will produce next
+    next_chunk: usize,
+    /// index of the first chunk past the end of the track, once we've seen the end
+    end_chunk: Option<usize>,
+}
+
+/// Decoded samples for one track, held in a bounded window of chunks around the playhead.
+///
+/// A prefetch thread decodes ahead of the playhead and evicts old chunks, so memory use
+/// doesn't depend on the track length. The FFT uses `read()`, which decodes a chunk that isn't
+/// there (eg. right after a seek) on demand. Playback reads it through `BufferSource`, which
+/// never waits on a decode: a missing chunk is left to the prefetch thread.
+///
+/// Decoding happens outside the lock, so the lock is only ever held to look up or add a chunk.
+///
+/// A loop region's chunks are kept too, so playback can jump back to its start without decoding.
+pub struct SampleStore {
+    sample_rate: u32,
+    num_channels: u16,
+    total_duration: Option<Duration>,
+    /// frame the playback source is currently at
+    playhead: AtomicUsize,
+    /// the A-B loop's start frame in the high half and end frame in the low half, 0 when
+    /// there's no loop. One atomic so playback never sees half of a change.
+    loop_region: AtomicU64,
+    state: Mutex<StoreState>,
+    /// signalled when the source is put back after decoding a chunk
+    decoded: Condvar,
+    /// the prefetch thread, woken when playback finds a chunk missing
+    prefetcher: OnceLock<thread::Thread>,
+    /// chunks playback is done with, see `retire`
+    retired: SyncSender<Arc<Chunk>>,
+    /// only ever locked by the prefetch thread
+    retired_receiver: Mutex<Receiver<Arc<Chunk>>>,
+}
+
+/// A chunk as playback gets it, see `SampleStore::try_chunk`
+pub enum ChunkLookup {
+    Ready(Arc<Chunk>),
+    /// not decoded yet, or the lock is busy
+    NotReady,
+    /// past the end of the track
+    End,
+}
+
+impl SampleStore {
+    /// Wrap a decoder. Nothing is decoded until a chunk is asked for or prefetching starts.
+    pub fn new<S>(source: S) -> Arc<Self>
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        // bounded, so retiring never allocates
+        let (retired, retired_receiver) = mpsc::sync_channel(RETIRED_CHUNKS);
+        let store = SampleStore {
+            sample_rate: source.sample_rate(),
+            num_channels: source.channels(),
+            total_duration: source.total_duration(),
+            playhead: AtomicUsize::new(0),
+            loop_region: AtomicU64::new(0),
+            state: Mutex::new(StoreState {
+                source: Some(Box::new(source)),
+                chunks: BTreeMap::new(),
+                next_chunk: 0,
+                end_chunk: None,
+            }),
+            decoded: Condvar::new(),
+            prefetcher: OnceLock::new(),
+            retired,
+            retired_receiver: Mutex::new(retired_receiver),
+        };
+        Arc::new(store)
+    }
+
+    /// Spawn the thread that keeps CHUNKS_AHEAD chunks decoded in front of the playhead.
+    /// It stops by itself once the store is dropped.
+    pub fn spawn_prefetch(store: &Arc<Self>) {
+        let weak: Weak<Self> = Arc::downgrade(store);
+        let handle = thread::spawn(move || {
+            while let Some(store) = weak.upgrade() {
+                store.drop_retired();
+                store.prefetch();
+                drop(store);
+                // woken early when playback is waiting on a chunk
+                thread::park_timeout(PREFETCH_INTERVAL);
+            }
+            debug!("sample store dropped, prefetch thread exiting");
+        });
+        let _ = store.prefetcher.set(handle.thread().clone());
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn num_channels(&self) -> u16 {
+        self.num_channels
+    }
+
+    pub fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    /// Tell the store where playback is, so it knows what to prefetch and what to evict.
+    pub fn set_playhead(&self, frame: usize) {
+        self.playhead.store(frame, Ordering::Relaxed);
+    }
+
+    /// the frame playback is at
+    pub fn playhead(&self) -> usize {
+        self.playhead.load(Ordering::Relaxed)
+    }
+
+    /// Loop playback between these frames, or stop looping. Playback jumps back to the start
+    /// when it reaches the end, so it keeps playing normally until then if it's outside the loop.
+    /// Frames past u32::MAX (a day at 48kHz) can't loop.
+    pub fn set_loop(&self, region: Option<Range<usize>>) {
+        let packed = match region {
+            Some(region) if region.start < region.end => {
+                match (u32::try_from(region.start), u32::try_from(region.end)) {
+                    (Ok(start), Ok(end)) => (start as u64) << 32 | end as u64,
+                    _ => {
+                        warn!("Loop {:?} is too far into the track", region);
+                        0
+                    }
+                }
+            }
+            _ => 0,
+        };
+        self.loop_region.store(packed, Ordering::Relaxed);
+    }
+
+    /// the loop's frames, if there is one
+    pub fn loop_region(&self) -> Option<Range<usize>> {
+        let packed = self.loop_region.load(Ordering::Relaxed);
+        if packed == 0 {
+            return None;
+        }
+        Some((packed >> 32) as usize..(packed & u32::MAX as u64) as usize)
+    }
+
+    /// frames of the fade at the end of `region`, short enough that it fits in the loop
+    fn loop_fade_frames(&self, region: &Range<usize>) -> usize {
+        let fade = (LOOP_FADE.as_secs_f64() * self.sample_rate as f64) as usize;
+        fade.min(region.len() / 2)
+    }
+
+    /// the first frame of the audio leading up to the loop's start that the fade fades in.
+    /// None if the loop starts too close to the start of the track to have any.
+    fn loop_lead_in(&self, region: &Range<usize>) -> Option<usize> {
+        region.start.checked_sub(self.loop_fade_frames(region))
+    }
+
+    /// `len` frames of one channel starting at `start_frame`.
+    /// Returns None if the range runs past the end of the track.
+    pub fn read(&self, channel: usize, start_frame: usize, len: usize) -> Option<Vec<f32>> {
+        let mut out = Vec::with_capacity(len);
+        let mut frame = start_frame;
+        while out.len() < len {
+            let chunk = self.chunk(frame / CHUNK_FRAMES)?;
+            let offset = frame % CHUNK_FRAMES;
+            let samples = chunk.channels[channel].get(offset..)?;
+            if samples.is_empty() {
+                return None;
+            }
+            let take = samples.len().min(len - out.len());
+            out.extend_from_slice(&samples[..take]);
+            frame += take;
+        }
+        Some(out)
+    }
+
+    /// Get a chunk, decoding it now if it isn't in the window. Waits if another thread is
+    /// decoding, so this isn't for the output callback, see `try_chunk`.
+    /// Returns None past the end of the track.
+    pub fn chunk(&self, index: usize) -> Option<Arc<Chunk>> {
+        let mut state = self.lock_state();
+        let mut source = loop {
+            if let Some(chunk) = state.chunks.get(&index) {
+                return Some(Arc::clone(chunk));
+            }
+            if state.end_chunk.map_or(false, |end| index >= end) {
+                return None;
+            }
+            match state.source.take() {
+                Some(source) => break source,
+                // someone else is decoding, maybe this very chunk
+                None => state = self.decoded.wait(state).unwrap_or_else(|e| e.into_inner()),
+            }
+        };
+        let next_chunk = state.next_chunk;
+        drop(state);
+
+        let mut seeked = true;
+        if index != next_chunk {
+            // not where the decoder is, happens after a seek
+            debug!("decoding chunk {} on demand", index);
+            let position =
+                Duration::from_secs_f64((index * CHUNK_FRAMES) as f64 / self.sample_rate as f64);
+            if let Err(e) = source.try_seek(position) {
+                warn!("Failed to seek decoder to chunk {}: {}", index, e);
+                seeked = false;
+            }
+        }
+        let channels = seeked.then(|| self.decode(&mut source));
+
+        let mut state = self.lock_state();
+        state.source = Some(source);
+        let chunk = channels.and_then(|channels| self.insert(&mut state, index, channels));
+        self.evict(&mut state);
+        drop(state);
+        self.decoded.notify_all();
+        chunk
+    }
+
+    /// Get a chunk only if it's already decoded, without waiting for the lock or decoding
+    /// anything, so it's safe to call from the output callback. The prefetch thread is woken
+    /// to decode a missing chunk.
+    pub fn try_chunk(&self, index: usize) -> ChunkLookup {
+        let state = match self.state.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return ChunkLookup::NotReady,
+        };
+        if let Some(chunk) = state.chunks.get(&index) {
+            return ChunkLookup::Ready(Arc::clone(chunk));
+        }
+        if state.end_chunk.map_or(false, |end| index >= end) {
+            return ChunkLookup::End;
+        }
+        drop(state);
+        if let Some(prefetcher) = self.prefetcher.get() {
+            prefetcher.unpark();
+        }
+        ChunkLookup::NotReady
+    }
+
+    /// Hand a chunk playback has finished with to the prefetch thread. After a seek or a loop
+    /// jump the window has usually moved on without it, and dropping the last reference in
+    /// the output callback would free it there.
+    fn retire(&self, chunk: Option<(usize, Arc<Chunk>)>) {
+        if let Some((_, chunk)) = chunk {
+            // only full if there's no prefetch thread collecting them, then it's dropped here
+            let _ = self.retired.try_send(chunk);
+        }
+    }
+
+    fn drop_retired(&self) {
+        let receiver = self
+            .retired_receiver
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        receiver.try_iter().for_each(drop);
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, StoreState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// decode the next chunk's worth of the source, per channel
+    fn decode(&self, source: &mut BoxedSource) -> Vec<Vec<f32>> {
+        let num_channels = self.num_channels as usize;
+        let interleaved: Vec<f32> = source.take(CHUNK_FRAMES * num_channels).collect();
+        // a truncated last frame is dropped, so the channels all have the same length
+        let interleaved = InterleavedSlice::new(&interleaved, num_channels);
+        let mut channels = vec![vec![0.0; interleaved.len_frames()]; num_channels];
+        interleaved.deinterleave_into(&mut channels);
+        channels
+    }
+
+    /// add the chunk decoded at `index` to the window, noting the end of the track if it's short
+    fn insert(
+        &self,
+        state: &mut StoreState,
+        index: usize,
+        channels: Vec<Vec<f32>>,
+    ) -> Option<Arc<Chunk>> {
+        let len_frames = channels.first().map_or(0, Vec::len);
+        if len_frames < CHUNK_FRAMES {
+            state.end_chunk = Some(if len_frames == 0 { index } else { index + 1 });
+            info!("reached end of track at chunk {}", index);
+        }
+        if len_frames == 0 {
+            return None;
+        }
+
+        let chunk = Arc::new(Chunk { channels });
+        state.chunks.insert(index, Arc::clone(&chunk));
+        state.next_chunk = index + 1;
+        Some(chunk)
+    }
+
+    /// drop chunks outside the window around the playhead, except the loop's
+    fn evict(&self, state: &mut StoreState) {
+        let (first, last) = self.window();
+        let pinned = self.loop_chunks();
+        state.chunks.retain(|&index, _| {
+            (index >= first && index <= last)
+                || pinned.as_ref().map_or(false, |r| r.contains(&index))
+        });
+    }
+
+    /// chunks kept for the jump back: the audio the crossfade fades in from and the start of
+    /// the loop, up to CHUNKS_AHEAD. The rest of a long loop is decoded again as it plays.
+    fn loop_chunks(&self) -> Option<Range<usize>> {
+        let region = self.loop_region()?;
+        let first = self.loop_lead_in(&region).unwrap_or(region.start) / CHUNK_FRAMES;
+        let last = (region.end - 1) / CHUNK_FRAMES + 1;
+        Some(first..last.min(region.start / CHUNK_FRAMES + CHUNKS_AHEAD))
+    }
+
+    /// first and last chunk index we want to keep
+    fn window(&self) -> (usize, usize) {
+        let playhead_chunk = self.playhead.load(Ordering::Relaxed) / CHUNK_FRAMES;
+        (
+            playhead_chunk.saturating_sub(CHUNKS_BEHIND),
+            playhead_chunk + CHUNKS_AHEAD,
+        )
+    }
+
+    /// decode whatever is missing in front of the playhead
+    fn prefetch(&self) {
+        let (_, last) = self.window();
+        let mut index = self.playhead.load(Ordering::Relaxed) / CHUNK_FRAMES;
+        while index <= last {
+            // chunk() only holds the lock to look up and add chunks, never while decoding
+            if self.chunk(index).is_none() {
+                break;
+            }
+            index += 1;
+        }
+        // the start of the loop has to be ready before playback jumps back to it
+        if let Some(pinned) = self.loop_chunks() {
+            for index in pinned {
+                if self.chunk(index).is_none() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Plays a `SampleStore`, re-interleaving the channels on the fly.
+/// Keeps the store's playhead up to date as it goes.
+///
+/// When the store has a loop, reaching its end jumps back to its start on the exact frame.
+/// The last few milliseconds before the end are crossfaded with the audio leading up to the
+/// start, so the jump lands on what was already playing instead of clicking. A loop that
+/// starts at the very start of the track has nothing before it, so the end fades out and the
+/// start fades back in after the jump.
+pub struct BufferSource {
+    store: Arc<SampleStore>,
+    frame: usize,
+    channel: usize,
+    current: Option<(usize, Arc<Chunk>)>,
+    /// the chunk the loop's crossfade fades in from
+    loop_chunk: Option<(usize, Arc<Chunk>)>,
+    /// the current chunk isn't decoded yet, so this frame is silent
+    waiting: bool,
+    /// jumped back to a loop start with no lead-in, so the start fades in
+    fading_in: bool,
+}
+
+impl BufferSource {
+    pub fn new(store: Arc<SampleStore>) -> Self {
+        BufferSource {
+            store,
+            frame: 0,
+            channel: 0,
+            current: None,
+            loop_chunk: None,
+            waiting: false,
+            fading_in: false,
+        }
+    }
+
+    /// the sample the loop's crossfade fades in, silent if its chunk isn't ready
+    fn loop_sample(&mut self, frame: usize) -> f32 {
+        let index = frame / CHUNK_FRAMES;
+        if self.loop_chunk.as_ref().map(|(i, _)| *i) != Some(index) {
+            let chunk = match self.store.try_chunk(index) {
+                ChunkLookup::Ready(chunk) => chunk,
+                ChunkLookup::NotReady | ChunkLookup::End => return 0.0,
+            };
+            self.store.retire(self.loop_chunk.replace((index, chunk)));
+        }
+        self.loop_chunk
+            .as_ref()
+            .and_then(|(_, chunk)| chunk.channels.get(self.channel)?.get(frame % CHUNK_FRAMES))
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+impl Iterator for BufferSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let region = self.store.loop_region();
+        if self.channel == 0 {
+            if let Some(region) = region.as_ref().filter(|r| self.frame == r.end) {
+                self.frame = region.start;
+                self.fading_in = self.store.loop_lead_in(region).is_none();
+            }
+            self.store.set_playhead(self.frame);
+            // the store's lock is only tried when crossing into a new chunk
+            let index = self.frame / CHUNK_FRAMES;
+            self.waiting = match &self.current {
+                Some((i, _)) if *i == index => false,
+                _ => match self.store.try_chunk(index) {
+                    ChunkLookup::Ready(chunk) => {
+                        self.store.retire(self.current.replace((index, chunk)));
+                        false
+                    }
+                    ChunkLookup::NotReady => true,
+                    ChunkLookup::End => return None,
+                },
+            };
+        }
+        let num_channels = self.store.num_channels() as usize;
+        if self.waiting {
+            // a frame of silence while the chunk is decoded, then this frame plays
+            self.channel = (self.channel + 1) % num_channels;
+            return Some(0.0);
+        }
+        let (_, chunk) = self.current.as_ref()?;
+        let mut sample = *chunk
+            .channels
+            .get(self.channel)?
+            .get(self.frame % CHUNK_FRAMES)?;
+
+        if let Some(region) = region {
+            let fade = self.store.loop_fade_frames(&region);
+            let fade_start = region.end - fade;
+            if (fade_start..region.end).contains(&self.frame) {
+                // fade in the audio that leads up to the start, reaching it right at the jump
+                let into_fade = self.frame - fade_start;
+                let incoming = match self.store.loop_lead_in(&region) {
+                    Some(lead_in) => self.loop_sample(lead_in + into_fade),
+                    None => 0.0,
+                };
+                // equal-power, like the crossfade between tracks
+                let t = into_fade as f32 / fade as f32 * FRAC_PI_2;
+                sample = sample * t.cos() + incoming * t.sin();
+            } else if self.fading_in && (region.start..region.start + fade).contains(&self.frame) {
+                let t = (self.frame - region.start) as f32 / fade as f32 * FRAC_PI_2;
+                sample *= t.sin();
+            }
+        }
+
+        self.channel += 1;
+        if self.channel == num_channels {
+            self.channel = 0;
+            self.frame += 1;
+        }
+        Some(sample)
+    }
+}
+
+impl Source for BufferSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.store.num_channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.store.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.store.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        // moving the read position is enough, the chunk gets decoded on demand if needed
+        self.frame = (pos.as_secs_f64() * self.store.sample_rate() as f64) as usize;
+        self.channel = 0;
+        self.store.retire(self.current.take());
+        self.store.retire(self.loop_chunk.take());
+        self.waiting = false;
+        self.fading_in = false;
+        self.store.set_playhead(self.frame);
+        Ok(())
+    }
+}
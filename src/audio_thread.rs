@@ -0,0 +1,1068 @@
+//! The audio thread: plays the playlist through the mixer (or analyses the input device in
+//! live mode) and runs the FFT, taking `Command`s from the UI.
+
+use rodio::{Sink, Source};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::audio_buffer::SampleStore;
+use crate::bookmarks;
+use crate::calibration::{LatencyStore, DEFAULT_LATENCY};
+use crate::config::AnalysisSettings;
+use crate::decoder::AudioDecoder;
+use crate::device_output::{self, DeviceOutput};
+use crate::devices::{self, DeviceSelector};
+use crate::error::AudioError;
+use crate::live_input::{InputLevel, LiveInput};
+use crate::metadata::{self, TrackInfo};
+use crate::null_output::{self, NullSpeed};
+use crate::palette::Palette;
+use crate::playlist::{PlaybackMode, Playlist};
+use crate::playlist_file::{self, PlaylistEntry};
+use crate::transition::{Deck, Mixer, MixerCommand, MixerStatus};
+use crate::volume::{Gain, VolumeStatus};
+
+#[derive(Debug)]
+pub enum Command {
+    Play,
+    Pause,
+    Seek(Duration),
+    CalculateFFT,
+    Next,
+    Previous,
+    /// play the track at this index in the playlist
+    JumpTo(usize),
+    /// write the queue to an M3U/M3U8 or PLS file
+    SavePlaylist(PathBuf),
+    /// the config file was reloaded
+    SetAnalysis(AnalysisSettings),
+    /// 0.0 - 1.0
+    SetVolume(f32),
+    Mute,
+    Unmute,
+    /// fade in from silence over this long
+    FadeIn(Duration),
+    /// fade out to silence over this long
+    FadeOut(Duration),
+    /// fade out and pause after this long, None cancels
+    SleepTimer(Option<Duration>),
+    /// loop the current track between these positions, None stops looping
+    SetLoop(Option<(Duration, Duration)>),
+    /// start or finish latency calibration, finishing saves the latency for the device
+    Calibrate(bool),
+    /// the output latency, while calibrating
+    SetLatency(Duration),
+}
+
+/// A struct to store the frequency and magnitude of a frequency bin.
+/// To be used by the visualizer
+#[derive(Debug, Clone, Copy)]
+pub struct FreqMagPair {
+    pub freq: f32,
+    pub mag: f32,
+}
+
+pub struct AudioManager {
+    pub sender_to_audio: Sender<Command>,
+    pub errors_from_audio: Receiver<AudioError>,
+    pub clock: Arc<MixerStatus>,
+    pub fft_output: Arc<Mutex<Vec<FreqMagPair>>>, // will use this to store the fft output -> 12 values per octave
+    pub track_info: Arc<Mutex<Option<(usize, TrackInfo)>>>,
+    pub playback_mode: Arc<Mutex<PlaybackMode>>,
+    pub volume: Arc<Mutex<VolumeStatus>>,
+    pub input_level: Arc<Mutex<Option<InputLevel>>>,
+    pub last_fft_generated_at: Arc<Mutex<Option<Instant>>>, // for debugging mostly
+    pub mp3_files: Vec<std::path::PathBuf>,
+}
+
+/// What the audio thread plays and how, from the command line
+pub struct AudioOptions {
+    pub tracks: Vec<PlaylistEntry>,
+    pub analysis: AnalysisSettings,
+    /// where to start the first track
+    pub start_at: Option<Duration>,
+    pub volume: f32,
+    /// the shuffle seed, None for a different one every run
+    pub seed: Option<u64>,
+    /// stop the audio thread when the playlist ends, instead of waiting for commands
+    pub exit_at_end: bool,
+    /// analyse the input device instead of playing tracks
+    pub live: bool,
+    /// play to the null output instead of a device
+    pub null_output: Option<NullSpeed>,
+    /// None for the default devices
+    pub output_device: Option<DeviceSelector>,
+    pub input_device: Option<DeviceSelector>,
+}
+
+pub fn create_audio_thread(options: AudioOptions) -> AudioManager {
+    let (sender, receiver) = mpsc::channel::<Command>();
+    let (error_sender, error_receiver) = mpsc::channel::<AudioError>();
+
+    // the audio thread plays through the same list the UI shows
+    let mp3_files = options
+        .tracks
+        .iter()
+        .map(|entry| entry.path.clone())
+        .collect();
+
+    let fft_output: Arc<Mutex<Vec<FreqMagPair>>> = Arc::new(Mutex::new(vec![]));
+
+    // made here rather than by the mixer, so the UI has it before the output is opened
+    let clock = Arc::new(MixerStatus::default());
+
+    let clock_clone = Arc::clone(&clock);
+    let fft_output_clone = Arc::clone(&fft_output);
+    let track_info: Arc<Mutex<Option<(usize, TrackInfo)>>> = Arc::new(Mutex::new(None));
+    let track_info_clone = Arc::clone(&track_info);
+    let playback_mode = Arc::new(Mutex::new(PlaybackMode::default()));
+    let playback_mode_clone = Arc::clone(&playback_mode);
+    let volume = Arc::new(Mutex::new(VolumeStatus::default()));
+    let volume_clone = Arc::clone(&volume);
+    let input_level = Arc::new(Mutex::new(None));
+    let input_level_clone = Arc::clone(&input_level);
+
+    thread::spawn(move || {
+        let result = if options.live {
+            live_control_thread(
+                receiver,
+                options,
+                fft_output_clone,
+                input_level_clone,
+                error_sender.clone(),
+            )
+        } else {
+            audio_control_thread(
+                receiver,
+                options,
+                clock_clone,
+                fft_output_clone,
+                track_info_clone,
+                playback_mode_clone,
+                volume_clone,
+                Arc::new(Mutex::new(None)),
+                error_sender.clone(),
+            )
+        };
+        // the audio thread can't continue after this, let the UI know why
+        if let Err(e) = result {
+            error!("Audio thread stopped: {}", e);
+            let _ = error_sender.send(e);
+        }
+    });
+    debug!("Audio thread spawned");
+    AudioManager {
+        sender_to_audio: sender,
+        errors_from_audio: error_receiver,
+        clock,
+        fft_output,
+        track_info,
+        playback_mode,
+        volume,
+        input_level,
+        mp3_files,
+        last_fft_generated_at: Arc::new(Mutex::new(None)),
+    }
+}
+
+/// The buffer is the fft output now contains the frequency and magnitude information for each frequency bin.
+/// buffer must of single channel (any one of the channels)
+/// The stored magnitudes are normalized so a full scale sine is 1.0 (0 dBFS),
+/// notes quieter than `threshold_dbfs` are left at 0
+fn display_frequencies(
+    buffer: &[Complex<f32>], // contains the actual fft_output
+    sample_rate: usize,
+    fft_size: usize,
+    threshold_dbfs: f32,
+    fft_output: Arc<Mutex<Vec<FreqMagPair>>>, // will use this to store the PROCESSED fft output -> 12 values per octave
+) {
+    assert!(
+        fft_output.lock().unwrap().len() % 12 == 0,
+        "Expected 12 target notes per octave"
+    );
+
+    let mut fft_output_locked = fft_output.lock().unwrap();
+
+    for freq_magnitude in fft_output_locked.iter_mut() {
+        freq_magnitude.mag = 0.0;
+    }
+
+    for (i, complex) in buffer.iter().enumerate() {
+        // buffer now contains the actual fft output of the audio
+        // so for each freq bin:
+        // frequency of the fft result at this freq bin
+        let frequency = (i as f32 * sample_rate as f32) / fft_size as f32;
+        // magnitude of the fft result at this freq bin, a full scale sine peaks at fft_size / 2
+        let magnitude = complex.norm() / (fft_size as f32 / 2.0);
+        let magnitude_dbfs = 20.0 * magnitude.max(f32::MIN_POSITIVE).log10();
+        // iterate over the target notes and update the magnitudes
+        for FreqMagPair { freq, mag } in fft_output_locked.iter_mut() {
+            // freq is the target note's frequency
+            // mag is the magnitude of the target note from previous iteration
+            if (frequency - *freq).abs() < 1.0 && magnitude_dbfs > threshold_dbfs {
+                // update the target note's magnitude if
+                // the frequency in buffer is close to the target note
+                // and the magnitude is greater than the threshold
+                // info!("FOUND CANDIDATE FREQ: {:?}", frequency);
+                *mag = magnitude;
+                // THE ABOVE LINE DOES"T SEEM TO BE WORKING, BECAUSE EVEN AFTER CANDIDATE FREQ IS FOUND,
+                // AT THE END fft_output HAS ALL ZEROS
+                break; // Stop checking once the first match is found and updated
+            }
+        }
+    }
+
+    // is the fft_output stll containing all 0.0 mags? if yes then prolly there's some problem
+    let all_zero = fft_output_locked.iter().all(|x| x.mag == 0.0); // RETURNS TRUE
+    if all_zero {
+        warn!("-- fft output (after) has all zeros");
+    }
+
+    // for (i, complex) in buffer.iter().enumerate() {
+    //     let frequency = (i as f32 * sample_rate as f32) / fft_size as f32;
+    //     let magnitude = complex.norm();
+    //     for freq_magnitude in output.iter_mut() {
+    //         if (frequency - freq_magnitude.re).abs() < 1.0 && magnitude > 1.0 {
+    //             // Update the magnitude if the condition is met
+    //             freq_magnitude.im = magnitude;
+    //             break; // Stop checking once the first match is found and updated
+    //         }
+    //     }
+    // }
+
+    // println!("--output: {:?}", output);
+}
+
+fn generate_note_frequencies(octaves: usize) -> Vec<f32> {
+    let base_notes = [
+        261.63, 277.18, 293.66, 311.13, 329.63, 349.23, 369.99, 392.00, 415.30, 440.00, 466.16,
+        493.88,
+    ];
+    let mut frequencies = Vec::new();
+    for octave in 0..octaves {
+        let multiplier = 2.0f32.powi(octave as i32);
+        frequencies.extend(base_notes.iter().map(|&f| f * multiplier));
+    }
+    frequencies
+}
+
+/// Open and probe an audio file. Samples come out as normalized f32 (-1.0 to 1.0)
+/// at the file's own sample rate, so 24 bit / 96kHz masters keep their resolution.
+fn load_audio(file_path: &Path) -> Result<AudioDecoder, AudioError> {
+    info!("Loading audio file");
+    let t = Instant::now();
+    let file = File::open(file_path).map_err(|err| AudioError::Io {
+        path: file_path.into(),
+        err,
+    })?;
+
+    // the format is detected from the file header, not the extension
+    let decoder = AudioDecoder::new(file).map_err(|e| AudioError::from_symphonia(file_path, e))?;
+
+    info!("Time to load audio: {:?}", t.elapsed());
+    return Ok(decoder);
+}
+
+/// A loaded track, with everything the fft needs to analyse it
+struct LoadedTrack {
+    /// how the mixer refers to this track
+    id: u64,
+    info: TrackInfo,
+    audio: Arc<SampleStore>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+/// ids for the mixer, 0 means "nothing" there so they start at 1
+fn next_track_id() -> u64 {
+    static NEXT_TRACK_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_TRACK_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The mixer feeding the sink, and the format it plays in
+struct MixerHandle {
+    commands: Sender<MixerCommand>,
+    /// decks the mixer has finished with, dropped here rather than in the output callback
+    retired: Receiver<Deck>,
+    status: Arc<MixerStatus>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl MixerHandle {
+    fn send(&self, command: MixerCommand) {
+        // only fails if the output stream is gone, and then there's nothing to play to anyway
+        let _ = self.commands.send(command);
+    }
+
+    /// free the decks the mixer has finished with
+    fn drop_retired(&self) {
+        for deck in self.retired.try_iter() {
+            drop(deck);
+        }
+    }
+
+    fn deck(&self, track: &LoadedTrack) -> Deck {
+        Deck::new(
+            track.id,
+            Arc::clone(&track.audio),
+            self.channels,
+            self.sample_rate,
+        )
+    }
+}
+
+/// what the null output is called where a device name is wanted
+const NULL_DEVICE_NAME: &str = "null";
+
+/// Load the playlist's current track.
+/// A track that fails to load is reported and skipped, so this only returns None
+/// when nothing from the current track onwards could be loaded.
+fn load_playlist_track(
+    playlist: &mut Playlist,
+    error_sender: &Sender<AudioError>,
+) -> Option<LoadedTrack> {
+    // in repeat-all next() wraps around, so stop after trying every track once
+    for _ in 0..playlist.entries().len() {
+        let file_path = playlist.current()?.to_path_buf();
+        match load_track(&file_path, error_sender) {
+            Ok(track) => {
+                // so a saved queue gets #EXTINF lines even for tracks from a plain directory
+                if let Some(entry) = playlist.current_mut() {
+                    let info = &track.info;
+                    entry.duration = entry.duration.or(Some(info.duration));
+                    if entry.title.is_none() {
+                        entry.title = Some(match &info.artist {
+                            Some(artist) => format!("{} - {}", artist, info.display_title()),
+                            None => info.display_title(),
+                        });
+                    }
+                }
+                return Some(track);
+            }
+            Err(e) => {
+                warn!("Skipping {:?}: {}", file_path, e);
+                let _ = error_sender.send(e);
+                playlist.next()?;
+            }
+        }
+    }
+    None
+}
+
+fn load_track(
+    file_path: &Path,
+    error_sender: &Sender<AudioError>,
+) -> Result<LoadedTrack, AudioError> {
+    let source = load_audio(file_path)?;
+
+    // missing or broken tags shouldn't stop playback, report and fall back to the decoder's info
+    let mut info = metadata::read_track_info(file_path).unwrap_or_else(|e| {
+        warn!("{}", e);
+        let _ = error_sender.send(e);
+        TrackInfo {
+            path: file_path.into(),
+            duration: source.total_duration().unwrap_or_default(),
+            channels: Some(source.channels() as u8),
+            sample_rate: Some(source.sample_rate()),
+            ..Default::default()
+        }
+    });
+    // done here rather than in the UI thread, decoding a large cover takes a while
+    info.palette = info
+        .cover_art
+        .as_deref()
+        .and_then(Palette::from_image_bytes);
+    // reads a couple of megabytes, so also better off this thread than the UI's
+    info.content_hash = bookmarks::content_hash(file_path)
+        .map_err(|e| {
+            warn!(
+                "Failed to hash {:?}, it can't have bookmarks: {}",
+                file_path, e
+            )
+        })
+        .ok();
+    info!(
+        "Loaded track: {:?} ({:?})",
+        info.display_title(),
+        info.duration
+    );
+
+    // decoded once, in chunks just ahead of the playhead; playback and fft both read from this
+    let audio = SampleStore::new(source);
+    SampleStore::spawn_prefetch(&audio);
+    let sample_rate = audio.sample_rate();
+    let channels = audio.num_channels();
+
+    Ok(LoadedTrack {
+        id: next_track_id(),
+        info,
+        audio,
+        sample_rate,
+        channels,
+    })
+}
+
+/// What a track is being loaded for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LoadFor {
+    /// cut to it, eg. after a skip
+    Play,
+    /// queue it in the mixer to follow the current one
+    Queue,
+}
+
+/// The playlist's current track, for the loader thread to load
+struct LoadRequest {
+    id: u64,
+    purpose: LoadFor,
+    playlist: Playlist,
+}
+
+/// A finished load, with the playlist moved past any tracks that failed to load.
+/// `track` is None if nothing from the requested track onwards could be loaded.
+struct LoadResult {
+    id: u64,
+    purpose: LoadFor,
+    playlist: Playlist,
+    track: Option<LoadedTrack>,
+}
+
+/// Loads tracks on a thread of its own. Probing, the tags, the cover's palette and the content
+/// hash take a while, and the audio thread has to keep up with commands and the mixer meanwhile.
+/// Only the latest request for each purpose counts, older ones are dropped when they finish.
+struct Loader {
+    requests: Sender<LoadRequest>,
+    results: Receiver<LoadResult>,
+    next_id: u64,
+    pending_play: Option<u64>,
+    pending_queue: Option<u64>,
+}
+
+impl Loader {
+    fn spawn(error_sender: Sender<AudioError>) -> Self {
+        let (requests, request_receiver) = mpsc::channel::<LoadRequest>();
+        let (result_sender, results) = mpsc::channel();
+        thread::spawn(move || {
+            for LoadRequest {
+                id,
+                purpose,
+                mut playlist,
+            } in request_receiver
+            {
+                let track = load_playlist_track(&mut playlist, &error_sender);
+                let result = LoadResult {
+                    id,
+                    purpose,
+                    playlist,
+                    track,
+                };
+                if result_sender.send(result).is_err() {
+                    break;
+                }
+            }
+            debug!("Loader thread exiting");
+        });
+        Loader {
+            requests,
+            results,
+            next_id: 0,
+            pending_play: None,
+            pending_queue: None,
+        }
+    }
+
+    /// Load the playlist's current track to cut to it. Whatever was being queued is stale.
+    fn play(&mut self, playlist: &Playlist) {
+        self.pending_play = Some(self.request(LoadFor::Play, playlist.clone()));
+        self.pending_queue = None;
+    }
+
+    /// Load the track that plays after the playlist's current one, so it can be queued to start
+    /// without a gap. Its result has the playlist as it will be once that track plays.
+    fn queue_after(&mut self, playlist: &Playlist) {
+        let mut upcoming = playlist.clone();
+        self.pending_queue = match upcoming.advance() {
+            Some(_) => Some(self.request(LoadFor::Queue, upcoming)),
+            None => None,
+        };
+    }
+
+    fn request(&mut self, purpose: LoadFor, playlist: Playlist) -> u64 {
+        self.next_id += 1;
+        let request = LoadRequest {
+            id: self.next_id,
+            purpose,
+            playlist,
+        };
+        // the thread only stops if loading panicked
+        if self.requests.send(request).is_err() {
+            error!("The loader thread is gone, can't load tracks");
+        }
+        self.next_id
+    }
+
+    /// whether a track to play is still being loaded
+    fn is_loading_play(&self) -> bool {
+        self.pending_play.is_some()
+    }
+
+    /// whether the upcoming track is still being loaded
+    fn is_loading_queue(&self) -> bool {
+        self.pending_queue.is_some()
+    }
+
+    /// the next finished load that's still wanted, if there is one
+    fn try_recv(&mut self) -> Option<LoadResult> {
+        while let Ok(result) = self.results.try_recv() {
+            let pending = match result.purpose {
+                LoadFor::Play => &mut self.pending_play,
+                LoadFor::Queue => &mut self.pending_queue,
+            };
+            if *pending == Some(result.id) {
+                *pending = None;
+                return Some(result);
+            }
+            debug!(
+                "Dropping a {:?} load that's no longer wanted",
+                result.purpose
+            );
+        }
+        None
+    }
+}
+
+/// Show `track` in the UI as the one playing
+fn publish_track(
+    track: &LoadedTrack,
+    index: usize,
+    track_info: &Arc<Mutex<Option<(usize, TrackInfo)>>>,
+) {
+    *track_info.lock().unwrap() = Some((index, track.info.clone()));
+}
+
+/// Seed for the shuffle order when there's no --seed, different every run
+fn shuffle_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// The notes the fft picks out: 12 per octave from C4 up, all silent to start with
+fn set_target_notes(fft_output: &Arc<Mutex<Vec<FreqMagPair>>>, octaves: usize) {
+    // this is out C4 C#4 ... etc data.
+    let output: Vec<FreqMagPair> = generate_note_frequencies(octaves)
+        .into_iter()
+        .map(|x| FreqMagPair { freq: x, mag: 0.0 })
+        .collect();
+    *fft_output.lock().unwrap() = output;
+}
+
+/// How long the audio thread waits for a command before checking on the mixer
+const MIXER_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+fn audio_control_thread(
+    receiver: Receiver<Command>,
+    options: AudioOptions,
+    clock: Arc<MixerStatus>,
+    fft_output: Arc<Mutex<Vec<FreqMagPair>>>,
+    track_info: Arc<Mutex<Option<(usize, TrackInfo)>>>,
+    playback_mode: Arc<Mutex<PlaybackMode>>,
+    volume: Arc<Mutex<VolumeStatus>>,
+    last_fft_generated_at: Arc<Mutex<Option<Instant>>>,
+    error_sender: Sender<AudioError>,
+) -> Result<(), AudioError> {
+    debug!("Audio control thread started");
+
+    // window, threshold and octaves, from the config file and replaced when it changes
+    let mut analysis = options.analysis;
+    set_target_notes(&fft_output, analysis.octaves);
+    // the window depends on the sample rate, so it's planned per track.
+    // The planner caches its plans, so that's only slow the first time for each size.
+    let mut planner = FftPlanner::new();
+
+    // one sink for the whole session, the mixer moves from track to track inside it.
+    // The stream plays it until it's dropped, or the null output does.
+    let (sink, queue) = Sink::new_idle();
+    let (mut output, format) = match options.null_output {
+        Some(speed) => {
+            null_output::spawn(queue, speed, Arc::clone(&clock));
+            (None, device_output::format(None))
+        }
+        None => {
+            let device = device_output::select(options.output_device.as_ref(), &error_sender);
+            let format = device_output::format(device.as_ref());
+            (Some(DeviceOutput::open(device, queue)?), format)
+        }
+    };
+    // playback gain, applied by the sink only. The decoded samples (and so the analysis) are not scaled.
+    let mut gain = Gain::new(options.volume);
+    sink.set_volume(gain.effective());
+    let (channels, sample_rate) = format;
+    let (mixer_source, commands, retired) = Mixer::new(channels, sample_rate, Arc::clone(&clock));
+    sink.append(mixer_source);
+    let mixer = MixerHandle {
+        commands,
+        retired,
+        status: clock,
+        channels,
+        sample_rate,
+    };
+
+    // a calibrated latency for this device wins over the config's and the measured one
+    let mut device = match &output {
+        Some(output) => output.device_name().to_string(),
+        None => NULL_DEVICE_NAME.to_string(),
+    };
+    let mut latency_store = LatencyStore::default_path().and_then(|path| {
+        LatencyStore::open(&path)
+            .map_err(|e| {
+                warn!("{}", e);
+                let _ = error_sender.send(e.into());
+            })
+            .ok()
+    });
+    let mut calibrated_latency = latency_store.as_ref().and_then(|store| store.get(&device));
+    info!(
+        "Output device {:?}, calibrated latency {:?}",
+        device, calibrated_latency
+    );
+
+    // logged so a shuffle order can be reproduced with --seed
+    let seed = options.seed.unwrap_or_else(shuffle_seed);
+    info!("Shuffle seed: {}", seed);
+    let mut playlist = Playlist::new(options.tracks, seed);
+
+    let mut loader = Loader::spawn(error_sender.clone());
+    loader.play(&playlist);
+    let mut current: Option<LoadedTrack> = None;
+    // the track after `current` and the playlist as it will be then, queued in the mixer
+    let mut upcoming: Option<(Playlist, LoadedTrack)> = None;
+    // whether the mixer has started playing `current`, so we can tell when it's done
+    let mut current_heard = false;
+    // --start-at, applied to the first track once it's loaded
+    let mut start_at = options.start_at;
+    info!("Audio ready to play");
+
+    loop {
+        let command = match receiver.recv_timeout(MIXER_POLL_INTERVAL) {
+            Ok(command) => Some(command),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // unplugged, carry on with whatever the default is now and its calibrated latency
+        if output
+            .as_mut()
+            .map_or(false, |output| output.check(&error_sender))
+        {
+            device = output.as_ref().unwrap().device_name().to_string();
+            calibrated_latency = latency_store.as_ref().and_then(|store| store.get(&device));
+            info!(
+                "Output device {:?}, calibrated latency {:?}",
+                device, calibrated_latency
+            );
+        }
+
+        // fades move on every poll, so they step every MIXER_POLL_INTERVAL at most
+        let now = Instant::now();
+        let sleep = gain.tick(now);
+        if sleep {
+            info!("Sleep timer ran out, pausing");
+            sink.pause();
+            gain.reset_fade();
+        }
+        sink.set_volume(gain.effective());
+        let latency = calibrated_latency
+            .or(analysis
+                .output_latency_ms
+                .map(|ms| Duration::from_secs_f32(ms / 1000.0)))
+            .unwrap_or_else(|| match options.null_output {
+                // the null output's bursts aren't a device's buffers, it has no latency
+                Some(_) => Duration::ZERO,
+                // the measured buffer is only a lower bound, so it's used when it's longer
+                None => mixer
+                    .status
+                    .measured_latency()
+                    .map_or(DEFAULT_LATENCY, |measured| measured.max(DEFAULT_LATENCY)),
+            });
+        mixer.status.set_latency(latency);
+        {
+            let mut volume = volume.lock().unwrap();
+            // stays set until the UI has seen it
+            let asleep = volume.asleep || sleep;
+            *volume = VolumeStatus {
+                asleep,
+                ..gain.status(now)
+            };
+        }
+
+        mixer.drop_retired();
+
+        // shuffle / repeat / crossfade changed, so what comes next may have too
+        let mode = *playback_mode.lock().unwrap();
+        if mode != playlist.mode() {
+            playlist.set_mode(mode);
+            mixer.send(MixerCommand::SetCrossfade(mode.crossfade));
+            mixer.send(MixerCommand::ClearQueue);
+            upcoming = None;
+            loader.queue_after(&playlist);
+        }
+
+        while let Some(LoadResult {
+            purpose,
+            playlist: loaded,
+            track,
+            ..
+        }) = loader.try_recv()
+        {
+            let Some(track) = track else {
+                // nothing more to load, keep playing what there is
+                continue;
+            };
+            // the current track can run out before the next one is ready, then it starts now
+            let ran_out = current_heard && mixer.status.audible_id().is_none();
+            if purpose == LoadFor::Queue && !ran_out {
+                debug!("Queued {:?}", track.info.display_title());
+                mixer.send(MixerCommand::Queue(mixer.deck(&track)));
+                upcoming = Some((loaded, track));
+                continue;
+            }
+            mixer.send(MixerCommand::Play(mixer.deck(&track)));
+            if let Some(position) = start_at.take() {
+                if let Err(e) = sink.try_seek(position) {
+                    warn!("Failed to start at {:?}: {}", position, e);
+                    let _ = error_sender.send(e.into());
+                }
+            }
+            playlist = loaded;
+            publish_track(&track, playlist.index(), &track_info);
+            current = Some(track);
+            current_heard = false;
+            upcoming = None;
+            loader.queue_after(&playlist);
+        }
+
+        // follow the mixer: the analysis and the UI switch to the next track when it becomes audible
+        let audible = mixer.status.audible_id();
+        let current_id = current.as_ref().map(|track| track.id);
+        let upcoming_id = upcoming.as_ref().map(|(_, track)| track.id);
+        if audible.is_some() && audible == current_id {
+            current_heard = true;
+        } else if audible.is_some() && audible == upcoming_id {
+            let (next_playlist, track) = upcoming.take().unwrap();
+            info!("Now playing {:?}", track.info.display_title());
+            playlist = next_playlist;
+            publish_track(&track, playlist.index(), &track_info);
+            current = Some(track);
+            current_heard = true;
+            loader.queue_after(&playlist);
+        } else if audible.is_none() && current_heard && !loader.is_loading_queue() {
+            info!("Reached the end of the playlist");
+            current = None;
+            current_heard = false;
+        }
+        if options.exit_at_end && current.is_none() && !loader.is_loading_play() {
+            break;
+        }
+
+        let Some(command) = command else {
+            continue;
+        };
+        match command {
+            Command::CalculateFFT => {
+                let Some(track) = &current else {
+                    continue;
+                };
+                let (audio, sample_rate, channels) =
+                    (&track.audio, track.sample_rate, track.channels);
+                let window_size = analysis.window_size(sample_rate);
+                let fft = planner.plan_fft_forward(window_size);
+                // let elapsed = last_play_time.elapsed();
+                let t1 = Instant::now();
+                // the frame being heard: where the output has reached in this track, exact even
+                // while looping, less what's still on its way through the output
+                let latency = mixer.status.latency();
+                let latency_frames = (latency.as_secs_f64() * sample_rate as f64) as usize;
+                let audible_frame = audio.playhead().saturating_sub(latency_frames);
+                // the window is centred on it, so it shows what's heard rather than what's next
+                let samples_offset = audible_frame.saturating_sub(window_size / 2);
+                let start_pos = Duration::from_secs_f64(audible_frame as f64 / sample_rate as f64);
+                trace!("playback at: (based on samples played) {:?}", start_pos);
+
+                // calculate fft for a given channel
+                let process_fft_for_channel = |channel_num: usize| {
+                    trace!("Calculating FFT for channel: {:?}", channel_num);
+                    // after a seek this decodes the window on demand
+                    if let Some(samples) = audio.read(channel_num, samples_offset, window_size) {
+                        let mut buffer: Vec<Complex<f32>> =
+                            samples.iter().map(|&x| Complex::new(x, 0.0)).collect();
+                        trace!(
+                            "-- sample offset {:?} window size {:?}",
+                            samples_offset,
+                            window_size
+                        );
+                        // info!("-- buffer len:{:?} {:?}",buffer.len(), buffer);
+                        fft.process(&mut buffer); // Perform FFT in-place
+
+                        // Display the frequency and magnitude information
+                        display_frequencies(
+                            &buffer,
+                            sample_rate as usize,
+                            window_size,
+                            analysis.threshold_dbfs,
+                            Arc::clone(&fft_output),
+                        );
+                    } else {
+                        debug!(
+                            "Not enough data available for FFT calculation at the current position."
+                        );
+                    }
+                };
+
+                let want_to_add = false; // DON"T SET TO TRUE!! DOESN"T WORKKKKKK
+                let mut res_buffer: Vec<Complex<f32>> = vec![Complex::new(0.0, 0.0); window_size];
+
+                if !want_to_add {
+                    // calculate for only channel 0
+                    process_fft_for_channel(0);
+                } else {
+                    // TODO: use the process_fft_for_channel closure here also (slight modfn needed)
+                    // add all fft result into the res_buffer
+
+                    // calc fft for each buffer
+                    for i in 0..channels as usize {
+                        trace!("Calculating FFT for channel: {:?}", i);
+                        if let Some(samples) = audio.read(i, samples_offset, window_size) {
+                            let mut buffer: Vec<Complex<f32>> =
+                                samples.iter().map(|&x| Complex::new(x, 0.0)).collect();
+                            fft.process(&mut buffer); // Perform FFT in-place
+                            for (j, val) in buffer.iter().enumerate() {
+                                res_buffer[j] = res_buffer[j] + val;
+                            }
+                        } else {
+                            debug!(
+                                "Not enough data available for FFT calculation at the current position."
+                            );
+                        }
+                    }
+                    display_frequencies(
+                        &res_buffer,
+                        sample_rate as usize,
+                        window_size,
+                        analysis.threshold_dbfs,
+                        Arc::clone(&fft_output),
+                    );
+                }
+
+                trace!("FFT Complete: Time taken to complete: {:?}", t1.elapsed());
+                let mut lock = last_fft_generated_at.lock().unwrap();
+                // if the last fft generated was at Some(instant), then we can calculate the time difference
+                if let Some(last_time) = *lock {
+                    let time_diff = Instant::now() - last_time;
+                    trace!("Time since last FFT : {:?} ", time_diff);
+                }
+                *lock = Some(Instant::now());
+            }
+            Command::Play => {
+                info!("Playing");
+                // after a fade out or the sleep timer, come back at full volume
+                gain.reset_fade();
+                sink.set_volume(gain.effective());
+                sink.play();
+            }
+            Command::Pause => {
+                info!("Pausing");
+                // the clock stops too, the paused sink stops taking samples from the mixer
+                sink.pause();
+            }
+            Command::Seek(position) => {
+                if current.is_none() {
+                    continue;
+                }
+                info!("Seeking to {:?}", position);
+                if let Err(e) = sink.try_seek(position) {
+                    warn!("Failed to seek: {}", e);
+                    // not fatal, keep playing from where we were
+                    let _ = error_sender.send(e.into());
+                } else {
+                    sink.play();
+                }
+            }
+            Command::Next | Command::Previous | Command::JumpTo(_) => {
+                // moved on a copy, `playlist` follows what's playing and only changes when
+                // the load arrives. The queued track can still become audible meanwhile.
+                let mut target = playlist.clone();
+                let moved = match command {
+                    Command::Next => target.next().is_some(),
+                    Command::Previous => target.previous().is_some(),
+                    Command::JumpTo(index) => target.jump_to(index).is_some(),
+                    _ => unreachable!(),
+                };
+                if !moved {
+                    info!("No track to move to, staying on the current one");
+                    continue;
+                }
+                // a manual skip cuts straight to the new track once it's loaded, no crossfade
+                loader.play(&target);
+            }
+            Command::SavePlaylist(path) => match playlist_file::save(&path, playlist.entries()) {
+                Ok(()) => info!("Saved the queue to {:?}", path),
+                Err(e) => {
+                    warn!("{}", e);
+                    let _ = error_sender.send(e.into());
+                }
+            },
+            Command::SetVolume(new_volume) => {
+                gain.set_volume(new_volume);
+                info!("Volume: {:.0}%", gain.volume() * 100.0);
+            }
+            Command::Mute => gain.set_muted(true),
+            Command::Unmute => gain.set_muted(false),
+            Command::FadeIn(length) => gain.fade_in(length, Instant::now()),
+            Command::FadeOut(length) => gain.fade_out(length, Instant::now()),
+            Command::SleepTimer(after) => {
+                info!("Sleep timer: {:?}", after);
+                gain.set_sleep_timer(after, Instant::now());
+            }
+            Command::Calibrate(on) => {
+                info!(
+                    "Latency calibration {}",
+                    if on { "started" } else { "finished" }
+                );
+                mixer.send(MixerCommand::Calibrate(on));
+                if on {
+                    // the clicks are played through the sink, so it can't stay paused
+                    sink.play();
+                    continue;
+                }
+                let Some(latency) = calibrated_latency else {
+                    continue;
+                };
+                if let Some(store) = latency_store.as_mut() {
+                    match store.set(&device, latency) {
+                        Ok(()) => info!("Saved latency {:?} for {:?}", latency, device),
+                        Err(e) => {
+                            warn!("{}", e);
+                            let _ = error_sender.send(e.into());
+                        }
+                    }
+                }
+            }
+            Command::SetLatency(latency) => {
+                calibrated_latency = Some(latency);
+                mixer.status.set_latency(latency);
+            }
+            Command::SetLoop(region) => {
+                let Some(track) = &current else {
+                    continue;
+                };
+                // in the track's own frames, the store loops before any resampling
+                let to_frame = |t: Duration| (t.as_secs_f64() * track.sample_rate as f64) as usize;
+                track
+                    .audio
+                    .set_loop(region.map(|(start, end)| to_frame(start)..to_frame(end)));
+            }
+            Command::SetAnalysis(new_analysis) => {
+                info!("Analysis settings: {:?}", new_analysis);
+                if new_analysis.octaves != analysis.octaves {
+                    set_target_notes(&fft_output, new_analysis.octaves);
+                }
+                analysis = new_analysis;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `--live`: analyse the input device like a playing track. Nothing plays, so only the
+/// analysis commands do anything.
+fn live_control_thread(
+    receiver: Receiver<Command>,
+    options: AudioOptions,
+    fft_output: Arc<Mutex<Vec<FreqMagPair>>>,
+    input_level: Arc<Mutex<Option<InputLevel>>>,
+    error_sender: Sender<AudioError>,
+) -> Result<(), AudioError> {
+    let mut analysis = options.analysis;
+    set_target_notes(&fft_output, analysis.octaves);
+    let mut planner = FftPlanner::new();
+
+    let host = nannou_audio::Host::new();
+    // the --input-device, or the default if there's no selection or it's gone
+    let device = options.input_device.as_ref().and_then(|selector| {
+        let device = devices::find_input(&host, selector);
+        if device.is_none() {
+            let e = AudioError::DeviceNotFound {
+                direction: "input",
+                device: selector.clone(),
+            };
+            warn!("{}", e);
+            let _ = error_sender.send(e);
+        }
+        device
+    });
+    let mut input = LiveInput::open(&host, device)?;
+    let mut sample_rate = input.sample_rate();
+    input.set_history(analysis.window_size(sample_rate));
+
+    loop {
+        let command = match receiver.recv_timeout(MIXER_POLL_INTERVAL) {
+            Ok(command) => Some(command),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        *input_level.lock().unwrap() = Some(input.take_level());
+
+        // unplugged, carry on with whatever the default is now
+        if input.is_lost() {
+            warn!("Input device went away, switching to the default");
+            input = LiveInput::open(&host, None)?;
+            sample_rate = input.sample_rate();
+            input.set_history(analysis.window_size(sample_rate));
+        }
+
+        let Some(command) = command else {
+            continue;
+        };
+        match command {
+            Command::CalculateFFT => {
+                let window_size = analysis.window_size(sample_rate);
+                // the latest window of channel 0, the same channel tracks are analysed on
+                let Some(samples) = input.latest(0, window_size) else {
+                    debug!("Not enough input captured for the FFT yet");
+                    continue;
+                };
+                let mut buffer: Vec<Complex<f32>> =
+                    samples.iter().map(|&x| Complex::new(x, 0.0)).collect();
+                planner.plan_fft_forward(window_size).process(&mut buffer);
+                display_frequencies(
+                    &buffer,
+                    sample_rate as usize,
+                    window_size,
+                    analysis.threshold_dbfs,
+                    Arc::clone(&fft_output),
+                );
+            }
+            Command::SetAnalysis(new_analysis) => {
+                info!("Analysis settings: {:?}", new_analysis);
+                if new_analysis.octaves != analysis.octaves {
+                    set_target_notes(&fft_output, new_analysis.octaves);
+                }
+                input.set_history(new_analysis.window_size(sample_rate));
+                analysis = new_analysis;
+            }
+            command => debug!("Ignoring {:?} in live mode", command),
+        }
+    }
+    Ok(())
+}
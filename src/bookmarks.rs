@@ -0,0 +1,383 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::error::BookmarkError;
+use crate::toml_file::{read_toml, write_toml_atomically};
+
+/// How much of the start and end of a file goes into its content hash
+const HASH_SPAN: u64 = 1024 * 1024;
+
+/// A named position in a track
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    /// stored as seconds
+    #[serde(serialize_with = "to_seconds", deserialize_with = "from_seconds")]
+    pub position: Duration,
+}
+
+/// Which track a set of bookmarks belongs to: its path, plus a hash of its contents so
+/// the bookmarks follow the file when it's moved or renamed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackKey {
+    pub path: PathBuf,
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct TrackBookmarks {
+    path: PathBuf,
+    hash: String,
+    /// sorted by position
+    #[serde(default, rename = "bookmark")]
+    bookmarks: Vec<Bookmark>,
+}
+
+/// The layout of bookmarks.toml:
+///
+/// ```toml
+/// [[track]]
+/// path = "/home/me/Music/etude.flac"
+/// hash = "5d1c9a0e7f3b2a64"
+///
+/// [[track.bookmark]]
+/// name = "bridge"
+/// position = 83.5
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BookmarkFile {
+    #[serde(default, rename = "track")]
+    tracks: Vec<TrackBookmarks>,
+}
+
+/// Bookmarks for every track, in one file in the data dir. Saved after every change.
+#[derive(Debug)]
+pub struct BookmarkStore {
+    path: PathBuf,
+    file: BookmarkFile,
+}
+
+impl BookmarkStore {
+    /// `$XDG_DATA_HOME/oxy-viz/bookmarks.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        Some(crate::config::data_dir()?.join("bookmarks.toml"))
+    }
+
+    /// Read the store at `path`, empty if it doesn't exist yet
+    pub fn open(path: &Path) -> Result<Self, BookmarkError> {
+        let file: BookmarkFile =
+            read_toml(path).map_err(|err| BookmarkError::from_toml_file(path, err))?;
+        debug!(
+            "Loaded bookmarks for {} tracks from {:?}",
+            file.tracks.len(),
+            path
+        );
+        Ok(BookmarkStore {
+            path: path.into(),
+            file,
+        })
+    }
+
+    /// The track's bookmarks, sorted by position.
+    /// A track is found by its hash first, so a moved or renamed file keeps its bookmarks,
+    /// then by its path, so it keeps them when its tags are edited too.
+    pub fn get(&self, key: &TrackKey) -> &[Bookmark] {
+        self.find(key)
+            .map(|i| self.file.tracks[i].bookmarks.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Add a bookmark and save. Returns its index in `get()`.
+    pub fn add(&mut self, key: &TrackKey, bookmark: Bookmark) -> Result<usize, BookmarkError> {
+        let track = self.track_mut(key);
+        let index = track
+            .bookmarks
+            .partition_point(|b| b.position <= bookmark.position);
+        track.bookmarks.insert(index, bookmark);
+        self.save()?;
+        Ok(index)
+    }
+
+    /// Rename the bookmark at `index` in `get()` and save
+    pub fn rename(
+        &mut self,
+        key: &TrackKey,
+        index: usize,
+        name: String,
+    ) -> Result<(), BookmarkError> {
+        if index >= self.get(key).len() {
+            return Err(BookmarkError::NotFound { index });
+        }
+        self.track_mut(key).bookmarks[index].name = name;
+        self.save()
+    }
+
+    /// Remove the bookmark at `index` in `get()` and save
+    pub fn remove(&mut self, key: &TrackKey, index: usize) -> Result<Bookmark, BookmarkError> {
+        if index >= self.get(key).len() {
+            return Err(BookmarkError::NotFound { index });
+        }
+        let bookmark = self.track_mut(key).bookmarks.remove(index);
+        // don't keep tracks around with nothing in them
+        self.file.tracks.retain(|track| !track.bookmarks.is_empty());
+        self.save()?;
+        Ok(bookmark)
+    }
+
+    fn find(&self, key: &TrackKey) -> Option<usize> {
+        let tracks = &self.file.tracks;
+        tracks
+            .iter()
+            .position(|track| track.hash == key.hash)
+            .or_else(|| tracks.iter().position(|track| track.path == key.path))
+    }
+
+    /// the track's entry, created if it has none yet, so anything but adding checks `find`
+    /// first. A moved or edited file gets its new path and hash.
+    fn track_mut(&mut self, key: &TrackKey) -> &mut TrackBookmarks {
+        let index = match self.find(key) {
+            Some(index) => index,
+            None => {
+                self.file.tracks.push(TrackBookmarks {
+                    path: key.path.clone(),
+                    hash: key.hash.clone(),
+                    bookmarks: vec![],
+                });
+                self.file.tracks.len() - 1
+            }
+        };
+        let track = &mut self.file.tracks[index];
+        if track.path != key.path {
+            info!("Bookmarks of {:?} moved to {:?}", track.path, key.path);
+            track.path = key.path.clone();
+        }
+        track.hash = key.hash.clone();
+        track
+    }
+
+    fn save(&self) -> Result<(), BookmarkError> {
+        write_toml_atomically(&self.path, &self.file)
+            .map_err(|err| BookmarkError::from_toml_file(&self.path, err))
+    }
+}
+
+/// The first bookmark after `position`
+pub fn next_after(bookmarks: &[Bookmark], position: Duration) -> Option<&Bookmark> {
+    bookmarks.iter().find(|b| b.position > position)
+}
+
+/// The last bookmark more than `grace` before `position`. The grace period means pressing
+/// previous right after landing on a bookmark goes to the one before it, not back to the same one.
+pub fn previous_before(
+    bookmarks: &[Bookmark],
+    position: Duration,
+    grace: Duration,
+) -> Option<&Bookmark> {
+    bookmarks
+        .iter()
+        .rev()
+        .find(|b| b.position + grace < position)
+}
+
+/// Index of the bookmark closest to `position`
+pub fn closest(bookmarks: &[Bookmark], position: Duration) -> Option<usize> {
+    let distance = |b: &Bookmark| {
+        if b.position > position {
+            b.position - position
+        } else {
+            position - b.position
+        }
+    };
+    (0..bookmarks.len()).min_by_key(|&i| distance(&bookmarks[i]))
+}
+
+/// Identifies a file by its contents: a 64 bit FNV-1a hash of its size and its first and
+/// last megabyte, as hex. Hashing all of a long FLAC would take too long to do on every load,
+/// and a re-encode or edit changes the size or the ends.
+pub fn content_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hash = Fnv1a::new();
+    hash.write(&size.to_le_bytes());
+
+    let mut buffer = vec![0; HASH_SPAN.min(size) as usize];
+    file.read_exact(&mut buffer)?;
+    hash.write(&buffer);
+    if size > HASH_SPAN {
+        let tail_start = size.saturating_sub(HASH_SPAN).max(HASH_SPAN);
+        file.seek(SeekFrom::Start(tail_start))?;
+        buffer.clear();
+        file.read_to_end(&mut buffer)?;
+        hash.write(&buffer);
+    }
+    Ok(format!("{:016x}", hash.finish()))
+}
+
+/// std's hasher isn't guaranteed to give the same hash in the next Rust release,
+/// and these are saved to disk
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn to_seconds<S: Serializer>(position: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    // to the millisecond, so the file doesn't fill up with float noise
+    serializer.serialize_f64((position.as_secs_f64() * 1000.0).round() / 1000.0)
+}
+
+fn from_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let seconds = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(seconds).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn bookmark(name: &str, seconds: u64) -> Bookmark {
+        Bookmark {
+            name: name.to_string(),
+            position: Duration::from_secs(seconds),
+        }
+    }
+
+    fn key(path: &str, hash: &str) -> TrackKey {
+        TrackKey {
+            path: path.into(),
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn content_hash_is_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.mp3"), "hello").unwrap();
+        fs::write(dir.path().join("b.mp3"), "hello").unwrap();
+        fs::write(dir.path().join("c.mp3"), "hellp").unwrap();
+        let hash = |name: &str| content_hash(&dir.path().join(name)).unwrap();
+        // saved in bookmarks.toml, so it must never change
+        assert_eq!(hash("a.mp3"), "ff7a61ff11320f78");
+        assert_eq!(hash("a.mp3"), hash("b.mp3"));
+        assert_ne!(hash("a.mp3"), hash("c.mp3"));
+    }
+
+    #[test]
+    fn content_hash_covers_both_ends_of_a_large_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut contents = vec![0u8; 3 * HASH_SPAN as usize];
+        fs::write(dir.path().join("a.flac"), &contents).unwrap();
+        let before = content_hash(&dir.path().join("a.flac")).unwrap();
+        // the middle isn't hashed, the end is
+        contents[HASH_SPAN as usize + 10] = 1;
+        fs::write(dir.path().join("a.flac"), &contents).unwrap();
+        assert_eq!(content_hash(&dir.path().join("a.flac")).unwrap(), before);
+        *contents.last_mut().unwrap() = 1;
+        fs::write(dir.path().join("a.flac"), &contents).unwrap();
+        assert_ne!(content_hash(&dir.path().join("a.flac")).unwrap(), before);
+    }
+
+    #[test]
+    fn tracks_are_found_by_hash_then_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bookmarks.toml");
+        let mut store = BookmarkStore::open(&path).unwrap();
+        store
+            .add(&key("/music/a.flac", "aaaa"), bookmark("intro", 5))
+            .unwrap();
+
+        // moved: same contents, new path
+        assert_eq!(store.get(&key("/elsewhere/a.flac", "aaaa")).len(), 1);
+        // tags edited: same path, new contents
+        assert_eq!(store.get(&key("/music/a.flac", "bbbb")).len(), 1);
+        assert!(store.get(&key("/music/other.flac", "cccc")).is_empty());
+
+        // and it's all still there after a reload
+        let store = BookmarkStore::open(&path).unwrap();
+        assert_eq!(
+            store.get(&key("/music/a.flac", "aaaa")),
+            &[bookmark("intro", 5)]
+        );
+    }
+
+    #[test]
+    fn bookmarks_are_sorted_by_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = BookmarkStore::open(&dir.path().join("bookmarks.toml")).unwrap();
+        let track = key("/music/a.flac", "aaaa");
+        assert_eq!(store.add(&track, bookmark("b", 20)).unwrap(), 0);
+        assert_eq!(store.add(&track, bookmark("a", 10)).unwrap(), 0);
+        assert_eq!(store.add(&track, bookmark("c", 30)).unwrap(), 2);
+        let names: Vec<&str> = store.get(&track).iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn unknown_bookmarks_are_not_found_and_nothing_is_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bookmarks.toml");
+        let mut store = BookmarkStore::open(&path).unwrap();
+        let unknown = key("/music/unknown.flac", "ffff");
+        assert!(matches!(
+            store.rename(&unknown, 0, "x".to_string()),
+            Err(BookmarkError::NotFound { index: 0 })
+        ));
+        assert!(matches!(
+            store.remove(&unknown, 0),
+            Err(BookmarkError::NotFound { index: 0 })
+        ));
+        assert!(store.file.tracks.is_empty());
+        assert!(!path.exists());
+
+        let track = key("/music/a.flac", "aaaa");
+        store.add(&track, bookmark("a", 10)).unwrap();
+        assert!(store.rename(&track, 1, "x".to_string()).is_err());
+        store.rename(&track, 0, "renamed".to_string()).unwrap();
+        assert_eq!(store.remove(&track, 0).unwrap(), bookmark("renamed", 10));
+        assert!(store.file.tracks.is_empty());
+    }
+
+    #[test]
+    fn next_and_previous() {
+        let bookmarks = [bookmark("a", 10), bookmark("b", 20), bookmark("c", 30)];
+        let at = Duration::from_secs;
+        let grace = Duration::from_secs(1);
+        let name = |b: Option<&Bookmark>| b.map(|b| b.name.clone());
+
+        assert_eq!(name(next_after(&bookmarks, at(0))), Some("a".into()));
+        assert_eq!(name(next_after(&bookmarks, at(10))), Some("b".into()));
+        assert_eq!(name(next_after(&bookmarks, at(30))), None);
+
+        assert_eq!(
+            name(previous_before(&bookmarks, at(25), grace)),
+            Some("b".into())
+        );
+        // just landed on b, so previous goes past it
+        assert_eq!(
+            name(previous_before(&bookmarks, at(20) + grace / 2, grace)),
+            Some("a".into())
+        );
+        assert_eq!(name(previous_before(&bookmarks, at(10), grace)), None);
+
+        assert_eq!(closest(&bookmarks, at(24)), Some(1));
+        assert_eq!(closest(&[], at(24)), None);
+    }
+}
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::f32::consts::TAU;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::debug;
+
+use crate::error::LatencyError;
+use crate::toml_file::{read_toml, write_toml_atomically};
+
+/// Time between calibration clicks
+pub const CLICK_INTERVAL: Duration = Duration::from_millis(750);
+/// How long a click rings for
+const CLICK_LENGTH: Duration = Duration::from_millis(15);
+const CLICK_FREQUENCY: f32 = 1000.0;
+/// How long the flash that goes with a click stays on
+const FLASH_LENGTH: Duration = Duration::from_millis(80);
+/// How much the Up / Down keys change the latency by while calibrating
+pub const LATENCY_STEP: Duration = Duration::from_millis(5);
+/// The most latency calibration goes up to
+pub const MAX_LATENCY: Duration = Duration::from_secs(1);
+/// The output latency when it's neither calibrated nor configured, or the output's measured
+/// buffer if that's longer
+pub const DEFAULT_LATENCY: Duration = Duration::from_millis(40);
+
+/// The click track's sample `frame` frames in: a short, decaying 1kHz beep every CLICK_INTERVAL
+pub fn click_sample(frame: u64, sample_rate: u32) -> f32 {
+    let interval = (CLICK_INTERVAL.as_secs_f64() * sample_rate as f64) as u64;
+    let t = (frame % interval.max(1)) as f32 / sample_rate as f32;
+    let length = CLICK_LENGTH.as_secs_f32();
+    if t >= length {
+        return 0.0;
+    }
+    0.5 * (1.0 - t / length) * (TAU * CLICK_FREQUENCY * t).sin()
+}
+
+/// Whether the flash is on while the click track is heard `heard` in.
+/// The flash starts on the frame the click does, so with the right latency they line up.
+pub fn is_flash(heard: Duration) -> bool {
+    let into_click = heard.as_secs_f64() % CLICK_INTERVAL.as_secs_f64();
+    into_click < FLASH_LENGTH.as_secs_f64()
+}
+
+/// The layout of latency.toml, milliseconds by output device name:
+///
+/// ```toml
+/// [devices]
+/// "Built-in Audio Analog Stereo" = 42.5
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LatencyFile {
+    #[serde(default)]
+    devices: BTreeMap<String, f64>,
+}
+
+/// The calibrated output latency of every device it's been calibrated on, in one file in the
+/// data dir.
+#[derive(Debug)]
+pub struct LatencyStore {
+    path: PathBuf,
+    file: LatencyFile,
+}
+
+impl LatencyStore {
+    /// `$XDG_DATA_HOME/oxy-viz/latency.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        Some(crate::config::data_dir()?.join("latency.toml"))
+    }
+
+    /// Read the store at `path`, empty if it doesn't exist yet
+    pub fn open(path: &Path) -> Result<Self, LatencyError> {
+        let file: LatencyFile =
+            read_toml(path).map_err(|err| LatencyError::from_toml_file(path, err))?;
+        debug!(
+            "Loaded latencies for {} devices from {:?}",
+            file.devices.len(),
+            path
+        );
+        Ok(LatencyStore {
+            path: path.into(),
+            file,
+        })
+    }
+
+    /// The device's calibrated latency, if it's been calibrated
+    pub fn get(&self, device: &str) -> Option<Duration> {
+        let ms = *self.file.devices.get(device)?;
+        Duration::try_from_secs_f64(ms / 1000.0).ok()
+    }
+
+    /// Save the device's calibrated latency
+    pub fn set(&mut self, device: &str, latency: Duration) -> Result<(), LatencyError> {
+        // to a tenth of a millisecond, so the file doesn't fill up with float noise
+        let ms = (latency.as_secs_f64() * 10_000.0).round() / 10.0;
+        self.file.devices.insert(device.to_string(), ms);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), LatencyError> {
+        write_toml_atomically(&self.path, &self.file)
+            .map_err(|err| LatencyError::from_toml_file(&self.path, err))
+    }
+}
@@ -0,0 +1,122 @@
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::MAX_OCTAVES;
+use crate::devices::{DeviceSelector, ListFormat};
+use crate::null_output::NullSpeed;
+use crate::render_drawing::Visualization;
+
+/// Oxy-Viz: an FFT audio visualizer.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Audio files, directories (searched recursively) or M3U/M3U8/PLS playlists, played in order
+    #[arg(value_name = "PATH", required_unless_present_any = ["live", "list_devices"])]
+    pub inputs: Vec<PathBuf>,
+
+    /// Print the tags and audio properties of the inputs and exit
+    #[arg(long)]
+    pub info: bool,
+
+    /// How the notes are drawn
+    #[arg(long, value_enum, default_value_t = Visualization::CircleWaveMultiple)]
+    pub visualizer: Visualization,
+
+    /// Number of octaves to analyse, starting at C4. Overrides the config file
+    #[arg(long, value_parser = parse_octaves)]
+    pub octaves: Option<usize>,
+
+    /// Config file to use instead of $XDG_CONFIG_HOME/oxy-viz/config.toml
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Write logs to this file instead of stdout. Overrides the config file
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Start the first track at this position, as seconds or [h:]m:ss
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub start_at: Option<Duration>,
+
+    /// Playback volume, from 0.0 (silent) to 1.0 (full scale)
+    #[arg(long, default_value_t = 0.25, value_parser = parse_volume)]
+    pub volume: f32,
+
+    /// Seed for the shuffle order, so it can be played again the same. Random if not given
+    #[arg(long, value_name = "SEED", conflicts_with = "live")]
+    pub seed: Option<u64>,
+
+    /// Play without opening a window, exits when the playlist ends
+    #[arg(long)]
+    pub no_window: bool,
+
+    /// Visualize the default input device (a microphone or line-in) instead of files
+    #[arg(long, conflicts_with_all = ["inputs", "info", "start_at", "no_window"])]
+    pub live: bool,
+
+    /// Print the input and output devices with their supported formats and exit
+    #[arg(long, value_name = "FORMAT", value_enum, num_args = 0..=1, default_missing_value = "text")]
+    pub list_devices: Option<ListFormat>,
+
+    /// Play through this device, by name or index in --list-devices, instead of the default
+    #[arg(long, value_name = "DEVICE")]
+    pub output_device: Option<DeviceSelector>,
+
+    /// Play to no device at all, in real time or as fast as possible. For machines without a sound card
+    #[arg(
+        long,
+        value_name = "SPEED",
+        value_enum,
+        num_args = 0..=1,
+        default_missing_value = "realtime",
+        conflicts_with_all = ["output_device", "live"]
+    )]
+    pub null_output: Option<NullSpeed>,
+
+    /// With --live, capture from this device, by name or index in --list-devices, instead of the default
+    #[arg(long, value_name = "DEVICE", requires = "live")]
+    pub input_device: Option<DeviceSelector>,
+}
+
+fn parse_octaves(s: &str) -> Result<usize, String> {
+    let octaves: usize = s
+        .parse()
+        .map_err(|_| format!("`{}` is not a whole number", s))?;
+    if !(1..=MAX_OCTAVES).contains(&octaves) {
+        return Err(format!("must be between 1 and {}", MAX_OCTAVES));
+    }
+    Ok(octaves)
+}
+
+fn parse_volume(s: &str) -> Result<f32, String> {
+    let volume: f32 = s.parse().map_err(|_| format!("`{}` is not a number", s))?;
+    if !(0.0..=1.0).contains(&volume) {
+        return Err("must be between 0.0 and 1.0".to_string());
+    }
+    Ok(volume)
+}
+
+/// "95", "95.5", "1:35" or "1:01:35"
+pub fn parse_timestamp(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("`{}` is not a time, expected seconds or [h:]m:ss", s);
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() > 3 {
+        return Err(invalid());
+    }
+    let (seconds, whole_units) = parts.split_last().ok_or_else(invalid)?;
+    let seconds: f64 = seconds.parse().map_err(|_| invalid())?;
+    if !seconds.is_finite() || seconds < 0.0 || (!whole_units.is_empty() && seconds >= 60.0) {
+        return Err(invalid());
+    }
+    // hours and minutes, most significant first
+    let mut total = 0.0;
+    for (i, unit) in whole_units.iter().enumerate() {
+        let value: u32 = unit.parse().map_err(|_| invalid())?;
+        if i > 0 && value >= 60 {
+            return Err(invalid());
+        }
+        total = total * 60.0 + value as f64;
+    }
+    Ok(Duration::from_secs_f64(total * 60.0 + seconds))
+}
@@ -0,0 +1,428 @@
+use nannou::prelude::*;
+use serde::{de, Deserialize, Deserializer};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info};
+use tracing_subscriber::EnvFilter;
+
+use crate::calibration;
+use crate::error::ConfigError;
+use crate::palette::Palette;
+
+/// The note frequencies start at C4, past 6 octaves they'd go above what most files contain
+pub const MAX_OCTAVES: usize = 6;
+
+/// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Settings from `config.toml`. Every key is optional, missing ones get the defaults below.
+///
+/// ```toml
+/// [draw]
+/// frame_rate = 60
+/// color_scheme = "cover-art"  # or "fixed"
+/// resolution = [1024, 768]
+/// num_samples = 400
+///
+/// [analysis]
+/// window_seconds = 0.5
+/// threshold_dbfs = -80.0
+/// octaves = 4
+/// output_latency_ms = 40.0  # 40ms, or the output's buffer if longer, if not set
+///
+/// [colors]
+/// background = "#6495ed"
+/// waves = ["#ff0000"]
+/// text = "#000000"
+///
+/// [colors.ui]
+/// text = "#000000"
+/// seek_bar = "#0000ff"
+/// play_button = "#ff0000"
+/// fav_play_button = "#ff0000"
+/// fav_record_button = "#0000ff"
+/// bookmark = "#ffa500"
+/// loop_region = "#32cd32"
+/// level_meter = "#008000"
+///
+/// [logging]
+/// filter = "audio_vis=info"
+/// format = "text"  # or "json"
+/// file = "logs/oxy-viz.log"  # stdout if not set
+/// rotation = "daily"  # "never", "daily" or "size"
+/// max_size_mb = 10
+/// max_files = 7
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub draw: DrawSettings,
+    pub analysis: AnalysisSettings,
+    pub colors: ColorSettings,
+    pub logging: LoggingSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DrawSettings {
+    /// updates (and so redraws) per second
+    pub frame_rate: usize,
+    pub color_scheme: ColorScheme,
+    /// window size in points, width and height
+    pub resolution: [u32; 2],
+    /// number of samples to generate from the spline curve of each ring
+    pub num_samples: usize,
+}
+
+impl Default for DrawSettings {
+    fn default() -> Self {
+        DrawSettings {
+            frame_rate: 60,
+            color_scheme: ColorScheme::CoverArt,
+            resolution: [1024, 768],
+            num_samples: 400,
+        }
+    }
+}
+
+/// Where the visualizer's colours come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorScheme {
+    /// the track's cover art, `[colors]` for tracks without one
+    CoverArt,
+    /// always `[colors]`
+    Fixed,
+}
+
+/// Read by the audio thread
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalysisSettings {
+    /// length of the fft window
+    pub window_seconds: f32,
+    /// magnitude a note needs to show up at all, in dB relative to a full scale sine
+    pub threshold_dbfs: f32,
+    /// number of octaves to analyse, starting at C4
+    pub octaves: usize,
+    /// How long after it's played the output is heard, so the analysis can line up with it.
+    /// Unset is `calibration::DEFAULT_LATENCY`, or the output stream's buffer if that's longer.
+    /// Calibrating a device (the K key) wins over both.
+    pub output_latency_ms: Option<f32>,
+}
+
+impl Default for AnalysisSettings {
+    fn default() -> Self {
+        AnalysisSettings {
+            window_seconds: 0.5,
+            threshold_dbfs: -80.0,
+            octaves: 4,
+            output_latency_ms: None,
+        }
+    }
+}
+
+impl AnalysisSettings {
+    /// fft window length in frames at this sample rate, each channel is analysed on its own
+    pub fn window_size(&self, sample_rate: u32) -> usize {
+        (self.window_seconds * sample_rate as f32) as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColorSettings {
+    #[serde(deserialize_with = "hex_color")]
+    pub background: Srgb<u8>,
+    /// one per ring, repeated when there are more rings than colours
+    #[serde(deserialize_with = "hex_colors")]
+    pub waves: Vec<Srgb<u8>>,
+    /// the note names
+    #[serde(deserialize_with = "hex_color")]
+    pub text: Srgb<u8>,
+    pub ui: UiColors,
+}
+
+impl Default for ColorSettings {
+    fn default() -> Self {
+        let palette = Palette::default();
+        ColorSettings {
+            background: palette.background,
+            waves: palette.waves,
+            text: palette.text,
+            ui: UiColors::default(),
+        }
+    }
+}
+
+impl ColorSettings {
+    pub fn palette(&self) -> Palette {
+        Palette {
+            background: self.background,
+            waves: self.waves.clone(),
+            text: self.text,
+        }
+    }
+}
+
+/// Colours of the controls and overlays, these don't follow the cover art
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiColors {
+    #[serde(deserialize_with = "hex_color")]
+    pub text: Srgb<u8>,
+    #[serde(deserialize_with = "hex_color")]
+    pub seek_bar: Srgb<u8>,
+    #[serde(deserialize_with = "hex_color")]
+    pub play_button: Srgb<u8>,
+    #[serde(deserialize_with = "hex_color")]
+    pub fav_play_button: Srgb<u8>,
+    #[serde(deserialize_with = "hex_color")]
+    pub fav_record_button: Srgb<u8>,
+    /// bookmark ticks on the seek bar
+    #[serde(deserialize_with = "hex_color")]
+    pub bookmark: Srgb<u8>,
+    /// the A-B loop on the seek bar
+    #[serde(deserialize_with = "hex_color")]
+    pub loop_region: Srgb<u8>,
+    /// the input level meter in live mode
+    #[serde(deserialize_with = "hex_color")]
+    pub level_meter: Srgb<u8>,
+}
+
+impl Default for UiColors {
+    fn default() -> Self {
+        UiColors {
+            text: BLACK,
+            seek_bar: BLUE,
+            play_button: RED,
+            fav_play_button: RED,
+            fav_record_button: BLUE,
+            bookmark: ORANGE,
+            loop_region: LIMEGREEN,
+            level_meter: GREEN,
+        }
+    }
+}
+
+/// Only `filter` is applied on reload, the rest is set up once at startup
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSettings {
+    /// levels per module, in `RUST_LOG` syntax. `RUST_LOG` wins if it's set
+    pub filter: String,
+    pub format: LogFormat,
+    /// log to this file instead of stdout
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+    /// size a log file can grow to with `rotation = "size"`
+    pub max_size_mb: u64,
+    /// log files to keep, counting the one being written
+    pub max_files: usize,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        LoggingSettings {
+            filter: "audio_vis=info".to_string(),
+            format: LogFormat::Text,
+            file: None,
+            rotation: LogRotation::Daily,
+            max_size_mb: 10,
+            max_files: 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+/// When the log file starts over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogRotation {
+    /// one file that keeps growing
+    Never,
+    /// a new file every day, named after the date
+    Daily,
+    /// a new file once it reaches `max_size_mb`
+    Size,
+}
+
+impl Config {
+    /// Read and validate the config at `path`. A missing file gives the defaults.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("No config at {:?}, using the defaults", path);
+                return Ok(Config::default());
+            }
+            Err(err) => {
+                return Err(ConfigError::Io {
+                    path: path.into(),
+                    err,
+                })
+            }
+        };
+        let config: Config = toml::from_str(&contents).map_err(|err| ConfigError::Parse {
+            path: path.into(),
+            err,
+        })?;
+        config
+            .validate()
+            .map_err(|(key, reason)| ConfigError::Invalid {
+                path: path.into(),
+                key,
+                reason,
+            })?;
+        info!("Loaded config from {:?}", path);
+        Ok(config)
+    }
+
+    /// values that parse but make no sense, as (key, reason)
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        let draw = &self.draw;
+        if draw.frame_rate == 0 {
+            return Err(("draw.frame_rate", "must be at least 1".to_string()));
+        }
+        if draw.resolution.contains(&0) {
+            return Err((
+                "draw.resolution",
+                "width and height must be at least 1".to_string(),
+            ));
+        }
+        // the spline sampler divides by num_samples - 1
+        if draw.num_samples < 2 {
+            return Err(("draw.num_samples", "must be at least 2".to_string()));
+        }
+
+        let analysis = &self.analysis;
+        if !(analysis.window_seconds > 0.0 && analysis.window_seconds <= 10.0) {
+            return Err((
+                "analysis.window_seconds",
+                "must be more than 0 and at most 10 seconds".to_string(),
+            ));
+        }
+        if !analysis.threshold_dbfs.is_finite() || analysis.threshold_dbfs > 0.0 {
+            return Err((
+                "analysis.threshold_dbfs",
+                "must be 0 or below, it's relative to full scale".to_string(),
+            ));
+        }
+        if !(1..=MAX_OCTAVES).contains(&analysis.octaves) {
+            return Err((
+                "analysis.octaves",
+                format!("must be between 1 and {}", MAX_OCTAVES),
+            ));
+        }
+
+        if let Some(latency) = analysis.output_latency_ms {
+            let max = calibration::MAX_LATENCY.as_millis();
+            if !(latency >= 0.0 && latency <= max as f32) {
+                return Err((
+                    "analysis.output_latency_ms",
+                    format!("must be between 0 and {}", max),
+                ));
+            }
+        }
+
+        if self.colors.waves.is_empty() {
+            return Err(("colors.waves", "needs at least one colour".to_string()));
+        }
+
+        let logging = &self.logging;
+        if let Err(e) = EnvFilter::try_new(&logging.filter) {
+            return Err(("logging.filter", e.to_string()));
+        }
+        if logging.max_size_mb == 0 {
+            return Err(("logging.max_size_mb", "must be at least 1".to_string()));
+        }
+        if logging.max_files == 0 {
+            return Err(("logging.max_files", "must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/oxy-viz/config.toml`, `~/.config/oxy-viz/config.toml` if that's not set
+pub fn default_path() -> Option<PathBuf> {
+    Some(xdg_dir("XDG_CONFIG_HOME", ".config")?.join("config.toml"))
+}
+
+/// `$XDG_DATA_HOME/oxy-viz`, `~/.local/share/oxy-viz` if that's not set.
+/// For what the app saves itself, like bookmarks.
+pub fn data_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// our directory under `$<var>`, or under `~/<fallback>` if that's not set
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    let base = std::env::var_os(var)
+        .map(PathBuf::from)
+        // the spec says relative paths are invalid and should be ignored
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)))?;
+    Some(base.join("oxy-viz"))
+}
+
+/// Re-read `path` whenever it changes and send the result.
+/// Polled rather than using inotify and friends, so editors that save by renaming a new file
+/// over the old one, and creating the file while running, are handled the same way.
+pub fn watch(path: PathBuf) -> Receiver<Result<Config, ConfigError>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut last_modified = modified(&path);
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            let modified = modified(&path);
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            info!("Config {:?} changed, reloading", path);
+            // the receiver is gone when the app closes
+            if sender.send(Config::load(&path)).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// "#rrggbb"
+fn parse_hex_color(s: &str) -> Result<Srgb<u8>, String> {
+    let invalid = || format!("invalid colour {:?}, expected \"#rrggbb\"", s);
+    let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok(Srgb::new(channel(0)?, channel(2)?, channel(4)?))
+}
+
+fn hex_color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Srgb<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_hex_color(&s).map_err(de::Error::custom)
+}
+
+fn hex_colors<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Srgb<u8>>, D::Error> {
+    let colors = Vec::<String>::deserialize(deserializer)?;
+    colors
+        .iter()
+        .map(|s| parse_hex_color(s).map_err(de::Error::custom))
+        .collect()
+}
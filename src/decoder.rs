@@ -0,0 +1,353 @@
+use rodio::source::SeekError;
+use rodio::Source;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use symphonia::core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, SampleBuffer, Signal, SignalSpec,
+};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, CodecRegistry, Decoder as SymphoniaDecoder, DecoderOptions,
+    FinalizeResult, CODEC_TYPE_NULL, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, Packet, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::support_codec;
+use symphonia::core::units::{Time, TimeBase};
+use tracing::{info, warn};
+
+/// File extensions we look for when scanning a directory for tracks.
+/// This is only used for discovery, the actual format is always sniffed from the file header.
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "mp3", "wav", "flac", "ogg", "oga", "opus", "m4a", "mp4", "aac",
+];
+
+/// true if the path has one of the SUPPORTED_EXTENSIONS (case insensitive)
+pub fn has_supported_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| {
+            SUPPORTED_EXTENSIONS
+                .iter()
+                .any(|supported| supported.eq_ignore_ascii_case(ext))
+        })
+        .unwrap_or(false)
+}
+
+/// symphonia's default codecs + our libopus backed decoder.
+/// symphonia can demux Ogg Opus but doesn't ship an Opus codec yet.
+fn codec_registry() -> &'static CodecRegistry {
+    static REGISTRY: OnceLock<CodecRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<OpusDecoder>();
+        registry
+    })
+}
+
+/// A decoded audio file, yielding interleaved f32 samples.
+///
+/// The container and codec are probed from the file contents, so a FLAC file named `.mp3`
+/// still plays. Implements `rodio::Source` so it can go straight into a `Sink`.
+pub struct AudioDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn SymphoniaDecoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    sample_rate: u32,
+    channels: u16,
+    total_duration: Option<Duration>,
+    // the last decoded packet, interleaved, and how far into it we've read
+    buffer: Vec<f32>,
+    buffer_pos: usize,
+}
+
+impl AudioDecoder {
+    /// probe `file` and set up a decoder for its first audio track
+    pub fn new(file: File) -> Result<Self, SymphoniaError> {
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        // intentionally no extension hint: we want the probe to go by the header bytes alone
+        let format_opts = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe().format(
+            &Hint::new(),
+            mss,
+            &format_opts,
+            &MetadataOptions::default(),
+        )?;
+        let format = probed.format;
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(SymphoniaError::Unsupported("no audio track found"))?;
+        let params = track.codec_params.clone();
+        let track_id = track.id;
+
+        let decoder = codec_registry().make(&params, &DecoderOptions::default())?;
+        info!(
+            "Probed audio: codec {:?}, {:?} Hz, {:?} channels",
+            codec_registry()
+                .get_codec(params.codec)
+                .map(|c| c.short_name)
+                .unwrap_or("unknown"),
+            params.sample_rate,
+            params.channels.map(|c| c.count())
+        );
+
+        let sample_rate = params
+            .sample_rate
+            .ok_or(SymphoniaError::Unsupported("unknown sample rate"))?;
+        let channels = params
+            .channels
+            .map(|c| c.count() as u16)
+            .ok_or(SymphoniaError::Unsupported("unknown channel layout"))?;
+
+        let time_base = params.time_base;
+        let total_duration = match (time_base, params.n_frames) {
+            (Some(tb), Some(n_frames)) => Some(time_to_duration(tb.calc_time(n_frames))),
+            (None, Some(n_frames)) => Some(Duration::from_secs_f64(
+                n_frames as f64 / sample_rate as f64,
+            )),
+            _ => None,
+        };
+
+        Ok(AudioDecoder {
+            format,
+            decoder,
+            track_id,
+            time_base,
+            sample_rate,
+            channels,
+            total_duration,
+            buffer: Vec::new(),
+            buffer_pos: 0,
+        })
+    }
+
+    /// Decode the next packet of our track into self.buffer.
+    /// Returns false at end of stream.
+    fn decode_next_packet(&mut self) -> bool {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                // UnexpectedEof is how symphonia says end of stream
+                Err(SymphoniaError::IoError(_)) => return false,
+                Err(SymphoniaError::ResetRequired) => {
+                    self.decoder.reset();
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to read packet: {}", e);
+                    return false;
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    self.buffer_pos = 0;
+                    copy_interleaved(decoded, &mut self.buffer);
+                    return true;
+                }
+                // a corrupt packet shouldn't end the song, skip it
+                Err(SymphoniaError::DecodeError(e)) => {
+                    warn!("Skipping undecodable packet: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to decode packet: {}", e);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// copy a decoded symphonia buffer into `out` as interleaved f32
+fn copy_interleaved(decoded: AudioBufferRef, out: &mut Vec<f32>) {
+    let spec = *decoded.spec();
+    let frames = decoded.frames();
+    let mut sample_buf = SampleBuffer::<f32>::new(frames as u64, spec);
+    sample_buf.copy_interleaved_ref(decoded);
+    out.clear();
+    out.extend_from_slice(sample_buf.samples());
+}
+
+fn time_to_duration(time: Time) -> Duration {
+    Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+}
+
+impl Iterator for AudioDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        while self.buffer_pos >= self.buffer.len() {
+            if !self.decode_next_packet() {
+                return None;
+            }
+        }
+        let sample = self.buffer[self.buffer_pos];
+        self.buffer_pos += 1;
+        Some(sample)
+    }
+}
+
+impl Source for AudioDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        // channels and sample rate never change mid-stream, so no frame boundaries to report
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let seeked = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::from(pos.as_secs_f64()),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(|e| SeekError::Other(Box::new(e)))?;
+        self.decoder.reset();
+        self.buffer.clear();
+        self.buffer_pos = 0;
+
+        // the demuxer lands on the packet containing `required_ts`, drop the frames before it
+        let mut frames_to_skip = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        if let Some(tb) = self.time_base {
+            // timestamps are in time base units, convert them to frames
+            let skip_secs = time_to_duration(tb.calc_time(frames_to_skip)).as_secs_f64();
+            frames_to_skip = (skip_secs * self.sample_rate as f64) as u64;
+        }
+        let mut samples_to_skip = frames_to_skip as usize * self.channels as usize;
+        while samples_to_skip > 0 && self.decode_next_packet() {
+            let skip = samples_to_skip.min(self.buffer.len());
+            self.buffer_pos = skip;
+            samples_to_skip -= skip;
+        }
+        Ok(())
+    }
+}
+
+// Opus
+
+/// Largest Opus frame: 120 ms at 48 kHz
+const OPUS_MAX_FRAMES_PER_PACKET: usize = 5760;
+
+/// symphonia `Decoder` for Opus, backed by libopus through audiopus.
+struct OpusDecoder {
+    params: CodecParameters,
+    // audiopus' decoder is Send but not Sync, symphonia wants both
+    opus: Mutex<audiopus::coder::Decoder>,
+    channels: usize,
+    buf: AudioBuffer<f32>,
+    interleaved: Vec<f32>,
+}
+
+impl SymphoniaDecoder for OpusDecoder {
+    fn try_new(
+        params: &CodecParameters,
+        _options: &DecoderOptions,
+    ) -> symphonia::core::errors::Result<Self> {
+        let channel_layout = params
+            .channels
+            .ok_or(SymphoniaError::Unsupported("opus: unknown channel layout"))?;
+        let channels = channel_layout.count();
+        let opus_channels = match channels {
+            1 => audiopus::Channels::Mono,
+            2 => audiopus::Channels::Stereo,
+            _ => {
+                return Err(SymphoniaError::Unsupported(
+                    "opus: only mono and stereo are supported",
+                ))
+            }
+        };
+        let opus = audiopus::coder::Decoder::new(audiopus::SampleRate::Hz48000, opus_channels)
+            .map_err(|_| SymphoniaError::Unsupported("opus: failed to create decoder"))?;
+
+        let spec = SignalSpec::new(48_000, channel_layout);
+        Ok(OpusDecoder {
+            params: params.clone(),
+            opus: Mutex::new(opus),
+            channels,
+            buf: AudioBuffer::new(OPUS_MAX_FRAMES_PER_PACKET as u64, spec),
+            interleaved: vec![0.0; OPUS_MAX_FRAMES_PER_PACKET * channels],
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus (libopus)")]
+    }
+
+    fn reset(&mut self) {
+        use audiopus::coder::GenericCtl;
+        if let Ok(mut opus) = self.opus.lock() {
+            let _ = opus.reset_state();
+        }
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> symphonia::core::errors::Result<AudioBufferRef<'_>> {
+        let input = audiopus::packet::Packet::try_from(&packet.data[..])
+            .map_err(|_| SymphoniaError::DecodeError("opus: empty packet"))?;
+        let output = audiopus::MutSignals::try_from(&mut self.interleaved[..])
+            .map_err(|_| SymphoniaError::DecodeError("opus: invalid output buffer"))?;
+        let frames = self
+            .opus
+            .lock()
+            .map_err(|_| SymphoniaError::DecodeError("opus: decoder poisoned"))?
+            .decode_float(Some(input), output, false)
+            .map_err(|_| SymphoniaError::DecodeError("opus: invalid packet"))?;
+
+        // de-interleave into our planar buffer
+        self.buf.clear();
+        self.buf.render_reserved(Some(frames));
+        for ch in 0..self.channels {
+            let plane = self.buf.chan_mut(ch);
+            for (i, sample) in plane.iter_mut().enumerate() {
+                *sample = self.interleaved[i * self.channels + ch];
+            }
+        }
+        // pre-skip and end padding, as signalled by the demuxer
+        self.buf
+            .trim(packet.trim_start() as usize, packet.trim_end() as usize);
+
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
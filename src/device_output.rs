@@ -0,0 +1,190 @@
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::cpal::Device;
+use rodio::queue::SourcesQueueOutput;
+use rodio::{OutputStream, Source};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::devices::{self, DeviceSelector};
+use crate::error::AudioError;
+
+/// How often the output device is looked for, to notice it's been unplugged
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The --output-device, or the default output device if there's no selection or it's gone.
+/// None if there's no output device at all.
+pub fn select(
+    selector: Option<&DeviceSelector>,
+    error_sender: &Sender<AudioError>,
+) -> Option<Device> {
+    let selected = selector.and_then(|selector| {
+        devices::find_output(&nannou_audio::Host::new(), selector)
+            .map_err(|e| {
+                warn!("{}", e);
+                let _ = error_sender.send(e);
+            })
+            .ok()
+    });
+    selected.or_else(|| rodio::cpal::default_host().default_output_device())
+}
+
+/// Name of the output device, calibrated latencies are saved under it
+pub fn name(device: Option<&Device>) -> String {
+    device
+        .and_then(|device| device.name().ok())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// Channels and sample rate of the output device. The mixer plays in this format
+/// so tracks only get converted once.
+pub fn format(device: Option<&Device>) -> (u16, u32) {
+    device
+        .and_then(|device| device.default_output_config().ok())
+        .map(|config| (config.channels(), config.sample_rate().0))
+        .unwrap_or((2, 44_100))
+}
+
+/// Plays a sink's queue (see `Sink::new_idle`) on an output device.
+///
+/// rodio doesn't pass on the stream's errors, so the device is looked for every
+/// DEVICE_CHECK_INTERVAL instead. When it's gone the stream is dropped, which hands the queue
+/// back, and it carries on from where it was on the default device, like the live input does.
+pub struct DeviceOutput {
+    stream: Option<OutputStream>,
+    name: String,
+    /// the queue comes back here when the stream playing it is dropped
+    returned: Receiver<SourcesQueueOutput<f32>>,
+    return_to: Sender<SourcesQueueOutput<f32>>,
+    last_check: Instant,
+}
+
+impl DeviceOutput {
+    /// Start playing `queue` on `device`, or the default device if that's None
+    pub fn open(
+        device: Option<Device>,
+        queue: SourcesQueueOutput<f32>,
+    ) -> Result<Self, AudioError> {
+        let (return_to, returned) = mpsc::channel();
+        let mut output = DeviceOutput {
+            stream: None,
+            name: String::new(),
+            returned,
+            return_to,
+            last_check: Instant::now(),
+        };
+        output.play_on(device, queue)?;
+        Ok(output)
+    }
+
+    /// name of the device playing, see `name`
+    pub fn device_name(&self) -> &str {
+        &self.name
+    }
+
+    fn play_on(
+        &mut self,
+        device: Option<Device>,
+        queue: SourcesQueueOutput<f32>,
+    ) -> Result<(), AudioError> {
+        // if anything fails the handover is dropped, and that returns the queue for another try
+        let handover = Handover {
+            queue: Some(queue),
+            return_to: self.return_to.clone(),
+        };
+        let (stream, handle) = match &device {
+            Some(device) => OutputStream::try_from_device(device)?,
+            None => OutputStream::try_default()?,
+        };
+        handle.play_raw(handover)?;
+        self.name = name(device.as_ref());
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    /// Move to the default device if this one has gone away. Returns true when it's moved, so
+    /// whatever depends on the device (the calibrated latency) can be looked up again.
+    pub fn check(&mut self, error_sender: &Sender<AudioError>) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_check) < DEVICE_CHECK_INTERVAL {
+            return false;
+        }
+        self.last_check = now;
+
+        if self.stream.is_some() {
+            // every device rather than the output ones, those leave out a device that's busy
+            let present = rodio::cpal::default_host()
+                .devices()
+                .map_or(true, |mut devices| {
+                    devices.any(|device| device.name().map_or(false, |n| n == self.name))
+                });
+            if present {
+                return false;
+            }
+            warn!(
+                "Output device {:?} went away, switching to the default",
+                self.name
+            );
+            self.stream = None;
+        }
+        // dropping a stream returns the queue, but not always straight away
+        let Ok(queue) = self.returned.try_recv() else {
+            return false;
+        };
+        let device = rodio::cpal::default_host().default_output_device();
+        match self.play_on(device, queue) {
+            Ok(()) => {
+                info!("Playing on {:?}", self.name);
+                true
+            }
+            Err(e) => {
+                warn!("{}", e);
+                let _ = error_sender.send(e);
+                false
+            }
+        }
+    }
+}
+
+/// The queue as a stream plays it, handing it back to its `DeviceOutput` when the stream is
+/// dropped
+struct Handover {
+    queue: Option<SourcesQueueOutput<f32>>,
+    return_to: Sender<SourcesQueueOutput<f32>>,
+}
+
+impl Drop for Handover {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            let _ = self.return_to.send(queue);
+        }
+    }
+}
+
+impl Iterator for Handover {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.queue.as_mut()?.next()
+    }
+}
+
+impl Source for Handover {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.queue.as_ref()?.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.queue.as_ref().map_or(2, |queue| queue.channels())
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.queue
+            .as_ref()
+            .map_or(44_100, |queue| queue.sample_rate())
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
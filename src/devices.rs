@@ -0,0 +1,228 @@
+use clap::ValueEnum;
+use nannou_audio::{Device, DevicesError, Host, SupportedBufferSize};
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::AudioError;
+
+/// A device picked on the command line, by its index in `--list-devices` or by name
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("expected a device name or index".to_string());
+        }
+        Ok(match s.parse() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) => DeviceSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceSelector::Index(index) => write!(f, "#{}", index),
+            DeviceSelector::Name(name) => write!(f, "{:?}", name),
+        }
+    }
+}
+
+/// How `--list-devices` prints them
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ListFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// One of a device's supported configs
+#[derive(Debug, Serialize)]
+pub struct ConfigRange {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    /// frames per buffer, None if the backend doesn't say
+    pub min_buffer_size: Option<u32>,
+    pub max_buffer_size: Option<u32>,
+    pub sample_format: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    /// what `--input-device` / `--output-device` take instead of the name
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<ConfigRange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceList {
+    pub inputs: Vec<DeviceInfo>,
+    pub outputs: Vec<DeviceInfo>,
+}
+
+/// A device's name, or a placeholder if the backend can't get it (it was unplugged)
+fn device_name(device: &Device) -> String {
+    device.name().unwrap_or_else(|_| "<unknown>".to_string())
+}
+
+fn device_info<I>(index: usize, device: &Device, default: Option<&str>, configs: I) -> DeviceInfo
+where
+    I: IntoIterator<Item = nannou_audio::cpal::SupportedStreamConfigRange>,
+{
+    let name = device_name(device);
+    let configs = configs
+        .into_iter()
+        .map(|config| {
+            let (min_buffer_size, max_buffer_size) = match *config.buffer_size() {
+                SupportedBufferSize::Range { min, max } => (Some(min), Some(max)),
+                SupportedBufferSize::Unknown => (None, None),
+            };
+            ConfigRange {
+                channels: config.channels(),
+                min_sample_rate: config.min_sample_rate().0,
+                max_sample_rate: config.max_sample_rate().0,
+                min_buffer_size,
+                max_buffer_size,
+                sample_format: format!("{:?}", config.sample_format()).to_lowercase(),
+            }
+        })
+        .collect();
+    DeviceInfo {
+        index,
+        is_default: default == Some(name.as_str()),
+        name,
+        configs,
+    }
+}
+
+/// Every input and output device with the configs it supports.
+/// A device that fails to report its configs is listed without any.
+pub fn list(host: &Host) -> Result<DeviceList, DevicesError> {
+    let default_input = host.default_input_device().map(|d| device_name(&d));
+    let default_output = host.default_output_device().map(|d| device_name(&d));
+    let inputs = host
+        .input_devices()?
+        .enumerate()
+        .map(|(i, device)| {
+            let configs = device.supported_input_configs().into_iter().flatten();
+            device_info(i, &device, default_input.as_deref(), configs)
+        })
+        .collect();
+    let outputs = host
+        .output_devices()?
+        .enumerate()
+        .map(|(i, device)| {
+            let configs = device.supported_output_configs().into_iter().flatten();
+            device_info(i, &device, default_output.as_deref(), configs)
+        })
+        .collect();
+    Ok(DeviceList { inputs, outputs })
+}
+
+impl DeviceList {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("the device list is always valid JSON")
+    }
+}
+
+impl fmt::Display for DeviceList {
+    /// ```text
+    /// Output devices:
+    ///   0: default (default)
+    ///      1-32 ch, 1000-384000 Hz, 32-4194304 frames, i16
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (heading, devices) in [("Input", &self.inputs), ("Output", &self.outputs)] {
+            writeln!(f, "{} devices:", heading)?;
+            if devices.is_empty() {
+                writeln!(f, "  none")?;
+            }
+            for device in devices {
+                let default = if device.is_default { " (default)" } else { "" };
+                writeln!(f, "  {}: {}{}", device.index, device.name, default)?;
+                for config in &device.configs {
+                    let buffer = match (config.min_buffer_size, config.max_buffer_size) {
+                        (Some(min), Some(max)) => format!("{}-{} frames", min, max),
+                        _ => "any buffer size".to_string(),
+                    };
+                    writeln!(
+                        f,
+                        "     {} ch, {}-{} Hz, {}, {}",
+                        config.channels,
+                        config.min_sample_rate,
+                        config.max_sample_rate,
+                        buffer,
+                        config.sample_format
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeviceSelector {
+    fn matches(&self, index: usize, device: &Device) -> bool {
+        match self {
+            DeviceSelector::Index(i) => *i == index,
+            DeviceSelector::Name(name) => device.name().map_or(false, |n| n == *name),
+        }
+    }
+}
+
+/// The selected input device, None if it isn't there (any more)
+pub fn find_input(host: &Host, selector: &DeviceSelector) -> Option<Device> {
+    host.input_devices()
+        .ok()?
+        .enumerate()
+        .find(|(i, device)| selector.matches(*i, device))
+        .map(|(_, device)| device)
+}
+
+/// The selected output device.
+/// Playback goes through rodio's cpal rather than nannou_audio's, which can list the devices
+/// in a different order, so the device is looked up there by the name it has in the list.
+/// A name more than one device has can't be told apart that way and is rejected.
+pub fn find_output(
+    host: &Host,
+    selector: &DeviceSelector,
+) -> Result<rodio::cpal::Device, AudioError> {
+    use rodio::cpal::traits::{DeviceTrait, HostTrait};
+    let not_found = || AudioError::DeviceNotFound {
+        direction: "output",
+        device: selector.clone(),
+    };
+    let names: Vec<String> = host
+        .output_devices()
+        .map_err(|_| not_found())?
+        .map(|device| device_name(&device))
+        .collect();
+    let name = match selector {
+        DeviceSelector::Index(index) => names.get(*index).ok_or_else(not_found)?.clone(),
+        DeviceSelector::Name(name) => name.clone(),
+    };
+    let mut matching = rodio::cpal::default_host()
+        .output_devices()
+        .map_err(|_| not_found())?
+        .filter(|device| device.name().map_or(false, |n| n == name));
+    let device = matching.next().ok_or_else(not_found)?;
+    if matching.next().is_some() || names.iter().filter(|n| **n == name).count() > 1 {
+        return Err(AudioError::AmbiguousDevice {
+            direction: "output",
+            device: selector.clone(),
+            name,
+        });
+    }
+    Ok(device)
+}
@@ -0,0 +1,192 @@
+use std::path::PathBuf;
+use symphonia::core::errors::Error as SymphoniaError;
+use thiserror::Error;
+
+use crate::devices::DeviceSelector;
+use crate::toml_file::TomlFileError;
+
+/// Everything that can go wrong while loading or playing a track.
+///
+/// These are sent from the audio thread to the UI thread, which reports them
+/// instead of letting the audio thread panic.
+#[derive(Debug, Error)]
+pub enum AudioError {
+    #[error("failed to open {path:?}: {err}")]
+    Io { path: PathBuf, err: std::io::Error },
+    #[error("failed to decode {path:?}: {err}")]
+    Decode { path: PathBuf, err: SymphoniaError },
+    #[error("unsupported format or codec in {path:?}: {reason}")]
+    UnsupportedCodec { path: PathBuf, reason: &'static str },
+    #[error("failed to read tags of {path:?}: {err}")]
+    Metadata {
+        path: PathBuf,
+        err: lofty::error::LoftyError,
+    },
+    #[error("failed to open audio output device: {err}")]
+    OutputDevice { err: rodio::StreamError },
+    #[error("failed to start playback on the output device: {err}")]
+    Playback { err: rodio::PlayError },
+    #[error("{direction} device {device} not found, using the default")]
+    DeviceNotFound {
+        direction: &'static str,
+        device: DeviceSelector,
+    },
+    #[error("{direction} device {device} is ambiguous, there's more than one called {name:?}; using the default")]
+    AmbiguousDevice {
+        direction: &'static str,
+        device: DeviceSelector,
+        name: String,
+    },
+    #[error("failed to open audio input device: {err}")]
+    InputDevice {
+        err: nannou_audio::stream::BuildError,
+    },
+    #[error("failed to start capturing from the input device: {err}")]
+    Capture { err: nannou_audio::PlayStreamError },
+    #[error("failed to seek: {err}")]
+    Seek { err: rodio::source::SeekError },
+    #[error("{err}")]
+    Playlist { err: PlaylistError },
+    #[error("{err}")]
+    Latency { err: LatencyError },
+}
+
+/// Reading or writing an M3U/M3U8 or PLS playlist file.
+#[derive(Debug, Error)]
+pub enum PlaylistError {
+    #[error("failed to access playlist {path:?}: {err}")]
+    Io { path: PathBuf, err: std::io::Error },
+    #[error("invalid playlist {path:?}, line {line}: {reason}")]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+    #[error("{path:?} is not a playlist, expected .m3u, .m3u8 or .pls")]
+    UnsupportedFormat { path: PathBuf },
+}
+
+/// Reading the TOML config file.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config {path:?}: {err}")]
+    Io { path: PathBuf, err: std::io::Error },
+    #[error("invalid config {path:?}: {err}")]
+    Parse { path: PathBuf, err: toml::de::Error },
+    #[error("invalid config {path:?}, `{key}` {reason}")]
+    Invalid {
+        path: PathBuf,
+        key: &'static str,
+        reason: String,
+    },
+}
+
+/// Loading or saving the bookmarks file.
+#[derive(Debug, Error)]
+pub enum BookmarkError {
+    #[error("failed to access bookmarks {path:?}: {err}")]
+    Io { path: PathBuf, err: std::io::Error },
+    #[error("invalid bookmarks file {path:?}: {err}")]
+    Parse { path: PathBuf, err: toml::de::Error },
+    #[error("failed to write bookmarks: {err}")]
+    Serialize { err: toml::ser::Error },
+    #[error("there's no bookmark {index} in this track")]
+    NotFound { index: usize },
+}
+
+/// Loading or saving the calibrated output latencies.
+#[derive(Debug, Error)]
+pub enum LatencyError {
+    #[error("failed to access latency calibration {path:?}: {err}")]
+    Io { path: PathBuf, err: std::io::Error },
+    #[error("invalid latency calibration file {path:?}: {err}")]
+    Parse { path: PathBuf, err: toml::de::Error },
+    #[error("failed to write latency calibration: {err}")]
+    Serialize { err: toml::ser::Error },
+}
+
+impl AudioError {
+    /// Sort a symphonia error into `Io`, `Decode` or `UnsupportedCodec`.
+    /// Symphonia reports a truncated or corrupt stream as an unexpected end of file, that's
+    /// a broken file rather than one that couldn't be opened.
+    pub fn from_symphonia(path: impl Into<PathBuf>, err: SymphoniaError) -> Self {
+        let path = path.into();
+        match err {
+            SymphoniaError::Unsupported(reason) => AudioError::UnsupportedCodec { path, reason },
+            SymphoniaError::IoError(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                AudioError::Decode {
+                    path,
+                    err: SymphoniaError::IoError(err),
+                }
+            }
+            SymphoniaError::IoError(err) => AudioError::Io { path, err },
+            err => AudioError::Decode { path, err },
+        }
+    }
+}
+
+impl BookmarkError {
+    /// Sort a `toml_file` error on the bookmarks file at `path` into the matching variant
+    pub fn from_toml_file(path: impl Into<PathBuf>, err: TomlFileError) -> Self {
+        let path = path.into();
+        match err {
+            TomlFileError::Io(err) => BookmarkError::Io { path, err },
+            TomlFileError::Parse(err) => BookmarkError::Parse { path, err },
+            TomlFileError::Serialize(err) => BookmarkError::Serialize { err },
+        }
+    }
+}
+
+impl LatencyError {
+    /// Sort a `toml_file` error on the latency file at `path` into the matching variant
+    pub fn from_toml_file(path: impl Into<PathBuf>, err: TomlFileError) -> Self {
+        let path = path.into();
+        match err {
+            TomlFileError::Io(err) => LatencyError::Io { path, err },
+            TomlFileError::Parse(err) => LatencyError::Parse { path, err },
+            TomlFileError::Serialize(err) => LatencyError::Serialize { err },
+        }
+    }
+}
+
+impl From<PlaylistError> for AudioError {
+    fn from(err: PlaylistError) -> Self {
+        AudioError::Playlist { err }
+    }
+}
+
+impl From<LatencyError> for AudioError {
+    fn from(err: LatencyError) -> Self {
+        AudioError::Latency { err }
+    }
+}
+
+impl From<rodio::StreamError> for AudioError {
+    fn from(err: rodio::StreamError) -> Self {
+        AudioError::OutputDevice { err }
+    }
+}
+
+impl From<rodio::PlayError> for AudioError {
+    fn from(err: rodio::PlayError) -> Self {
+        AudioError::Playback { err }
+    }
+}
+
+impl From<nannou_audio::stream::BuildError> for AudioError {
+    fn from(err: nannou_audio::stream::BuildError) -> Self {
+        AudioError::InputDevice { err }
+    }
+}
+
+impl From<nannou_audio::PlayStreamError> for AudioError {
+    fn from(err: nannou_audio::PlayStreamError) -> Self {
+        AudioError::Capture { err }
+    }
+}
+
+impl From<rodio::source::SeekError> for AudioError {
+    fn from(err: rodio::source::SeekError) -> Self {
+        AudioError::Seek { err }
+    }
+}
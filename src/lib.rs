@@ -0,0 +1,27 @@
+//! Plays audio files (or listens to an input device) and shows the notes in them.
+//! The binary is the nannou UI, everything it drives lives here.
+
+pub mod ab_loop;
+pub mod audio_buffer;
+pub mod audio_thread;
+pub mod bookmarks;
+pub mod calculation;
+pub mod calibration;
+pub mod cli;
+pub mod config;
+pub mod decoder;
+pub mod device_output;
+pub mod devices;
+pub mod error;
+pub mod live_input;
+pub mod logging;
+pub mod metadata;
+pub mod null_output;
+pub mod palette;
+pub mod playlist;
+pub mod playlist_file;
+pub mod render_drawing;
+pub mod toml_file;
+pub mod transition;
+pub mod ui;
+pub mod volume;
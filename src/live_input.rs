@@ -0,0 +1,138 @@
+use nannou_audio::ring_buffer::{self, CaptureReader, CaptureWriter};
+use nannou_audio::{Device, Host, Stream, StreamError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::error::AudioError;
+
+/// Peak and RMS of the input since the last reading, linear (1.0 is full scale)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InputLevel {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+/// How much the capture ring holds, the longest the audio thread can go between reads before
+/// the input overruns
+const RING_SECONDS: f32 = 1.0;
+
+/// Capture from an input device (a microphone or line-in) so it can be analysed like a track.
+/// Keeps the last few seconds of audio, as much as the analysis window needs.
+pub struct LiveInput {
+    // capturing stops when this is dropped
+    _stream: Stream<CaptureWriter<f32>>,
+    reader: CaptureReader<f32>,
+    /// the device went away (it was unplugged), nothing more will be captured
+    lost: Arc<AtomicBool>,
+    /// overruns already reported
+    overruns: usize,
+    sample_rate: u32,
+    channels: usize,
+}
+
+impl LiveInput {
+    /// Start capturing from `device`, or the default input device if it's None.
+    /// Streams aren't Send on every platform, so this has to be called on the thread that uses it.
+    pub fn open(host: &Host, device: Option<Device>) -> Result<Self, AudioError> {
+        let name = device
+            .as_ref()
+            .and_then(|device| device.name().ok())
+            .unwrap_or_else(|| "the default input device".to_string());
+        // the ring is sized before the stream exists, so go by the device's default config
+        let default_config = match &device {
+            Some(device) => device.default_input_config().ok(),
+            None => host
+                .default_input_device()
+                .and_then(|device| device.default_input_config().ok()),
+        };
+        let (channels, rate) = default_config.map_or((2, 48_000), |config| {
+            (config.channels() as usize, config.sample_rate().0)
+        });
+        let (writer, reader) =
+            ring_buffer::capture((rate as f32 * RING_SECONDS) as usize, channels);
+
+        let lost = Arc::new(AtomicBool::new(false));
+        let lost_clone = Arc::clone(&lost);
+        let builder = host.new_input_stream(writer).channels(channels);
+        let builder = match device {
+            Some(device) => builder.device(device),
+            None => builder,
+        };
+        let stream = builder
+            .capture(CaptureWriter::capture)
+            .error(move |_: &mut CaptureWriter<f32>, err| {
+                warn!("Input stream error: {}", err);
+                if let StreamError::DeviceNotAvailable = err {
+                    lost_clone.store(true, Ordering::Relaxed);
+                }
+            })
+            .build()?;
+        stream.play()?;
+
+        let sample_rate = stream.cpal_config().sample_rate.0;
+        info!(
+            "Capturing {} channels at {} Hz from {}",
+            channels, sample_rate, name
+        );
+        Ok(LiveInput {
+            _stream: stream,
+            reader,
+            lost,
+            overruns: 0,
+            sample_rate,
+            channels,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Whether the device has gone away, so capturing has to start again on another one
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    /// Keep the last `frames` frames of each channel, enough for the analysis window
+    pub fn set_history(&mut self, frames: usize) {
+        self.reader.set_history(frames);
+    }
+
+    /// The latest `len` frames of `channel` as of the last `take_level`, None until that much has
+    /// been captured
+    pub fn latest(&self, channel: usize, len: usize) -> Option<Vec<f32>> {
+        self.reader.latest(channel, len)
+    }
+
+    /// Take what's been captured since the last call into the history and return its level
+    pub fn take_level(&mut self) -> InputLevel {
+        let (mut peak, mut sum_squares, mut num_samples) = (0.0f32, 0.0f64, 0usize);
+        self.reader.read(|frame| {
+            for &sample in frame {
+                peak = peak.max(sample.abs());
+                sum_squares += (sample * sample) as f64;
+            }
+            num_samples += frame.len();
+        });
+
+        let overruns = self.reader.overruns();
+        if overruns > self.overruns {
+            warn!("Input overrun, dropped {} frames", overruns - self.overruns);
+            self.overruns = overruns;
+        }
+
+        InputLevel {
+            peak,
+            rms: if num_samples == 0 {
+                0.0
+            } else {
+                (sum_squares / num_samples as f64).sqrt() as f32
+            },
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tracing::{warn, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt};
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::config::{LogFormat, LogRotation, LoggingSettings};
+
+/// Flushes the file writer's buffer when dropped, so it has to live until the app exits
+static GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+/// To change the levels when the config is reloaded
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Start logging to stdout, or to `settings.file` if set.
+/// If the log file can't be opened this says why on stderr and logs to stdout instead.
+pub fn init(settings: &LoggingSettings) {
+    let (filter, handle) = reload::Layer::new(initial_filter(settings));
+    let _ = FILTER.set(handle);
+
+    let file_writer = settings
+        .file
+        .as_deref()
+        .map(|path| open_file(path, settings));
+    let layer = match file_writer {
+        Some(Ok(writer)) => {
+            let (non_blocking, guard) = tracing_appender::non_blocking(writer);
+            *GUARD.lock().unwrap() = Some(guard);
+            fmt_layer(
+                settings.format,
+                BoxMakeWriter::new(non_blocking),
+                "%Y-%m-%d %H:%M:%S%.6f",
+                false,
+            )
+        }
+        Some(Err(e)) => {
+            eprintln!(
+                "failed to open log file {:?}: {}, logging to stdout",
+                settings.file, e
+            );
+            stdout_layer(settings.format)
+        }
+        None => stdout_layer(settings.format),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .init();
+}
+
+/// Write out what's still buffered for the log file. Logging to the file stops after this.
+pub fn shutdown() {
+    drop(GUARD.lock().unwrap().take());
+}
+
+/// Apply `[logging] filter` from a reloaded config, unless `RUST_LOG` overrides it
+pub fn set_filter(directives: &str) {
+    if std::env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+        return;
+    }
+    let Some(handle) = FILTER.get() else {
+        return;
+    };
+    // the config was validated, so the filter parses
+    if let Ok(filter) = EnvFilter::try_new(directives) {
+        if let Err(e) = handle.reload(filter) {
+            warn!("Failed to change the log filter: {}", e);
+        }
+    }
+}
+
+/// `RUST_LOG` if it's set, otherwise the config's filter
+fn initial_filter(settings: &LoggingSettings) -> EnvFilter {
+    match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&settings.filter)
+            .unwrap_or_else(|_| EnvFilter::new(LoggingSettings::default().filter)),
+    }
+}
+
+fn stdout_layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fmt_layer(format, BoxMakeWriter::new(io::stdout), "%H:%M:%S%.6f", true)
+}
+
+fn fmt_layer<S>(
+    format: LogFormat,
+    writer: BoxMakeWriter,
+    time_format: &str,
+    ansi: bool,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let layer = fmt::layer()
+        .with_timer(fmt::time::ChronoUtc::new(time_format.to_string()))
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+fn open_file(path: &Path, settings: &LoggingSettings) -> io::Result<Box<dyn Write + Send>> {
+    let directory = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::create_dir_all(directory)?;
+    match settings.rotation {
+        LogRotation::Never => Ok(Box::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        LogRotation::Daily => {
+            let file_name = path.file_name().unwrap_or(path.as_os_str());
+            // <file>.YYYY-MM-DD
+            let appender: RollingFileAppender = rolling::Builder::new()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(file_name.to_string_lossy())
+                .max_log_files(settings.max_files)
+                .build(directory)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            Ok(Box::new(appender))
+        }
+        LogRotation::Size => Ok(Box::new(SizeRotatingFile::open(
+            path,
+            settings.max_size_mb * 1024 * 1024,
+            settings.max_files,
+        )?)),
+    }
+}
+
+/// Appends to a file and moves it aside once it reaches `max_bytes`: `<file>` is the one being
+/// written, `<file>.1` the one before it and so on. The oldest is deleted so there are never
+/// more than `max_files`.
+struct SizeRotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl SizeRotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(SizeRotatingFile {
+            path: path.to_path_buf(),
+            file,
+            written,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    /// `<file>.<n>`
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        // shift .1 -> .2 and so on, the last one is overwritten
+        for n in (1..self.max_files.saturating_sub(1)).rev() {
+            match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.max_files > 1 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // each write is one whole log line, so lines never get split across files
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
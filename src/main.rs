@@ -1,152 +1,487 @@
-use crate::ui::Button;
+use audio_vis::ab_loop::{AbLoop, LoopEdge, NUDGE_STEP, NUDGE_STEP_LARGE};
+use audio_vis::audio_thread::{create_audio_thread, AudioOptions, Command, FreqMagPair};
+use audio_vis::bookmarks::{Bookmark, BookmarkStore, TrackKey};
+use audio_vis::calibration::{LATENCY_STEP, MAX_LATENCY};
+use audio_vis::cli::Cli;
+use audio_vis::config::Config;
+use audio_vis::devices::ListFormat;
+use audio_vis::error::{AudioError, ConfigError, PlaylistError};
+use audio_vis::live_input::InputLevel;
+use audio_vis::metadata::TrackInfo;
+use audio_vis::playlist::PlaybackMode;
+use audio_vis::playlist_file::PlaylistEntry;
+use audio_vis::render_drawing::DrawConfig;
+use audio_vis::transition::MixerStatus;
+use audio_vis::ui::Button;
+use audio_vis::volume::VolumeStatus;
+use audio_vis::{
+    bookmarks, calibration, config, decoder, devices, logging, metadata, playlist_file,
+    render_drawing, ui,
+};
+use clap::{CommandFactory, Parser};
 use core::time;
 use minimp3::{Decoder as MiniDecoder, Frame as miniFrame};
 use nannou::prelude::*;
 use nannou::state::mouse;
 use nannou::text::pt_to_scale;
-use rodio::{Decoder, OutputStream, Sink, Source};
-use rustfft::num_complex::Complex;
-use rustfft::FftPlanner;
-use std::fs::File;
 use std::io::{BufReader, Stdout};
-use std::path::Path;
-use std::sync::mpsc::{self, channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use walkdir::WalkDir;
 
-use tracing::{debug, info, warn, Level};
-use tracing_appender::{non_blocking, rolling};
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
-
-mod calculation;
-mod render_drawing;
-mod ui;
-
-enum Command {
-    Play,
-    Pause,
-    Seek(Duration),
-    CalculateFFT,
-}
+use tracing::{debug, error, info, warn, Level};
 
 struct Playback {
     is_playing: bool,
-    curr_pos: Arc<Mutex<Duration>>,
+    /// the playback clock, kept by the audio thread
+    clock: Arc<MixerStatus>,
     fft_output: Arc<Mutex<Vec<FreqMagPair>>>,
-    fav_part: Duration,
+    /// playlist index and info of a newly loaded track, set by the audio thread and taken by update()
+    new_track_info: Arc<Mutex<Option<(usize, TrackInfo)>>>,
+    track_info: Option<TrackInfo>,
+    /// shuffle / repeat, read by the audio thread when a track ends
+    mode: Arc<Mutex<PlaybackMode>>,
+    /// volume, mute and sleep timer, published by the audio thread
+    volume: Arc<Mutex<VolumeStatus>>,
+    /// the input level, only published in live mode
+    input_level: Arc<Mutex<Option<InputLevel>>>,
 }
 
 struct Model {
     sender: Sender<Command>,
+    error_receiver: Receiver<AudioError>,
+    last_error: Option<String>,
+    /// from the command line and config file, plus the current track's palette
+    draw_config: DrawConfig,
+    /// the config file, re-read whenever it changes. None if there's no config dir
+    config_updates: Option<Receiver<Result<Config, ConfigError>>>,
+    /// the last reload's error, until the config is fixed
+    config_error: Option<String>,
     playback: Playback,
     data: render_drawing::Data,
     temp: u128,
     ui_elements: Vec<ui::UIElem>,
     mp3_files: Vec<std::path::PathBuf>,
     current_track_index: u32,
+    /// None if there's no data dir or the file couldn't be read
+    bookmarks: Option<BookmarkStore>,
+    /// a bookmark that was just added and is being named
+    naming_bookmark: Option<NamingBookmark>,
+    /// the current track's A-B loop
+    ab_loop: AbLoop,
+    /// playing the click track to calibrate the output latency
+    calibrating: bool,
 }
 
-enum LogDestination {
-    Stdout,
-    File,
+/// Typing a name for a new bookmark. Return keeps it, Escape keeps the default name.
+struct NamingBookmark {
+    /// the track it's on, which may not be the one playing by the time the name is done
+    key: TrackKey,
+    /// in the track's bookmarks
+    index: usize,
+    name: String,
+    /// the B that opened the prompt also arrives as a character, which isn't part of the name
+    skip_char: bool,
 }
 
-/// file output is not working
-fn setup_logger(destination: LogDestination) {
-    if let LogDestination::File = destination {
-        let file_appender = rolling::never(".", "output.log");
-        let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-        let fmt_layer = fmt::layer()
-            .with_timer(fmt::time::ChronoUtc::new(String::from(
-                "%Y-%m-%d %H:%M:%S%.6f",
-            )))
-            .with_writer(move || non_blocking.clone());
-        tracing_subscriber::registry()
-            .with(EnvFilter::new("audio_vis=info"))
-            .with(fmt_layer)
-            .init();
-    } else {
-        let fmt_layer =
-            fmt::layer().with_timer(fmt::time::ChronoUtc::new(String::from("%H:%M:%S%.6f")));
-        tracing_subscriber::registry()
-            .with(EnvFilter::new("audio_vis=info"))
-            .with(fmt_layer)
-            .init();
-    };
-}
+/// The parsed command line, set once in main(). nannou's model() can't take arguments.
+static CLI: OnceLock<Cli> = OnceLock::new();
+/// The config as it was at startup, with the command line overrides applied
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
 fn main() {
-    setup_logger(LogDestination::Stdout);
+    let cli = Cli::parse();
+
+    // `--info <files>`: print tags and audio properties, one `key: value` per line, and exit
+    if cli.info {
+        print_track_info(&cli.inputs);
+        return;
+    }
+
+    // `--list-devices [text|json]`: print the devices and what they support, and exit
+    if let Some(format) = cli.list_devices {
+        match devices::list(&nannou_audio::Host::new()) {
+            Ok(list) if format == ListFormat::Json => println!("{}", list.to_json()),
+            Ok(list) => print!("{}", list),
+            Err(e) => Cli::command()
+                .error(
+                    clap::error::ErrorKind::Io,
+                    format!("failed to list devices: {}", e),
+                )
+                .exit(),
+        }
+        return;
+    }
+
+    // a broken config at startup is an error, later edits only get reported
+    let config = match config_path(&cli) {
+        Some(path) => Config::load(&path).unwrap_or_else(|e| {
+            Cli::command()
+                .error(clap::error::ErrorKind::InvalidValue, e.to_string())
+                .exit()
+        }),
+        None => Config::default(),
+    };
+    let config = CONFIG.get_or_init(|| with_cli_overrides(config, &cli));
+
+    logging::init(&config.logging);
 
     info!("Starting app");
 
-    nannou::app(model).update(update).run();
+    let no_window = cli.no_window;
+    let cli = CLI.get_or_init(|| cli);
+    if no_window {
+        run_headless(cli);
+        logging::shutdown();
+    } else {
+        nannou::app(model).update(update).exit(exit).run();
+    }
 }
 
-const SRC: &str = "src/c4_maj.wav";
+/// nannou never returns from run(), so the log file is flushed here
+fn exit(_app: &App, _model: Model) {
+    info!("Exiting app");
+    logging::shutdown();
+}
 
-fn find_mp3_files(dir: &str) -> Vec<std::path::PathBuf> {
+/// --config, or the default in the XDG config dir
+fn config_path(cli: &Cli) -> Option<PathBuf> {
+    cli.config.clone().or_else(config::default_path)
+}
+
+/// command line options win over the config file
+fn with_cli_overrides(mut config: Config, cli: &Cli) -> Config {
+    if let Some(octaves) = cli.octaves {
+        config.analysis.octaves = octaves;
+    }
+    if let Some(log_file) = &cli.log_file {
+        config.logging.file = Some(log_file.clone());
+    }
+    config
+}
+
+/// --no-window: play the playlist through once, logging what plays.
+/// The config is only read at startup here, there's no UI to apply changes to.
+fn run_headless(cli: &Cli) {
+    let config = CONFIG
+        .get()
+        .expect("config is loaded before the audio starts");
+    let audio_manager = create_audio_thread(audio_options(cli, config));
+    // the audio thread drops its end of the channel when it stops, which ends this loop
+    for e in &audio_manager.errors_from_audio {
+        error!("Audio error: {}", e);
+    }
+    info!("Playlist finished");
+}
+
+/// where the S key saves the queue
+const QUEUE_FILE: &str = "queue.m3u8";
+/// how much the +/- keys change the volume
+const VOLUME_STEP: f32 = 0.05;
+/// length of the I / O key fades
+const FADE_LENGTH: Duration = Duration::from_secs(3);
+/// pressing [ within this long after a bookmark goes to the one before it
+const BOOKMARK_GRACE: Duration = Duration::from_secs(1);
+
+fn print_track_info(paths: &[PathBuf]) {
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        match metadata::read_track_info(path) {
+            Ok(info) => println!("{}", info),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+/// The tracks to play from `path`: a playlist file, a directory (searched recursively)
+/// or a single audio file.
+fn collect_tracks(path: &Path) -> Result<Vec<PlaylistEntry>, PlaylistError> {
+    if playlist_file::is_playlist_file(path) && path.is_file() {
+        return playlist_file::load(path);
+    }
+    let files = if path.is_dir() {
+        find_mp3_files(path)
+    } else {
+        vec![path.to_path_buf()]
+    };
+    Ok(files.into_iter().map(PlaylistEntry::new).collect())
+}
+
+/// Everything to play from the command line, in order.
+/// Exits with a usage error if an input can't be read or there's nothing to play.
+fn collect_inputs(inputs: &[PathBuf]) -> Vec<PlaylistEntry> {
+    let mut tracks = vec![];
+    for input in inputs {
+        if !input.exists() {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("{:?} does not exist", input),
+                )
+                .exit();
+        }
+        match collect_tracks(input) {
+            Ok(found) if found.is_empty() => warn!("No audio files found in {:?}", input),
+            Ok(found) => tracks.extend(found),
+            Err(e) => Cli::command()
+                .error(clap::error::ErrorKind::Io, e.to_string())
+                .exit(),
+        }
+    }
+    if tracks.is_empty() {
+        let supported = decoder::SUPPORTED_EXTENSIONS.join(", ");
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "nothing to play, no audio files ({}) in the inputs",
+                    supported
+                ),
+            )
+            .exit();
+    }
+    tracks
+}
+
+fn find_mp3_files(dir: &Path) -> Vec<std::path::PathBuf> {
     WalkDir::new(dir)
         .follow_links(true)
+        .sort_by_file_name()
         .into_iter()
         .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file() && e.path().extension() == Some("mp3".as_ref()))
+        .filter(|e| e.file_type().is_file() && decoder::has_supported_extension(e.path()))
         .map(|e| e.path().to_path_buf())
         .collect()
 }
 
-/// A struct to store the frequency and magnitude of a frequency bin.
-/// To be used by the visualizer
-#[derive(Debug, Clone, Copy)]
-struct FreqMagPair {
-    freq: f32,
-    mag: f32,
+/// Collects the inputs, so this exits with a usage error if there's nothing to play
+fn audio_options(cli: &Cli, config: &Config) -> AudioOptions {
+    AudioOptions {
+        tracks: if cli.live {
+            vec![]
+        } else {
+            collect_inputs(&cli.inputs)
+        },
+        analysis: config.analysis,
+        start_at: cli.start_at,
+        volume: cli.volume,
+        seed: cli.seed,
+        exit_at_end: cli.no_window,
+        live: cli.live,
+        null_output: cli.null_output,
+        output_device: cli.output_device.clone(),
+        input_device: cli.input_device.clone(),
+    }
 }
 
-struct AudioManager {
-    sender_to_audio: Sender<Command>,
-    playback_position: Arc<Mutex<Duration>>,
-    fft_output: Arc<Mutex<Vec<FreqMagPair>>>, // will use this to store the fft output -> 48 values
-    last_fft_generated_at: Arc<Mutex<Option<Instant>>>, // for debugging mostly
-    mp3_files: Vec<std::path::PathBuf>,
-}
+impl Model {
+    /// Store the new shuffle / repeat mode for the audio thread and show it
+    fn set_playback_mode(&mut self, mode: PlaybackMode) {
+        info!("Playback mode: {}", mode);
+        *self.playback.mode.lock().unwrap() = mode;
+        for element in self.ui_elements.iter_mut() {
+            if let ui::UIElem::PlaybackModeInfo(mode_info) = element {
+                mode_info.set(mode);
+            }
+        }
+    }
+
+    /// Apply a reloaded config: drawing straight away, the analysis settings in the audio thread
+    fn apply_config(&mut self, app: &App, config: Config) {
+        let config = match CLI.get() {
+            Some(cli) => with_cli_overrides(config, cli),
+            None => config,
+        };
+        let old_resolution = self.draw_config.resolution();
+        self.draw_config.apply(&config);
+        app.set_loop_mode(LoopMode::rate_fps(self.draw_config.frame_rate() as f64));
+        if self.draw_config.resolution() != old_resolution {
+            let [w, h] = self.draw_config.resolution();
+            app.main_window().set_inner_size_points(w as f32, h as f32);
+            // the layout is worked out from the window size
+            self.rebuild_ui(Rect::from_w_h(w as f32, h as f32));
+        }
+        self.send(Command::SetAnalysis(config.analysis));
+        logging::set_filter(&config.logging.filter);
+        // the edit fixed the config, so its error no longer applies
+        if let Some(e) = self.config_error.take() {
+            if self.last_error.as_ref() == Some(&e) {
+                self.last_error = None;
+            }
+        }
+    }
+
+    /// Lay the UI out again for a window of size `win`, keeping what it shows
+    fn rebuild_ui(&mut self, win: Rect) {
+        self.ui_elements = ui::create_ui_elements(win);
+        let mode = *self.playback.mode.lock().unwrap();
+        let index = self.current_track_index as usize;
+        let len = self.mp3_files.len();
+        for element in self.ui_elements.iter_mut() {
+            match element {
+                ui::UIElem::SongInfo(song_info) => {
+                    if let Some(info) = &self.playback.track_info {
+                        song_info.set(info);
+                        song_info.set_queue_position(index, len);
+                    }
+                }
+                ui::UIElem::PlaybackModeInfo(mode_info) => mode_info.set(mode),
+                _ => {}
+            }
+        }
+    }
+
+    /// The current track's key in the bookmark store, once it's loaded and hashed
+    fn track_key(&self) -> Option<TrackKey> {
+        let info = self.playback.track_info.as_ref()?;
+        Some(TrackKey {
+            path: info.path.clone(),
+            hash: info.content_hash.clone()?,
+        })
+    }
 
-fn create_audio_thread() -> AudioManager {
-    let directory = "src"; // Change this to the directory you want to search
-    let mp3_files = find_mp3_files(directory);
+    /// The current track's bookmarks
+    fn track_bookmarks(&self) -> &[Bookmark] {
+        match (&self.bookmarks, self.track_key()) {
+            (Some(store), Some(key)) => store.get(&key),
+            _ => &[],
+        }
+    }
 
-    let fft_output: Arc<Mutex<Vec<FreqMagPair>>> = Arc::new(Mutex::new(vec![]));
+    /// Bookmark the playhead and start naming it
+    fn add_bookmark(&mut self, opened_by_key: bool) {
+        let Some(key) = self.track_key() else {
+            return;
+        };
+        let Some(store) = &mut self.bookmarks else {
+            return;
+        };
+        let position = self.playback.clock.heard_position();
+        let name = format!("bookmark {}", store.get(&key).len() + 1);
+        let bookmark = Bookmark {
+            name: name.clone(),
+            position,
+        };
+        match store.add(&key, bookmark) {
+            Ok(index) => {
+                info!("Bookmarked {} at {:?}", key.path.display(), position);
+                self.naming_bookmark = Some(NamingBookmark {
+                    key,
+                    index,
+                    name,
+                    skip_char: opened_by_key,
+                });
+            }
+            Err(e) => {
+                error!("{}", e);
+                self.last_error = Some(e.to_string());
+            }
+        }
+    }
 
-    let (sender, receiver) = mpsc::channel::<Command>();
-    let playback_position = Arc::new(Mutex::new(Duration::from_secs(0)));
+    /// Keep the typed name, or the default one if nothing was typed
+    fn finish_naming_bookmark(&mut self, keep_typed: bool) {
+        let Some(naming) = self.naming_bookmark.take() else {
+            return;
+        };
+        let Some(store) = &mut self.bookmarks else {
+            return;
+        };
+        let name = naming.name.trim();
+        if !keep_typed || name.is_empty() {
+            return;
+        }
+        if let Err(e) = store.rename(&naming.key, naming.index, name.to_string()) {
+            error!("{}", e);
+            self.last_error = Some(e.to_string());
+        }
+    }
 
-    let playback_position_clone = Arc::clone(&playback_position);
-    let fft_output_clone = Arc::clone(&fft_output);
+    /// Seek to the bookmark after the playhead, or before it if `forward` is false
+    fn seek_to_bookmark(&self, forward: bool) {
+        let position = self.playback.clock.heard_position();
+        let bookmarks = self.track_bookmarks();
+        let bookmark = if forward {
+            bookmarks::next_after(bookmarks, position)
+        } else {
+            bookmarks::previous_before(bookmarks, position, BOOKMARK_GRACE)
+        };
+        if let Some(bookmark) = bookmark {
+            info!("Seeking to bookmark {:?}", bookmark.name);
+            self.send(Command::Seek(bookmark.position));
+        }
+    }
 
-    println!("this is just before stream play");
-    thread::spawn(move || {
-        audio_control_thread(
-            receiver,
-            playback_position_clone,
-            fft_output_clone,
-            Arc::new(Mutex::new(None)),
-        )
-    });
-    println!("Audio thread spawned");
-    AudioManager {
-        sender_to_audio: sender,
-        playback_position,
-        fft_output,
-        mp3_files,
-        last_fft_generated_at: Arc::new(Mutex::new(None)),
+    /// Delete the bookmark closest to the playhead
+    fn remove_bookmark(&mut self) {
+        let position = self.playback.clock.heard_position();
+        let Some(index) = bookmarks::closest(self.track_bookmarks(), position) else {
+            return;
+        };
+        let (Some(key), Some(store)) = (self.track_key(), &mut self.bookmarks) else {
+            return;
+        };
+        match store.remove(&key, index) {
+            Ok(bookmark) => info!("Removed bookmark {:?}", bookmark.name),
+            Err(e) => {
+                error!("{}", e);
+                self.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Change the A-B loop, and what the audio thread loops if that changed
+    fn set_ab_loop(&mut self, ab_loop: AbLoop) {
+        if ab_loop == self.ab_loop {
+            return;
+        }
+        info!("A-B loop: {:?}", ab_loop);
+        if ab_loop.region() != self.ab_loop.region() {
+            self.send(Command::SetLoop(ab_loop.region()));
+        }
+        self.ab_loop = ab_loop;
+    }
+
+    /// Move the start or end of the A-B loop, further with shift held
+    fn nudge_ab_loop(&mut self, app: &App, edge: LoopEdge, later: bool) {
+        let step = if app.keys.mods.shift() {
+            NUDGE_STEP_LARGE
+        } else {
+            NUDGE_STEP
+        };
+        let track_length = self
+            .playback
+            .track_info
+            .as_ref()
+            .map(|info| info.duration)
+            .filter(|duration| !duration.is_zero());
+        self.set_ab_loop(self.ab_loop.nudge(edge, step, later, track_length));
+    }
+
+    /// Send a command to the audio thread.
+    /// If the audio thread has stopped (its error was already reported) the command is dropped.
+    fn send(&self, command: Command) {
+        if self.sender.send(command).is_err() {
+            debug!("audio thread is not running, dropping command");
+        }
     }
 }
 
 fn model(app: &App) -> Model {
     println!("i am in model");
+    let cli = CLI
+        .get()
+        .expect("command line is parsed before the app starts");
+    let config = CONFIG
+        .get()
+        .expect("config is loaded before the app starts");
+    let [width, height] = config.draw.resolution;
     app.new_window()
+        .size(width, height)
         .key_pressed(key_pressed)
         .event(mouse_event)
         .view(view)
@@ -161,26 +496,58 @@ fn model(app: &App) -> Model {
     let ui_elements = ui::create_ui_elements(app.window_rect());
 
     // all the audio stuff
-    let audio_manager = create_audio_thread();
+    let audio_manager = create_audio_thread(audio_options(cli, config));
+    let draw_config = DrawConfig::new(cli.visualizer, config);
+    app.set_loop_mode(LoopMode::rate_fps(draw_config.frame_rate() as f64));
+    let config_updates = config_path(cli).map(config::watch);
 
     let sender = audio_manager.sender_to_audio;
-    let playback_position = audio_manager.playback_position;
+    let error_receiver = audio_manager.errors_from_audio;
+    let clock = audio_manager.clock;
     let fft_output = audio_manager.fft_output;
+    let track_info = audio_manager.track_info;
+    let playback_mode = audio_manager.playback_mode;
+    let volume = audio_manager.volume;
+    let input_level = audio_manager.input_level;
     let mp3_files = audio_manager.mp3_files;
 
+    let mut last_error = None;
+    let bookmarks = BookmarkStore::default_path().and_then(|path| {
+        BookmarkStore::open(&path)
+            .map_err(|e| {
+                error!("{}", e);
+                last_error = Some(e.to_string());
+            })
+            .ok()
+    });
+
     Model {
         sender,
+        error_receiver,
+        last_error,
+        draw_config,
+        config_updates,
+        config_error: None,
         playback: Playback {
-            is_playing: false,
-            curr_pos: playback_position,
+            // the sink starts playing straight away
+            is_playing: true,
+            clock,
             fft_output,
-            fav_part: Duration::from_secs(0),
+            new_track_info: track_info,
+            track_info: None,
+            mode: playback_mode,
+            volume,
+            input_level,
         },
         temp,
         data: random_data,
         ui_elements,
         mp3_files,
         current_track_index: 0,
+        bookmarks,
+        naming_bookmark: None,
+        ab_loop: AbLoop::Off,
+        calibrating: false,
     }
 }
 
@@ -189,9 +556,21 @@ fn mouse_event(app: &App, model: &mut Model, event: WindowEvent) {
     let pos = app.mouse.position();
     let (x, y) = (pos.x, pos.y);
     match event {
+        // typing a bookmark's name
+        ReceivedCharacter(c) => {
+            if let Some(naming) = &mut model.naming_bookmark {
+                if naming.skip_char {
+                    naming.skip_char = false;
+                } else if !c.is_control() {
+                    naming.name.push(c);
+                }
+            }
+        }
         MousePressed(_button) => {
             println!(" -- x: -- y:{:?} {:?}", x, y);
 
+            // which button was clicked, handled after the loop so the model can be changed
+            let mut clicked = None;
             for element in &model.ui_elements {
                 // if element is a button
                 match element {
@@ -202,22 +581,15 @@ fn mouse_event(app: &App, model: &mut Model, event: WindowEvent) {
                                 ui::ButtonName::Play => {
                                     println!("Play button clicked");
                                     model.playback.is_playing = !model.playback.is_playing;
-                                    let cmd = if model.playback.is_playing == false {
+                                    let cmd = if model.playback.is_playing {
                                         Command::Play
                                     } else {
                                         Command::Pause
                                     };
-                                    model.sender.send(cmd).unwrap();
-                                }
-                                ui::ButtonName::FavPlay => {
-                                    println!("playing your fav part of the song");
-                                    let new_position = model.playback.fav_part;
-                                    model.sender.send(Command::Seek(new_position)).unwrap();
+                                    model.send(cmd);
                                 }
-                                ui::ButtonName::FavRecord => {
-                                    println!("record the fav part of the song");
-                                    let lock = model.playback.curr_pos.lock().unwrap();
-                                    model.playback.fav_part = *lock;
+                                ui::ButtonName::FavPlay | ui::ButtonName::FavRecord => {
+                                    clicked = Some(button.button_name);
                                 }
                                 ui::ButtonName::Seek => {
                                     // println!("seeking the song");
@@ -240,398 +612,282 @@ fn mouse_event(app: &App, model: &mut Model, event: WindowEvent) {
                     _ => {}
                 }
             }
+            match clicked {
+                // back to the last bookmark
+                Some(ui::ButtonName::FavPlay) => model.seek_to_bookmark(false),
+                Some(ui::ButtonName::FavRecord) => model.add_bookmark(false),
+                _ => {}
+            }
         }
         _ => {}
     }
 }
 
-fn key_pressed(_app: &App, model: &mut Model, key: Key) {
+fn key_pressed(app: &App, model: &mut Model, key: Key) {
+    // while a bookmark is being named the keys are typing, not shortcuts
+    if let Some(naming) = &mut model.naming_bookmark {
+        match key {
+            Key::Return | Key::NumpadEnter => model.finish_naming_bookmark(true),
+            Key::Escape => model.finish_naming_bookmark(false),
+            Key::Back => {
+                naming.name.pop();
+            }
+            _ => {}
+        }
+        return;
+    }
+    // calibration takes over the keys until it's finished
+    if model.calibrating {
+        let latency = model.playback.clock.latency();
+        match key {
+            Key::K => {
+                model.calibrating = false;
+                model.send(Command::Calibrate(false));
+            }
+            Key::Up => model.send(Command::SetLatency(
+                (latency + LATENCY_STEP).min(MAX_LATENCY),
+            )),
+            Key::Down => model.send(Command::SetLatency(latency.saturating_sub(LATENCY_STEP))),
+            _ => {}
+        }
+        return;
+    }
     match key {
         Key::Space => {
             model.playback.is_playing = !model.playback.is_playing;
-            let cmd = if model.playback.is_playing == false {
+            let cmd = if model.playback.is_playing {
                 Command::Play
             } else {
                 Command::Pause
             };
-            model.sender.send(cmd).unwrap();
+            model.send(cmd);
         }
         Key::Q => {
-            model.sender.send(Command::CalculateFFT).unwrap();
+            model.send(Command::CalculateFFT);
         }
         Key::N => {
-            println!("looking for new N");
+            model.send(Command::Next);
+        }
+        Key::P => {
+            model.send(Command::Previous);
+        }
+        Key::S => {
+            model.send(Command::SavePlaylist(PathBuf::from(QUEUE_FILE)));
+        }
+        Key::Equals | Key::Plus | Key::NumpadAdd => {
+            let volume = model.playback.volume.lock().unwrap().volume;
+            model.send(Command::SetVolume(volume + VOLUME_STEP));
+        }
+        Key::Minus | Key::NumpadSubtract => {
+            let volume = model.playback.volume.lock().unwrap().volume;
+            model.send(Command::SetVolume(volume - VOLUME_STEP));
+        }
+        Key::M => {
+            let muted = model.playback.volume.lock().unwrap().muted;
+            model.send(if muted {
+                Command::Unmute
+            } else {
+                Command::Mute
+            });
+        }
+        Key::I => {
+            model.send(Command::FadeIn(FADE_LENGTH));
+        }
+        Key::O => {
+            model.send(Command::FadeOut(FADE_LENGTH));
+        }
+        Key::T => {
+            let next = model.playback.volume.lock().unwrap().next_sleep_timer();
+            model.send(Command::SleepTimer(next));
+        }
+        Key::B => model.add_bookmark(true),
+        Key::LBracket => model.seek_to_bookmark(false),
+        Key::RBracket => model.seek_to_bookmark(true),
+        Key::Delete => model.remove_bookmark(),
+        Key::L => {
+            let position = model.playback.clock.heard_position();
+            model.set_ab_loop(model.ab_loop.mark(position));
+        }
+        Key::K => {
+            model.calibrating = true;
+            model.send(Command::Calibrate(true));
+        }
+        Key::Comma => model.nudge_ab_loop(app, LoopEdge::Start, false),
+        Key::Period => model.nudge_ab_loop(app, LoopEdge::Start, true),
+        Key::Semicolon => model.nudge_ab_loop(app, LoopEdge::End, false),
+        Key::Apostrophe => model.nudge_ab_loop(app, LoopEdge::End, true),
+        Key::Z => {
+            let mut mode = *model.playback.mode.lock().unwrap();
+            mode.shuffle = !mode.shuffle;
+            model.set_playback_mode(mode);
+        }
+        Key::R => {
+            let mut mode = *model.playback.mode.lock().unwrap();
+            mode.repeat = mode.repeat.cycle();
+            model.set_playback_mode(mode);
+        }
+        Key::C => {
+            let mut mode = *model.playback.mode.lock().unwrap();
+            mode.crossfade = mode.next_crossfade();
+            model.set_playback_mode(mode);
+        }
+        // 1-9 jump straight to that track
+        Key::Key1
+        | Key::Key2
+        | Key::Key3
+        | Key::Key4
+        | Key::Key5
+        | Key::Key6
+        | Key::Key7
+        | Key::Key8
+        | Key::Key9 => {
+            let index = key as usize - Key::Key1 as usize;
+            model.send(Command::JumpTo(index));
         }
         Key::Left => {
             println!("Left arrow key pressed");
-            let lock = model.playback.curr_pos.lock().unwrap(); //locked the variable here for mutex,
-            let new_position = if *lock > Duration::from_secs(5) {
-                *lock - Duration::from_secs(5)
-            } else {
-                Duration::from_secs(0)
-            };
-            model.sender.send(Command::Seek(new_position)).unwrap(); // passing the timestamp to audio thread
+            let position = model.playback.clock.heard_position();
+            let new_position = position.saturating_sub(Duration::from_secs(5));
+            model.send(Command::Seek(new_position)); // passing the timestamp to audio thread
         }
         Key::Right => {
             println!("Right arrow key pressed");
-            let lock = model.playback.curr_pos.lock().unwrap(); //locked the variable here for mutex,  and blah blah blah
-            let new_position = *lock + Duration::from_secs(5);
-            model.sender.send(Command::Seek(new_position)).unwrap(); // passing the timestamp to audio thread
+            let new_position = model.playback.clock.heard_position() + Duration::from_secs(5);
+            model.send(Command::Seek(new_position)); // passing the timestamp to audio thread
         }
         _ => {}
     }
 }
 
-/// The buffer is the fft output now contains the frequency and magnitude information for each frequency bin.
-/// buffer must of single channel (any one of the channels)
-fn display_frequencies(
-    buffer: &[Complex<f32>], // contains the actual fft_output
-    sample_rate: usize,
-    fft_size: usize,
-    fft_output: Arc<Mutex<Vec<FreqMagPair>>>, // will use this to store the PROCESSED fft output -> 48 values
-) {
-    let THRESHOLD = 100.0;
-
-    assert!(
-        fft_output.lock().unwrap().len() == 48,
-        "Expected 48 target notes"
-    );
-
-    let mut fft_output_locked = fft_output.lock().unwrap();
-
-    for freq_magnitude in fft_output_locked.iter_mut() {
-        freq_magnitude.mag = 0.0;
-    }
-
-    for (i, complex) in buffer.iter().enumerate() {
-        // buffer now contains the actual fft output of the audio
-        // so for each freq bin:
-        let frequency = (i as f32 * sample_rate as f32) / fft_size as f32; // frequency of the fft result at this freq bin
-        let magnitude = complex.norm(); // magnitude of the fft result at this freq bin
-                                        // iterate over the target notes and update the magnitudes
-        for FreqMagPair { freq, mag } in fft_output_locked.iter_mut() {
-            // freq is the target note's frequency
-            // mag is the magnitude of the target note from previous iteration
-            if (frequency - *freq).abs() < 1.0 && magnitude > THRESHOLD {
-                // update the target note's magnitude if
-                // the frequency in buffer is close to the target note
-                // and the magnitude is greater than the threshold
-                // info!("FOUND CANDIDATE FREQ: {:?}", frequency);
-                *mag = magnitude;
-                // THE ABOVE LINE DOES"T SEEM TO BE WORKING, BECAUSE EVEN AFTER CANDIDATE FREQ IS FOUND,
-                // AT THE END fft_output HAS ALL ZEROS
-                break; // Stop checking once the first match is found and updated
-            }
-        }
+fn update(app: &App, model: &mut Model, event: Update) {
+    // errors reported by the audio thread
+    while let Ok(e) = model.error_receiver.try_recv() {
+        error!("Audio error: {}", e);
+        model.last_error = Some(e.to_string());
     }
 
-    // is the fft_output stll containing all 0.0 mags? if yes then prolly there's some problem
-    let all_zero = fft_output_locked.iter().all(|x| x.mag == 0.0); // RETURNS TRUE
-    if all_zero {
-        warn!("-- fft output (after) has all zeros");
+    // the config file was edited
+    let config_update = model
+        .config_updates
+        .as_ref()
+        .and_then(|updates| updates.try_iter().last());
+    match config_update {
+        Some(Ok(config)) => model.apply_config(app, config),
+        Some(Err(e)) => {
+            // keep going with the last good config
+            error!("{}", e);
+            model.last_error = Some(e.to_string());
+            model.config_error = Some(e.to_string());
+        }
+        None => {}
     }
 
-    // for (i, complex) in buffer.iter().enumerate() {
-    //     let frequency = (i as f32 * sample_rate as f32) / fft_size as f32;
-    //     let magnitude = complex.norm();
-    //     for freq_magnitude in output.iter_mut() {
-    //         if (frequency - freq_magnitude.re).abs() < 1.0 && magnitude > 1.0 {
-    //             // Update the magnitude if the condition is met
-    //             freq_magnitude.im = magnitude;
-    //             break; // Stop checking once the first match is found and updated
-    //         }
-    //     }
-    // }
-
-    // println!("--output: {:?}", output);
-}
-
-fn generate_note_frequencies(octaves: usize) -> Vec<f32> {
-    let base_notes = [
-        261.63, 277.18, 293.66, 311.13, 329.63, 349.23, 369.99, 392.00, 415.30, 440.00, 466.16,
-        493.88,
-    ];
-    let mut frequencies = Vec::new();
-    for octave in 0..octaves {
-        let multiplier = 2.0f32.powi(octave as i32);
-        frequencies.extend(base_notes.iter().map(|&f| f * multiplier));
-    }
-    frequencies
-}
-
-type RodioSource =
-    rodio::source::Amplify<rodio::source::SamplesConverter<Decoder<BufReader<File>>, i16>>;
-fn load_audio(file_path: &str) -> Result<RodioSource, &str> {
-    // if mp3:
-    info!("Loading audio file");
-    let t = Instant::now();
-    let file = File::open(file_path).map_err(|_| "Failed to open audio file")?;
-    let file = BufReader::new(file);
-
-    let decoder = match file_path {
-        _ if file_path.ends_with(".mp3") => Decoder::new(file),
-        _ if file_path.ends_with(".wav") => Decoder::new_wav(file),
-        _ => return Err("Unsupported file format"),
-    }
-    .map_err(|_| "Failed to decode audio file")?;
-
-    let source: rodio::source::Amplify<
-        rodio::source::SamplesConverter<Decoder<BufReader<File>>, i16>,
-    > = decoder.convert_samples::<i16>().amplify(0.25);
-    info!("Time to load audio: {:?}", t.elapsed());
-    return Ok(source);
-}
-
-fn audio_control_thread(
-    receiver: Receiver<Command>,
-    playback_position: Arc<Mutex<Duration>>,
-    fft_output: Arc<Mutex<Vec<FreqMagPair>>>,
-    last_fft_generated_at: Arc<Mutex<Option<Instant>>>,
-) {
-    println!("i am in audio_control_thread");
-
-    let file_path = SRC;
-    let source = load_audio(file_path).unwrap();
-
-    // !!!!! SO USING THE SAME SOURCE SOUNDS GOOD
-    // BUT THERE'S A LONG WAIT AT START: UNCOMMENT THIS MAYBE:
-
-    // let file = File::open(file_path).expect("Failed to open audio file");
-    // let mut decoder: MiniDecoder<BufReader<File>> = MiniDecoder::new(BufReader::new(file));
-
-    // // Decode entire file into memory
-    // let mut all_samples: Vec<i16> = Vec::new();
-    // while let Ok(miniFrame { data, .. }) = decoder.next_frame() {
-    //     all_samples.extend(data);
-    // }
-
-    // we need the same decoded Vec<i16> for fft and playback (OR DO WE?)
-    // source.collect() will give that vector, but it will consume source, so we'll recreate it
-
-    let sample_rate = source.sample_rate();
-    let channels = source.channels();
-
-    let source_copy = load_audio(file_path).unwrap();
-    let t = Instant::now();
-    let all_samples: Vec<i16> = source_copy.collect();
-    // all_samples.len() must be divisible by channels
-
-    // create list of all_samples_channel_0, all_samples_channel_1, etc
-    // all samples is interleaved : [ch0 ch1 ch3 ch0 ch1 ch3 ...]
-    let mut all_samples_channels: Vec<Vec<i16>> = vec![Vec::new(); channels as usize];
-    info!(
-        "all_samples.len() {:?} should be divisibel by : {:?}",
-        all_samples.len(),
-        channels
-    );
-    for (i, e) in all_samples.into_iter().enumerate() {
-        let channel_index = i % channels as usize;
-        all_samples_channels[channel_index].push(e);
-    }
-    let all_samples_channels = all_samples_channels;
-
-    info!(
-        "Audio file loaded into memory for fft, time taken: {:?}",
-        t.elapsed()
-    );
-
-    // Generate frequencies for 4 octaves
-    // this is out C4 C#4 ... etc data.
-    // TODO: Actually we need to run this only one time in the whole program,
-    // so we can move it to main or during audio setup
-    let output: Vec<FreqMagPair> = generate_note_frequencies(4)
-        .into_iter()
-        .map(|x| FreqMagPair {
-            freq: x,
-            mag: 0.0,
-        })
-        .collect();
-    
-    *fft_output.lock().unwrap() = output;
-
-    let window_size = (0.5 * sample_rate as f32) as usize * channels as usize;
-    let mut planner = FftPlanner::new();
-    let fft = planner.plan_fft_forward(window_size);
-
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&stream_handle).unwrap();
-    sink.append(source);
-
-    *playback_position.lock().unwrap() = Duration::from_secs(0); // important cuz all that writing all_samples takes time
-    info!("Audio loaded and ready to play");
-
-    for command in receiver {
-        match command {
-            Command::CalculateFFT => {
-                // println!("Calculating FFT");
-                // let elapsed = last_play_time.elapsed();
-                let t1 = Instant::now();
-                let start_pos = *playback_position.lock().unwrap();
-                // println!("will this be {:?}", start_pos);
-                let samples_offset = (start_pos.as_secs_f32() * sample_rate as f32) as usize;
-                println!(
-                    "playback at: (based on duration since start) {:?}",
-                    start_pos
-                );
-                // println!("and time from start: {:?}", Instant::now()-*time_at_start);
-                // println!("TIME DIFF: {:?}", Instant::now()-*time_at_start - start_pos);
-
-                // calculate fft for a given channel
-                let process_fft_for_channel = |channel_num: usize| {
-                    info!("Calculating FFT for channel: {:?}", channel_num);
-                    let all_samples_for_ch = &all_samples_channels[channel_num];
-                    if samples_offset + window_size <= all_samples_for_ch.len() {
-                        let mut buffer: Vec<Complex<f32>> = all_samples_for_ch
-                            [samples_offset..samples_offset + window_size]
-                            .iter()
-                            .map(|&x| Complex::new(x as f32, 0.0))
-                            .collect();
-                        info!(
-                            "-- sample offset {:?} window size {:?}",
-                            samples_offset, window_size
-                        );
-                        // info!("-- buffer len:{:?} {:?}",buffer.len(), buffer);
-                        fft.process(&mut buffer); // Perform FFT in-place
-
-                        // Display the frequency and magnitude information
-                        display_frequencies(
-                            &buffer,
-                            sample_rate as usize,
-                            window_size,
-                            Arc::clone(&fft_output),
-                        );
-                        let t2 = t1.elapsed() + start_pos;
-                        println!(
-                            "sampled time: (based on pos in fft buffer): {:?}",
-                            t2.as_secs_f32()
-                        );
-                    } else {
-                        println!(
-                        "Not enough data available for FFT calculation at the current position."
-                    );
-                    }
-                };
-
-                let want_to_add = false; // DON"T SET TO TRUE!! DOESN"T WORKKKKKK
-                let mut res_buffer: Vec<Complex<f32>> = vec![Complex::new(0.0, 0.0); window_size];
-
-                if !want_to_add {
-                    // calculate for only channel 0
-                    process_fft_for_channel(0);
-                } else {
-                    // TODO: use the process_fft_for_channel closure here also (slight modfn needed)
-                    // add all fft result into the res_buffer
-
-                    // calc fft for each buffer
-                    for i in 0..channels as usize {
-                        let all_samples_for_ch = &all_samples_channels[i];
-                        info!("Calculating FFT for channel: {:?}", i);
-                        if samples_offset + window_size <= all_samples_for_ch.len() {
-                            let mut buffer: Vec<Complex<f32>> = all_samples_for_ch
-                                [samples_offset..samples_offset + window_size]
-                                .iter()
-                                .map(|&x| Complex::new(x as f32, 0.0))
-                                .collect();
-                            fft.process(&mut buffer); // Perform FFT in-place
-                            for (j, val) in buffer.iter().enumerate() {
-                                res_buffer[j] = res_buffer[j] + val;
-                            }
-                        } else {
-                            println!(
-                                "Not enough data available for FFT calculation at the current position."
-                            );
-                        }
-                    }
-                    display_frequencies(
-                        &res_buffer,
-                        sample_rate as usize,
-                        window_size,
-                        Arc::clone(&fft_output),
-                    );
-                }
-
-                //     // The data is like [ch0, ch1, ch0, ch1, ch0, ch1, ...]
-                //     // So we may need to skip some samples to get the correct channel
-                //     let samples_offset =
-                //         (start_pos.as_secs_f32() * sample_rate as f32) as usize * channels as usize;
-                //     println!("{:?}", start_pos);
-                //     if samples_offset + window_size * channels as usize <= all_samples.len() {
-                //         // De-interleave the data: collect samples for the first channel (e.g., left channel in a stereo file)
-                //         let mut buffer: Vec<Complex<f32>> = (0..window_size)
-                //             .filter_map(|i| all_samples.get(samples_offset + i * channels as usize)) // Get every 'channels'-th sample starting from 'samples_offset'
-                //             .map(|&x| Complex::new(x as f32, 0.0))
-                //             .collect();
-
-                //         if buffer.len() == window_size {
-                //             fft.process(&mut buffer); // Perform FFT in-place
-
-                //             // Display the frequency and magnitude information
-                //             display_frequencies(
-                //                 &buffer,
-                //                 sample_rate as usize,
-                //                 window_size,
-                //                 fft_output.clone(),
-                //             );
-                //         } else {
-                //             println!(
-                //                 "Insufficient samples for a complete buffer after de-interleaving."
-                //             );
-                //         }
-                //     } else {
-                //         println!(
-                //             "Not enough data available for FFT calculation at the current position."
-                //         );
-                //     }
-
-                info!("FFT Complete: Time taken to complete: {:?}", t1.elapsed());
-                let mut lock = last_fft_generated_at.lock().unwrap();
-                // if the last fft generated was at Some(instant), then we can calculate the time difference
-                if let Some(last_time) = *lock {
-                    let time_diff = Instant::now() - last_time;
-                    info!("Time since last FFT : {:?} ", time_diff);
-                }
-                *lock = Some(Instant::now());
-            }
-            Command::Play => {
-                println!("Playing audio");
-                sink.play();
-            }
-            Command::Pause => {
-                println!("Pausing audio");
-                // HOW does pause work if we're not keeping track of playback_position?
-                // actually playback position does stop updating when paused
-                // but why?!
-                sink.pause();
-            }
-            Command::Seek(position) => {
-                println!("Seeking audio to {:?}", position);
-                if let Err(e) = sink.try_seek(position) {
-                    eprintln!("Failed to seek: {}", e);
-                } else {
-                    *playback_position.lock().unwrap() = position;
-                    sink.play();
-                }
+    // a new track was loaded
+    let new_track_info = model.playback.new_track_info.lock().unwrap().take();
+    if let Some((index, info)) = new_track_info {
+        model.current_track_index = index as u32;
+        for element in model.ui_elements.iter_mut() {
+            if let ui::UIElem::SongInfo(song_info) = element {
+                song_info.set(&info);
+                song_info.set_queue_position(index, model.mp3_files.len());
             }
         }
+        model.draw_config.set_palette(info.palette.clone());
+        model.playback.track_info = Some(info);
+        // loops belong to a track, the audio thread's went with the old one
+        model.ab_loop = AbLoop::Off;
     }
-}
 
-fn update(app: &App, model: &mut Model, event: Update) {
     model.temp += event.since_last.as_millis();
     if model.temp > 100 {
         model.temp = 0;
         // println!("------ event called{:?}", event.since_last.as_secs_f32());
-        model.sender.send(Command::CalculateFFT).unwrap();
+        model.send(Command::CalculateFFT);
     }
-    // update curr position
-    if !model.playback.is_playing {
-        let elapsed = event.since_last;
-        let mut lock = model.playback.curr_pos.lock().unwrap();
-        *lock += elapsed;
+
+    // volume and sleep timer
+    let volume = {
+        let mut volume = model.playback.volume.lock().unwrap();
+        let status = *volume;
+        volume.asleep = false;
+        status
+    };
+    if volume.asleep {
+        // the sleep timer paused playback, so the next Space resumes it
+        model.playback.is_playing = false;
+    }
+    let input_level = *model.playback.input_level.lock().unwrap();
+    for element in model.ui_elements.iter_mut() {
+        match element {
+            ui::UIElem::VolumeInfo(volume_info) => volume_info.set(&volume),
+            ui::UIElem::LevelMeter(meter) => meter.set(input_level),
+            _ => {}
+        }
+    }
+
+    // elapsed / total overlay
+    if let Some(info) = &model.playback.track_info {
+        let elapsed = model.playback.clock.heard_position();
+        for element in model.ui_elements.iter_mut() {
+            if let ui::UIElem::TimeStamp(time_stamp) = element {
+                time_stamp.set(elapsed, info.duration);
+            }
+        }
+    }
+
+    // the scroller, bookmark ticks and loop on the seek bar
+    let duration = model
+        .playback
+        .track_info
+        .as_ref()
+        .map_or(Duration::ZERO, |info| info.duration);
+    let ticks: Vec<f32> = if duration.is_zero() {
+        vec![]
+    } else {
+        model
+            .track_bookmarks()
+            .iter()
+            .map(|b| b.position.as_secs_f32() / duration.as_secs_f32())
+            .collect()
+    };
+    let fraction = |t: Duration| t.as_secs_f32() / duration.as_secs_f32();
+    let loop_region = match model.ab_loop {
+        _ if duration.is_zero() => None,
+        AbLoop::Off => None,
+        AbLoop::Started(a) => Some((fraction(a), fraction(a))),
+        AbLoop::Looping { start, end } => Some((fraction(start), fraction(end))),
+    };
+    let scroller_pos = if duration.is_zero() {
+        0.0
+    } else {
+        fraction(model.playback.clock.heard_position())
+    };
+    for element in model.ui_elements.iter_mut() {
+        if let ui::UIElem::SeekLine(seekline) = element {
+            seekline.set_bookmarks(&ticks);
+            seekline.set_loop(loop_region);
+            seekline.set_scroller_pos(scroller_pos);
+        }
     }
 }
 
 fn view(app: &App, model: &Model, frame: Frame) {
+    if model.calibrating {
+        let clock = &model.playback.clock;
+        // the click has to come out of the output before it's heard, the flash doesn't
+        let heard = clock.calibration_position().checked_sub(clock.latency());
+        let message = format!(
+            "calibrating: latency {} ms. Up / Down until the flash is on the click, K to save",
+            clock.latency().as_millis()
+        );
+        render_drawing::draw_calibration(
+            app,
+            frame,
+            heard.map_or(false, calibration::is_flash),
+            &message,
+        );
+        return;
+    }
     // calulations for viz
     // let amp = calculation::calculate(&model.playback.is_playing);
 
@@ -641,7 +897,12 @@ fn view(app: &App, model: &Model, frame: Frame) {
         .lock()
         .unwrap_or_else(|e| e.into_inner())
         .to_vec();
-    if x.len() < 48 {
+    let num_notes = 12 * model.draw_config.octaves();
+    if x.len() < num_notes {
+        // nothing to visualize yet, but if loading failed we want to show why
+        if let Some(message) = &model.last_error {
+            render_drawing::draw_message(app, frame, message);
+        }
         return;
     } else {
         let octaves_flat: Vec<f32> = model
@@ -649,19 +910,35 @@ fn view(app: &App, model: &Model, frame: Frame) {
             .fft_output
             .lock()
             .unwrap_or_else(|e| e.into_inner())
-            .to_vec()[x.len() - 48..]
+            .to_vec()[x.len() - num_notes..]
             .iter()
             .map(|x| x.mag)
             .collect();
 
         let octaves: Vec<Vec<f32>> = octaves_flat.chunks(12).map(|x| x.to_vec()).collect();
         // println!("--octaves: {:?}", octaves);
-        assert!(octaves.len() == 4, "Expected 4 octaves");
+        assert!(
+            octaves.len() == model.draw_config.octaves(),
+            "Expected {} octaves",
+            model.draw_config.octaves()
+        );
 
         let data = render_drawing::Data::new(octaves);
+        // the bookmark name prompt, otherwise the last error
+        let prompt = model
+            .naming_bookmark
+            .as_ref()
+            .map(|naming| format!("bookmark name: {}_", naming.name));
 
         // println!("--final data before drawing: {:?}", data);
         // println!("--fft_output: {:?}", octaves_flat);
-        render_drawing::draw_on_window(app, frame, &data, &model.ui_elements);
+        render_drawing::draw_on_window(
+            app,
+            frame,
+            &data,
+            &model.ui_elements,
+            &model.draw_config,
+            prompt.as_deref().or(model.last_error.as_deref()),
+        );
     }
 }
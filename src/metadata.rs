@@ -0,0 +1,131 @@
+use lofty::picture::PictureType;
+use lofty::prelude::*;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::AudioError;
+use crate::palette::Palette;
+
+/// Tags and technical info of a track, read with lofty when the track loads.
+#[derive(Debug, Clone, Default)]
+pub struct TrackInfo {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub duration: Duration,
+    /// audio bitrate in kbps
+    pub bitrate: Option<u32>,
+    pub codec: String,
+    pub channels: Option<u8>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u8>,
+    /// the encoded front cover image, if the tags have one
+    pub cover_art: Option<Vec<u8>>,
+    /// colours picked from the cover art, filled in by the loader
+    pub palette: Option<Palette>,
+    /// identifies the file for its bookmarks, filled in by the loader
+    pub content_hash: Option<String>,
+}
+
+impl TrackInfo {
+    /// the title tag, or the file name if there isn't one
+    pub fn display_title(&self) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            self.path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        })
+    }
+}
+
+/// Read tags and audio properties of the file at `path`
+pub fn read_track_info(path: &Path) -> Result<TrackInfo, AudioError> {
+    let tagged_file = lofty::read_from_path(path).map_err(|err| AudioError::Metadata {
+        path: path.into(),
+        err,
+    })?;
+    let properties = tagged_file.properties();
+
+    let mut info = TrackInfo {
+        path: path.into(),
+        duration: properties.duration(),
+        bitrate: properties
+            .audio_bitrate()
+            .or_else(|| properties.overall_bitrate()),
+        codec: codec_name(tagged_file.file_type()).to_string(),
+        channels: properties.channels(),
+        sample_rate: properties.sample_rate(),
+        bit_depth: properties.bit_depth(),
+        ..Default::default()
+    };
+
+    // the format's main tag type if it has one, otherwise whatever tag we find (eg. ID3v2 in a FLAC)
+    if let Some(tag) = tagged_file.primary_tag().or(tagged_file.first_tag()) {
+        info.title = tag.title().map(|s| s.into_owned());
+        info.artist = tag.artist().map(|s| s.into_owned());
+        info.album = tag.album().map(|s| s.into_owned());
+        info.year = tag.year();
+        info.genre = tag.genre().map(|s| s.into_owned());
+        // not every tagger sets the picture type, fall back to whatever picture there is
+        info.cover_art = tag
+            .get_picture_type(PictureType::CoverFront)
+            .or(tag.pictures().first())
+            .map(|picture| picture.data().to_vec());
+    }
+
+    Ok(info)
+}
+
+fn codec_name(file_type: lofty::file::FileType) -> &'static str {
+    use lofty::file::FileType;
+    match file_type {
+        FileType::Aac => "AAC",
+        FileType::Aiff => "AIFF",
+        FileType::Ape => "APE",
+        FileType::Flac => "FLAC",
+        FileType::Mpeg => "MP3",
+        FileType::Mp4 => "MP4/AAC",
+        FileType::Mpc => "Musepack",
+        FileType::Opus => "Opus",
+        FileType::Vorbis => "Vorbis",
+        FileType::Speex => "Speex",
+        FileType::Wav => "WAV",
+        FileType::WavPack => "WavPack",
+        FileType::Custom(name) => name,
+        _ => "unknown",
+    }
+}
+
+/// format a duration as m:ss
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// `key: value` lines, one per field, for the --info output
+impl fmt::Display for TrackInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn or_empty<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+        }
+        writeln!(f, "path: {}", self.path.display())?;
+        writeln!(f, "title: {}", or_empty(&self.title))?;
+        writeln!(f, "artist: {}", or_empty(&self.artist))?;
+        writeln!(f, "album: {}", or_empty(&self.album))?;
+        writeln!(f, "year: {}", or_empty(&self.year))?;
+        writeln!(f, "genre: {}", or_empty(&self.genre))?;
+        writeln!(f, "duration: {:.3}", self.duration.as_secs_f64())?;
+        writeln!(f, "bitrate_kbps: {}", or_empty(&self.bitrate))?;
+        writeln!(f, "codec: {}", self.codec)?;
+        writeln!(f, "channels: {}", or_empty(&self.channels))?;
+        writeln!(f, "sample_rate: {}", or_empty(&self.sample_rate))?;
+        writeln!(f, "bit_depth: {}", or_empty(&self.bit_depth))?;
+        let cover_art_len = self.cover_art.as_ref().map(Vec::len);
+        write!(f, "cover_art_bytes: {}", or_empty(&cover_art_len))
+    }
+}
@@ -0,0 +1,70 @@
+use clap::ValueEnum;
+use rodio::queue::SourcesQueueOutput;
+use rodio::Source;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::transition::MixerStatus;
+
+/// How fast the null output takes samples
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum NullSpeed {
+    /// as a sound card would
+    #[default]
+    Realtime,
+    /// as fast as the mixer can make them
+    Fast,
+}
+
+/// Frames taken at a time, like a sound card's buffer
+const BLOCK_FRAMES: usize = 512;
+/// How long a track change holds up the fast output, so the audio thread (which polls every
+/// MIXER_POLL_INTERVAL) sees every track become audible before it ends
+const TRACK_CHANGE_HOLD: Duration = Duration::from_millis(100);
+
+/// Play `source` (a sink's queue, see `Sink::new_idle`) into nothing, for running without a
+/// sound card. The samples are thrown away, but they're taken at the rate a device would take
+/// them, so the mixer keeps the clock and everything that follows it works as usual.
+/// Stops when the sink is dropped.
+pub fn spawn(source: SourcesQueueOutput<f32>, speed: NullSpeed, status: Arc<MixerStatus>) {
+    info!("Playing to the null output, {:?}", speed);
+    thread::spawn(move || run(source, speed, &status));
+}
+
+fn run(mut source: SourcesQueueOutput<f32>, speed: NullSpeed, status: &MixerStatus) {
+    // the virtual clock: frames taken since `start`, at the source's rate
+    let mut start = Instant::now();
+    let mut frames = 0u64;
+    let mut sample_rate = source.sample_rate();
+    loop {
+        let (channels, rate) = (source.channels().max(1) as usize, source.sample_rate());
+        if rate != sample_rate {
+            // restart the clock at the new rate
+            (start, frames, sample_rate) = (Instant::now(), 0, rate);
+        }
+        let (id, position) = (status.audible_id(), status.position());
+        for _ in 0..BLOCK_FRAMES * channels {
+            if source.next().is_none() {
+                debug!("Null output stopped");
+                return;
+            }
+        }
+        frames += BLOCK_FRAMES as u64;
+
+        let block = Duration::from_secs_f64(BLOCK_FRAMES as f64 / sample_rate.max(1) as f64);
+        match speed {
+            NullSpeed::Realtime => {
+                // against the clock rather than a sleep per block, so it doesn't drift
+                let due =
+                    start + Duration::from_secs_f64(frames as f64 / sample_rate.max(1) as f64);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+            // paused or nothing playing: there's nothing to hurry through
+            NullSpeed::Fast if status.position() == position => thread::sleep(block),
+            NullSpeed::Fast if status.audible_id() != id => thread::sleep(TRACK_CHANGE_HOLD),
+            NullSpeed::Fast => {}
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use nannou::color::{Srgb, BLACK, CORNFLOWERBLUE, RED, WHITE};
+use nannou::image;
+use tracing::{debug, warn};
+
+/// Number of colours k-means looks for
+const NUM_CLUSTERS: usize = 5;
+const KMEANS_ITERATIONS: usize = 10;
+/// Cover art is shrunk to at most this size (in pixels, per side) before clustering
+const THUMBNAIL_SIZE: u32 = 64;
+/// Minimum luminance difference between the background and a wave colour
+const MIN_CONTRAST: f32 = 0.3;
+
+/// Colours used to draw one song, picked from its cover art.
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub background: Srgb<u8>,
+    /// one colour per octave ring, repeats if there are more octaves than colours
+    pub waves: Vec<Srgb<u8>>,
+    /// black or white, whichever reads better on the background
+    pub text: Srgb<u8>,
+}
+
+impl Default for Palette {
+    /// the look we had before palettes, used when a track has no cover art
+    fn default() -> Self {
+        Palette {
+            background: CORNFLOWERBLUE,
+            waves: vec![RED],
+            text: BLACK,
+        }
+    }
+}
+
+impl Palette {
+    /// Build a palette from an encoded image (JPEG, PNG, ...).
+    /// Returns None if the image can't be decoded.
+    pub fn from_image_bytes(bytes: &[u8]) -> Option<Self> {
+        let image = match image::load_from_memory(bytes) {
+            Ok(image) => image,
+            Err(e) => {
+                warn!("Failed to decode cover art: {}", e);
+                return None;
+            }
+        };
+        let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+        let pixels: Vec<[f32; 3]> = thumbnail
+            .pixels()
+            .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+            .collect();
+        let palette = Self::from_colors(kmeans(&pixels, NUM_CLUSTERS));
+        debug!("Palette from cover art: {:?}", palette);
+        palette
+    }
+
+    /// `colors` are cluster centres sorted by how much of the image they cover.
+    /// The most common colour becomes the background, the rest become wave colours.
+    fn from_colors(colors: Vec<[f32; 3]>) -> Option<Self> {
+        let (&background, rest) = colors.split_first()?;
+        let bg_luminance = luminance(background);
+
+        let mut waves: Vec<Srgb<u8>> = rest
+            .iter()
+            .map(|&color| to_srgb(with_contrast(color, bg_luminance)))
+            .collect();
+        if waves.is_empty() {
+            // single colour cover, derive one from the background
+            waves.push(to_srgb(with_contrast(background, bg_luminance)));
+        }
+
+        let text = if bg_luminance > 0.5 { BLACK } else { WHITE };
+        Some(Palette {
+            background: to_srgb(background),
+            waves,
+            text,
+        })
+    }
+
+    /// wave colour for the i'th octave
+    pub fn wave(&self, i: usize) -> Srgb<u8> {
+        self.waves[i % self.waves.len()]
+    }
+}
+
+/// Cluster `pixels` into at most `k` colours.
+/// Returns the cluster centres, most populated first. Empty clusters are dropped.
+fn kmeans(pixels: &[[f32; 3]], k: usize) -> Vec<[f32; 3]> {
+    if pixels.is_empty() {
+        return vec![];
+    }
+
+    // deterministic farthest-point init, so the same cover always gives the same palette
+    let mut centres = vec![pixels[0]];
+    while centres.len() < k {
+        let farthest = pixels
+            .iter()
+            .map(|p| (p, nearest(&centres, p).1))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .filter(|(_, dist)| *dist > 0.0);
+        match farthest {
+            Some((p, _)) => centres.push(*p),
+            // fewer distinct colours than k
+            None => break,
+        }
+    }
+
+    let mut counts = vec![0usize; centres.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![[0.0f32; 3]; centres.len()];
+        counts.iter_mut().for_each(|c| *c = 0);
+        for p in pixels {
+            let (i, _) = nearest(&centres, p);
+            for axis in 0..3 {
+                sums[i][axis] += p[axis];
+            }
+            counts[i] += 1;
+        }
+        for (i, centre) in centres.iter_mut().enumerate() {
+            if counts[i] > 0 {
+                *centre = sums[i].map(|sum| sum / counts[i] as f32);
+            }
+        }
+    }
+
+    let mut clusters: Vec<([f32; 3], usize)> = centres
+        .into_iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    clusters.sort_by(|a, b| b.1.cmp(&a.1));
+    clusters.into_iter().map(|(centre, _)| centre).collect()
+}
+
+/// index of and squared distance to the closest centre
+fn nearest(centres: &[[f32; 3]], p: &[f32; 3]) -> (usize, f32) {
+    centres
+        .iter()
+        .map(|c| (0..3).map(|axis| (c[axis] - p[axis]).powi(2)).sum::<f32>())
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 0.0))
+}
+
+/// relative luminance, 0.0 to 1.0 (ignores gamma, good enough for picking contrast)
+fn luminance(color: [f32; 3]) -> f32 {
+    (0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]) / 255.0
+}
+
+/// lighten or darken `color` until it stands out from a background of `bg_luminance`
+fn with_contrast(color: [f32; 3], bg_luminance: f32) -> [f32; 3] {
+    let diff = luminance(color) - bg_luminance;
+    if diff.abs() >= MIN_CONTRAST {
+        return color;
+    }
+    // move away from the background, towards white on dark backgrounds and black on light ones
+    if bg_luminance < 0.5 {
+        let t = (MIN_CONTRAST - diff).min(1.0);
+        color.map(|c| c + (255.0 - c) * t)
+    } else {
+        let t = (MIN_CONTRAST + diff).min(1.0);
+        color.map(|c| c * (1.0 - t))
+    }
+}
+
+fn to_srgb(color: [f32; 3]) -> Srgb<u8> {
+    let [r, g, b] = color.map(|c| c.round().clamp(0.0, 255.0) as u8);
+    Srgb::new(r, g, b)
+}
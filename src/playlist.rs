@@ -0,0 +1,308 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::playlist_file::PlaylistEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// play the current track again when it ends
+    One,
+    /// start over from the first track after the last one
+    All,
+}
+
+impl RepeatMode {
+    /// Off -> All -> One -> Off, for a single toggle key
+    pub fn cycle(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+}
+
+/// Crossfade lengths the C key steps through, zero is gapless
+pub const CROSSFADE_STEPS: [Duration; 4] = [
+    Duration::ZERO,
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// How the playlist moves on when a track ends.
+/// Set by the UI, read by the audio thread before it picks the next track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PlaybackMode {
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+    /// equal-power crossfade between tracks, zero for gapless
+    pub crossfade: Duration,
+}
+
+impl PlaybackMode {
+    /// the crossfade after this one in CROSSFADE_STEPS, wrapping back to gapless
+    pub fn next_crossfade(&self) -> Duration {
+        CROSSFADE_STEPS
+            .iter()
+            .copied()
+            .find(|&step| step > self.crossfade)
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+impl fmt::Display for PlaybackMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shuffle = if self.shuffle { "shuffle" } else { "in order" };
+        let repeat = match self.repeat {
+            RepeatMode::Off => "repeat off",
+            RepeatMode::One => "repeat one",
+            RepeatMode::All => "repeat all",
+        };
+        write!(f, "{} · {} · ", shuffle, repeat)?;
+        if self.crossfade.is_zero() {
+            write!(f, "gapless")
+        } else {
+            write!(f, "crossfade {}s", self.crossfade.as_secs_f32())
+        }
+    }
+}
+
+/// The ordered list of tracks to play and which one is playing.
+/// Owned by the audio thread, the UI only sees the index it publishes.
+///
+/// Tracks are played through `order`, a permutation of the track indices. It's the identity
+/// normally and a seeded shuffle in shuffle mode, so every track plays once before any repeats
+/// and the same seed always gives the same order.
+#[derive(Debug, Clone, Default)]
+pub struct Playlist {
+    tracks: Vec<PlaylistEntry>,
+    order: Vec<usize>,
+    /// position in `order` of the current track
+    position: usize,
+    mode: PlaybackMode,
+    seed: u64,
+    /// how many times the shuffle has been redone, each round gets its own permutation
+    shuffle_round: u64,
+}
+
+impl Playlist {
+    /// `seed` drives the shuffle order
+    pub fn new(tracks: Vec<PlaylistEntry>, seed: u64) -> Self {
+        let order = (0..tracks.len()).collect();
+        Playlist {
+            tracks,
+            order,
+            position: 0,
+            mode: PlaybackMode::default(),
+            seed,
+            shuffle_round: 0,
+        }
+    }
+
+    /// all tracks in their original order, eg. to save the queue
+    pub fn entries(&self) -> &[PlaylistEntry] {
+        &self.tracks
+    }
+
+    /// index of the current track in `entries()`
+    pub fn index(&self) -> usize {
+        self.order.get(self.position).copied().unwrap_or(0)
+    }
+
+    pub fn current(&self) -> Option<&Path> {
+        self.tracks
+            .get(self.index())
+            .map(|entry| entry.path.as_path())
+    }
+
+    /// the current track's entry, to fill in its title and duration once it's loaded
+    pub fn current_mut(&mut self) -> Option<&mut PlaylistEntry> {
+        let index = self.index();
+        self.tracks.get_mut(index)
+    }
+
+    pub fn mode(&self) -> PlaybackMode {
+        self.mode
+    }
+
+    /// Switch modes. Turning shuffle on shuffles the tracks that haven't played yet, so nothing
+    /// comes round again before they all have. Turning it off goes back to the original order
+    /// from the current track.
+    pub fn set_mode(&mut self, mode: PlaybackMode) {
+        if mode.shuffle != self.mode.shuffle {
+            let current = self.index();
+            if mode.shuffle {
+                self.shuffle_remaining();
+            } else {
+                self.order = (0..self.tracks.len()).collect();
+                self.position = current;
+            }
+        }
+        self.mode = mode;
+    }
+
+    /// The track to play after the current one ends on its own: the same one in repeat-one,
+    /// otherwise the next. Returns None when playback should stop.
+    pub fn advance(&mut self) -> Option<&Path> {
+        if self.mode.repeat == RepeatMode::One {
+            return self.current();
+        }
+        self.next()
+    }
+
+    /// Move to the next track. At the end of the list this wraps around in repeat-all
+    /// (with a fresh shuffle if shuffling), otherwise returns None and stays put.
+    pub fn next(&mut self) -> Option<&Path> {
+        if self.position + 1 < self.order.len() {
+            self.position += 1;
+            return self.current();
+        }
+        if self.mode.repeat != RepeatMode::All || self.tracks.is_empty() {
+            return None;
+        }
+        if self.mode.shuffle {
+            self.shuffle_round += 1;
+            self.reshuffle();
+        }
+        self.position = 0;
+        self.current()
+    }
+
+    /// Move to the previous track. At the start of the list this wraps around in repeat-all,
+    /// otherwise returns None and stays put.
+    pub fn previous(&mut self) -> Option<&Path> {
+        if self.position > 0 {
+            self.position -= 1;
+            return self.current();
+        }
+        if self.mode.repeat != RepeatMode::All || self.tracks.is_empty() {
+            return None;
+        }
+        self.position = self.order.len() - 1;
+        self.current()
+    }
+
+    /// Move to the track at `index` in `entries()`. Returns None (and stays put) if it's out of range.
+    pub fn jump_to(&mut self, index: usize) -> Option<&Path> {
+        let position = self.order.iter().position(|&i| i == index)?;
+        self.position = position;
+        self.current()
+    }
+
+    /// shuffle the tracks after the current one, the ones up to it have played
+    fn shuffle_remaining(&mut self) {
+        let mut rng = self.rng();
+        if let Some(remaining) = self.order.get_mut(self.position + 1..) {
+            remaining.shuffle(&mut rng);
+        }
+    }
+
+    fn reshuffle(&mut self) {
+        let mut rng = self.rng();
+        self.order = (0..self.tracks.len()).collect();
+        self.order.shuffle(&mut rng);
+    }
+
+    fn rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.seed.wrapping_add(self.shuffle_round))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn playlist(len: usize, seed: u64) -> Playlist {
+        let tracks = (0..len)
+            .map(|i| PlaylistEntry::new(PathBuf::from(format!("{}.mp3", i))))
+            .collect();
+        Playlist::new(tracks, seed)
+    }
+
+    fn shuffled(mut playlist: Playlist) -> Playlist {
+        playlist.set_mode(PlaybackMode {
+            shuffle: true,
+            ..playlist.mode()
+        });
+        playlist
+    }
+
+    /// the indices played from the current track to the end of the list
+    fn play_to_end(playlist: &mut Playlist) -> Vec<usize> {
+        let mut played = vec![playlist.index()];
+        while playlist.next().is_some() {
+            played.push(playlist.index());
+        }
+        played
+    }
+
+    fn is_permutation(played: &[usize], len: usize) -> bool {
+        let mut sorted = played.to_vec();
+        sorted.sort_unstable();
+        sorted == (0..len).collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once() {
+        let mut playlist = shuffled(playlist(50, 7));
+        let played = play_to_end(&mut playlist);
+        assert!(is_permutation(&played, 50), "{:?}", played);
+        assert_ne!(played, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn same_seed_same_order() {
+        let first = play_to_end(&mut shuffled(playlist(50, 42)));
+        let second = play_to_end(&mut shuffled(playlist(50, 42)));
+        let other_seed = play_to_end(&mut shuffled(playlist(50, 43)));
+        assert_eq!(first, second);
+        assert_ne!(first, other_seed);
+    }
+
+    #[test]
+    fn shuffle_mid_playlist_only_shuffles_what_hasnt_played() {
+        let mut playlist = playlist(20, 3);
+        for _ in 0..5 {
+            playlist.next();
+        }
+        let mut played = vec![0, 1, 2, 3, 4];
+        let mut playlist = shuffled(playlist);
+        assert_eq!(playlist.index(), 5, "the current track keeps playing");
+        played.extend(play_to_end(&mut playlist));
+        assert!(is_permutation(&played, 20), "{:?}", played);
+    }
+
+    #[test]
+    fn repeat_all_reshuffles_each_round() {
+        let mut playlist = shuffled(playlist(30, 9));
+        playlist.set_mode(PlaybackMode {
+            repeat: RepeatMode::All,
+            ..playlist.mode()
+        });
+        let first_round: Vec<usize> = (0..30)
+            .map(|_| {
+                let index = playlist.index();
+                playlist.next();
+                index
+            })
+            .collect();
+        let second_round: Vec<usize> = (0..30)
+            .map(|_| {
+                let index = playlist.index();
+                playlist.next();
+                index
+            })
+            .collect();
+        assert!(is_permutation(&first_round, 30));
+        assert!(is_permutation(&second_round, 30));
+        assert_ne!(first_round, second_round);
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::error::PlaylistError;
+
+/// One track of a playlist file, with the duration and title the file gives for it (if any).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    pub path: PathBuf,
+    pub duration: Option<Duration>,
+    pub title: Option<String>,
+}
+
+impl PlaylistEntry {
+    pub fn new(path: PathBuf) -> Self {
+        PlaylistEntry {
+            path,
+            duration: None,
+            title: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    M3u,
+    M3u8,
+    Pls,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "m3u" => Some(Format::M3u),
+            "m3u8" => Some(Format::M3u8),
+            "pls" => Some(Format::Pls),
+            _ => None,
+        }
+    }
+}
+
+/// true if `path` has a playlist extension (.m3u, .m3u8 or .pls)
+pub fn is_playlist_file(path: &Path) -> bool {
+    Format::from_path(path).is_some()
+}
+
+/// Read an M3U/M3U8 or PLS playlist, picked by extension.
+/// Relative paths in it are resolved against the playlist's own directory.
+pub fn load(path: &Path) -> Result<Vec<PlaylistEntry>, PlaylistError> {
+    let format = Format::from_path(path)
+        .ok_or_else(|| PlaylistError::UnsupportedFormat { path: path.into() })?;
+    let bytes = fs::read(path).map_err(|err| PlaylistError::Io {
+        path: path.into(),
+        err,
+    })?;
+    let contents = decode_text(&bytes, format);
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut entries = match format {
+        Format::M3u | Format::M3u8 => parse_m3u(&contents),
+        Format::Pls => parse_pls(&contents).map_err(|(line, reason)| PlaylistError::Parse {
+            path: path.into(),
+            line,
+            reason,
+        })?,
+    };
+    for entry in entries.iter_mut() {
+        entry.path = resolve(base_dir, &entry.path);
+    }
+    debug!("Loaded {} entries from {:?}", entries.len(), path);
+    Ok(entries)
+}
+
+/// Write `entries` as an M3U/M3U8 or PLS playlist, picked by extension.
+/// Tracks under the playlist's directory are written as relative paths, so the
+/// playlist keeps working when the whole folder is moved to another machine.
+pub fn save(path: &Path, entries: &[PlaylistEntry]) -> Result<(), PlaylistError> {
+    let format = Format::from_path(path)
+        .ok_or_else(|| PlaylistError::UnsupportedFormat { path: path.into() })?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let relative: Vec<PlaylistEntry> = entries
+        .iter()
+        .map(|entry| PlaylistEntry {
+            path: relative_to(base_dir, &entry.path),
+            ..entry.clone()
+        })
+        .collect();
+
+    let contents = match format {
+        Format::M3u | Format::M3u8 => write_m3u(&relative),
+        Format::Pls => write_pls(&relative),
+    };
+    fs::write(path, contents).map_err(|err| PlaylistError::Io {
+        path: path.into(),
+        err,
+    })
+}
+
+/// .m3u8 and .pls are UTF-8, plain .m3u is traditionally Latin-1 but often UTF-8 in practice
+fn decode_text(bytes: &[u8], format: Format) -> String {
+    // a BOM shows up in files saved by some Windows players
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) if format == Format::M3u => bytes.iter().map(|&b| b as char).collect(),
+        Err(_) => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Extended M3U: `#EXTINF:<seconds>,<title>` before a path applies to that path.
+/// Any other `#` line is a comment or a directive we don't use.
+fn parse_m3u(contents: &str) -> Vec<PlaylistEntry> {
+    let mut entries = vec![];
+    let mut extinf: Option<(Option<Duration>, Option<String>)> = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            extinf = Some(parse_extinf(info));
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let (duration, title) = extinf.take().unwrap_or((None, None));
+        entries.push(PlaylistEntry {
+            path: path_from_location(line),
+            duration,
+            title,
+        });
+    }
+    entries
+}
+
+/// `<seconds>[ key="value" ...],<title>`, seconds is -1 for unknown
+fn parse_extinf(info: &str) -> (Option<Duration>, Option<String>) {
+    let (meta, title) = info.split_once(',').unwrap_or((info, ""));
+    let seconds = meta.split_whitespace().next().unwrap_or("");
+    let duration = seconds
+        .parse::<f64>()
+        .ok()
+        .filter(|s| *s >= 0.0)
+        .map(Duration::from_secs_f64);
+    let title = Some(title.trim().to_string()).filter(|t| !t.is_empty());
+    (duration, title)
+}
+
+/// PLS is an ini file: `FileN=`, `TitleN=` and `LengthN=` keys under `[playlist]`.
+/// Entries are ordered by N, not by where they appear in the file.
+fn parse_pls(contents: &str) -> Result<Vec<PlaylistEntry>, (usize, String)> {
+    let mut files: BTreeMap<u32, PlaylistEntry> = BTreeMap::new();
+    let mut in_playlist = false;
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            in_playlist = line.eq_ignore_ascii_case("[playlist]");
+            continue;
+        }
+        if !in_playlist {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| (i + 1, format!("expected key=value, got {:?}", line)))?;
+        let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+
+        let Some((name, number)) = split_numbered_key(&key) else {
+            // NumberOfEntries, Version: we go by the numbered keys instead
+            continue;
+        };
+        let entry = files
+            .entry(number)
+            .or_insert_with(|| PlaylistEntry::new(PathBuf::new()));
+        match name {
+            "file" => entry.path = path_from_location(value),
+            "title" => entry.title = Some(value.to_string()).filter(|t| !t.is_empty()),
+            "length" => {
+                let seconds: f64 = value
+                    .parse()
+                    .map_err(|_| (i + 1, format!("invalid length {:?}", value)))?;
+                entry.duration = Some(seconds)
+                    .filter(|s| *s >= 0.0)
+                    .map(Duration::from_secs_f64);
+            }
+            _ => {}
+        }
+    }
+
+    let mut entries = vec![];
+    for (number, entry) in files {
+        if entry.path.as_os_str().is_empty() {
+            warn!(
+                "Playlist entry {} has a title or length but no File{}",
+                number, number
+            );
+            continue;
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// "file12" -> ("file", 12)
+fn split_numbered_key(key: &str) -> Option<(&str, u32)> {
+    let digits_at = key.find(|c: char| c.is_ascii_digit())?;
+    let (name, number) = key.split_at(digits_at);
+    Some((name, number.parse().ok()?))
+}
+
+fn write_m3u(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for entry in entries {
+        if entry.duration.is_some() || entry.title.is_some() {
+            let _ = writeln!(
+                out,
+                "#EXTINF:{},{}",
+                seconds_or_unknown(entry.duration),
+                entry.title.as_deref().unwrap_or("")
+            );
+        }
+        let _ = writeln!(out, "{}", entry.path.display());
+    }
+    out
+}
+
+fn write_pls(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (i, entry) in entries.iter().enumerate() {
+        let n = i + 1;
+        let _ = writeln!(out, "File{}={}", n, entry.path.display());
+        if let Some(title) = &entry.title {
+            let _ = writeln!(out, "Title{}={}", n, title);
+        }
+        let _ = writeln!(out, "Length{}={}", n, seconds_or_unknown(entry.duration));
+    }
+    let _ = writeln!(out, "NumberOfEntries={}", entries.len());
+    let _ = writeln!(out, "Version=2");
+    out
+}
+
+/// whole seconds, or -1 which both formats use for "unknown"
+fn seconds_or_unknown(duration: Option<Duration>) -> i64 {
+    duration
+        .map(|d| d.as_secs_f64().round() as i64)
+        .unwrap_or(-1)
+}
+
+/// a playlist line is a path or a `file://` URL
+fn path_from_location(location: &str) -> PathBuf {
+    match location.strip_prefix("file://") {
+        Some(path) => PathBuf::from(percent_decode(path)),
+        // Windows playlists use backslashes, which are just part of the file name on unix
+        None if cfg!(unix) => PathBuf::from(location.replace('\\', "/")),
+        None => PathBuf::from(location),
+    }
+}
+
+/// decode %XX escapes, as used in file:// URLs
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn resolve(base_dir: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base_dir.join(path)
+    }
+}
+
+/// `path` relative to `base_dir` if it's inside it, otherwise absolute. A path relative to
+/// the working directory would resolve against the playlist's directory when it's loaded.
+fn relative_to(base_dir: &Path, path: &Path) -> PathBuf {
+    let Ok(path_abs) = absolute(path) else {
+        // no working directory to make it absolute with
+        return path.to_path_buf();
+    };
+    let Ok(base_dir) = absolute(base_dir) else {
+        return path_abs;
+    };
+    match path_abs.strip_prefix(&base_dir) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path_abs,
+    }
+}
+
+/// absolute and without `.`/`..`, without touching the filesystem (the file may not exist)
+fn absolute(path: &Path) -> std::io::Result<PathBuf> {
+    let joined = std::env::current_dir()?.join(path);
+    let mut out = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn m3u_extinf_gives_duration_and_title() {
+        let entries = parse_m3u(
+            "#EXTM3U\n#EXTINF:123,Artist - Title\nsong.mp3\n#EXTINF:-1,\nother.flac\nplain.wav\n",
+        );
+        assert_eq!(
+            entries,
+            vec![
+                PlaylistEntry {
+                    path: "song.mp3".into(),
+                    duration: Some(Duration::from_secs(123)),
+                    title: Some("Artist - Title".to_string()),
+                },
+                PlaylistEntry::new("other.flac".into()),
+                PlaylistEntry::new("plain.wav".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn extinf_attributes_are_skipped() {
+        let (duration, title) = parse_extinf(r#"7.5 tvg-id="x",Title, with comma"#);
+        assert_eq!(duration, Some(Duration::from_secs_f64(7.5)));
+        assert_eq!(title.as_deref(), Some("Title, with comma"));
+    }
+
+    #[test]
+    fn pls_entries_are_ordered_by_number() {
+        let entries = parse_pls(
+            "[playlist]\nFile2=b.mp3\nTitle2=Bee\nLength2=-1\nFile1=a.mp3\nTitle1=Ay\nLength1=61\nNumberOfEntries=2\nVersion=2\n",
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                PlaylistEntry {
+                    path: "a.mp3".into(),
+                    duration: Some(Duration::from_secs(61)),
+                    title: Some("Ay".to_string()),
+                },
+                PlaylistEntry {
+                    path: "b.mp3".into(),
+                    duration: None,
+                    title: Some("Bee".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pls_bad_length_reports_the_line() {
+        let err = parse_pls("[playlist]\nFile1=a.mp3\nLength1=long\n").unwrap_err();
+        assert_eq!(err.0, 3);
+    }
+
+    #[test]
+    fn relative_paths_resolve_against_the_playlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let playlist = dir.path().join("list.m3u8");
+        fs::write(&playlist, "sub/a.mp3\n/abs/b.mp3\nfile:///abs/c%20d.mp3\n").unwrap();
+        let paths: Vec<PathBuf> = load(&playlist)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                dir.path().join("sub/a.mp3"),
+                PathBuf::from("/abs/b.mp3"),
+                PathBuf::from("/abs/c d.mp3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip_keeps_paths_titles_and_durations() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = vec![
+            PlaylistEntry {
+                path: dir.path().join("music/a.mp3"),
+                duration: Some(Duration::from_secs(200)),
+                title: Some("A".to_string()),
+            },
+            PlaylistEntry::new(dir.path().join("b.flac")),
+        ];
+        for name in ["list.m3u", "list.m3u8", "list.pls"] {
+            let playlist = dir.path().join(name);
+            save(&playlist, &entries).unwrap();
+            let written = fs::read_to_string(&playlist).unwrap();
+            assert!(written.contains("music/a.mp3"), "{}", written);
+            assert!(
+                !written.contains(dir.path().to_str().unwrap()),
+                "{}",
+                written
+            );
+            assert_eq!(load(&playlist).unwrap(), entries, "{}", name);
+        }
+    }
+
+    #[test]
+    fn saving_elsewhere_keeps_tracks_outside_the_playlist_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let track = dir.path().join("tracks/a.mp3");
+        let playlist = dir.path().join("lists/list.m3u8");
+        fs::create_dir_all(playlist.parent().unwrap()).unwrap();
+        save(&playlist, &[PlaylistEntry::new(track.clone())]).unwrap();
+        assert_eq!(load(&playlist).unwrap(), vec![PlaylistEntry::new(track)]);
+    }
+
+    #[test]
+    fn cwd_relative_tracks_are_written_absolute() {
+        let dir = tempfile::tempdir().unwrap();
+        let track = Path::new("some/track.mp3");
+        let written = relative_to(dir.path(), track);
+        assert!(written.is_absolute());
+        assert_eq!(written, std::env::current_dir().unwrap().join(track));
+    }
+}
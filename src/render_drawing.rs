@@ -3,6 +3,8 @@ use rand::Rng;
 use splines::{Interpolation, Key, Spline};
 use tracing::{debug, info, warn, Level};
 
+use crate::config::{ColorScheme, Config, UiColors};
+use crate::palette::Palette;
 use crate::ui;
 
 #[derive(Debug)]
@@ -45,6 +47,20 @@ impl Data {
         }
     }
 
+    /// All octaves folded into one: each note gets its loudest amplitude across the octaves.
+    /// CircleWave only draws one octave.
+    fn fold_octaves(&self) -> Data {
+        let notes = (0..12)
+            .map(|note| {
+                self.octaves
+                    .iter()
+                    .filter_map(|octave| octave.get(note))
+                    .fold(0., |acc: f32, &amp| acc.max(amp))
+            })
+            .collect();
+        Data::new(vec![notes])
+    }
+
     /// normalize each amplitude in an octave from MIN to MAX
     fn normalize(octave: Vec<f32>) -> Vec<f32> {
         let min = 0.;
@@ -158,16 +174,91 @@ impl Spline2D {
     }
 }
 
-enum Visualization {
-    CircleWave(CircleWave),
-    CircleWaveMultiple(CircleWaveMultiple),
+/// Which visual `draw_on_window` draws, picked with --visualizer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Visualization {
+    /// one ring, each note at its loudest octave
+    CircleWave,
+    /// one ring per octave
+    CircleWaveMultiple,
 }
 
 pub struct DrawConfig {
     frame_rate: usize,
-    color_scheme: String,
-    resolution: usize,
+    color_scheme: ColorScheme,
+    /// the colours in use, from the cover art or the config
+    palette: Palette,
+    /// `[colors]` from the config
+    config_palette: Palette,
+    /// the current track's cover art colours, if it has any
+    cover_palette: Option<Palette>,
+    resolution: [u32; 2],
     num_samples: usize, // number of samples to generate from spline curve
+    visualizer: Visualization,
+    octaves: usize,
+    ui_colors: UiColors,
+}
+
+impl DrawConfig {
+    pub fn new(visualizer: Visualization, config: &Config) -> Self {
+        let mut draw_config = DrawConfig {
+            frame_rate: 0,
+            color_scheme: ColorScheme::CoverArt,
+            palette: Palette::default(),
+            config_palette: Palette::default(),
+            cover_palette: None,
+            resolution: [0, 0],
+            num_samples: 0,
+            visualizer,
+            octaves: 0,
+            ui_colors: UiColors::default(),
+        };
+        draw_config.apply(config);
+        draw_config
+    }
+
+    /// take the settings from a (re)loaded config
+    pub fn apply(&mut self, config: &Config) {
+        self.frame_rate = config.draw.frame_rate;
+        self.color_scheme = config.draw.color_scheme;
+        self.resolution = config.draw.resolution;
+        self.num_samples = config.draw.num_samples;
+        self.octaves = config.analysis.octaves;
+        self.config_palette = config.colors.palette();
+        self.ui_colors = config.colors.ui.clone();
+        self.update_palette();
+    }
+
+    /// number of octaves the data is expected to have
+    pub fn octaves(&self) -> usize {
+        self.octaves
+    }
+
+    pub fn frame_rate(&self) -> usize {
+        self.frame_rate
+    }
+
+    /// window size in points
+    pub fn resolution(&self) -> [u32; 2] {
+        self.resolution
+    }
+
+    pub fn ui_colors(&self) -> &UiColors {
+        &self.ui_colors
+    }
+
+    /// the current track's cover art colours, None if it has no cover
+    pub fn set_palette(&mut self, cover_palette: Option<Palette>) {
+        self.cover_palette = cover_palette;
+        self.update_palette();
+    }
+
+    fn update_palette(&mut self) {
+        self.palette = match (self.color_scheme, &self.cover_palette) {
+            (ColorScheme::CoverArt, Some(cover_palette)) => cover_palette.clone(),
+            _ => self.config_palette.clone(),
+        };
+    }
 }
 
 struct PointPolar {
@@ -231,12 +322,13 @@ impl CircleWave {
     }
 }
 
-impl DrawVisual for CircleWave {
-    fn draw_visual(&self, draw: &Draw, win: Rect, config: &DrawConfig) {
+impl CircleWave {
+    /// draw the wave in `color`, CircleWaveMultiple gives each octave its own palette colour
+    fn draw_with_color(&self, draw: &Draw, win: Rect, config: &DrawConfig, color: Srgb<u8>) {
         draw.ellipse()
             .x_y(0 as f32, 0 as f32)
             .radius(win.w() * 0.0125)
-            .color(RED);
+            .color(color);
         // self.scale_visual(win);
         let points = self.points.iter().map(|p| p.to_cartesian()).collect();
 
@@ -256,7 +348,7 @@ impl DrawVisual for CircleWave {
             draw.text(note)
                 .font_size(20)
                 .x_y(points_for_text[i].x, points_for_text[i].y)
-                .color(BLACK);
+                .color(config.palette.text);
         }
 
         // feed points to spline_generator
@@ -294,11 +386,17 @@ impl DrawVisual for CircleWave {
 
 
         draw.polyline()
-            .color(RED)
+            .color(color)
             .stroke_weight(5.0)
             .points(spline_samples);
 
     }
+}
+
+impl DrawVisual for CircleWave {
+    fn draw_visual(&self, draw: &Draw, win: Rect, config: &DrawConfig) {
+        self.draw_with_color(draw, win, config, config.palette.wave(0));
+    }
 
     fn scale_visual(&mut self, win: Rect) {
         // scale the circle wave
@@ -333,8 +431,8 @@ impl CircleWaveMultiple {
 
 impl DrawVisual for CircleWaveMultiple {
     fn draw_visual(&self, draw: &Draw, win: Rect, config: &DrawConfig) {
-        for circle_wave in &self.circle_waves {
-            circle_wave.draw_visual(draw, win, config);
+        for (i, circle_wave) in self.circle_waves.iter().enumerate() {
+            circle_wave.draw_with_color(draw, win, config, config.palette.wave(i));
         }
     }
 
@@ -345,29 +443,70 @@ impl DrawVisual for CircleWaveMultiple {
     }
 }
 
-pub fn draw_on_window(app: &App, frame: Frame, data: &Data, ui_elements: &Vec<ui::UIElem>) {
+pub fn draw_on_window(
+    app: &App,
+    frame: Frame,
+    data: &Data,
+    ui_elements: &Vec<ui::UIElem>,
+    draw_config: &DrawConfig,
+    message: Option<&str>,
+) {
     let draw = app.draw();
-    draw.background().color(CORNFLOWERBLUE);
+    draw.background().color(draw_config.palette.background);
     let win = app.window_rect();
 
-    let radii = data.octaves.iter().enumerate().map(|(i, _)| win.w() / 8.0 + i as f32 * 50.).collect();
-    let max_amps = data.octaves.iter().map(|_| win.w() / 16.0).collect();
-    let draw_config = DrawConfig {
-        frame_rate: 60,
-        color_scheme: "red".to_string(),
-        resolution: 200,
-        num_samples: 400,
-    };
-    CircleWaveMultiple::new(&data, radii, max_amps).draw_visual(
-        &draw,
-        win,
-        &draw_config,
-    );
+    match draw_config.visualizer {
+        Visualization::CircleWave => {
+            CircleWave::new(&data.fold_octaves(), win.w() / 8.0, win.w() / 16.0).draw_visual(
+                &draw,
+                win,
+                draw_config,
+            );
+        }
+        Visualization::CircleWaveMultiple => {
+            let radii = data.octaves.iter().enumerate().map(|(i, _)| win.w() / 8.0 + i as f32 * 50.).collect();
+            let max_amps = data.octaves.iter().map(|_| win.w() / 16.0).collect();
+            CircleWaveMultiple::new(&data, radii, max_amps).draw_visual(
+                &draw,
+                win,
+                draw_config,
+            );
+        }
+    }
     ui::render_ui(&draw,
         win,
-        &draw_config,
+        draw_config,
         ui_elements,
     );
+    if let Some(message) = message {
+        draw_message_text(&draw, win, message, draw_config.palette.text);
+    }
+
+    draw.to_frame(app, &frame).unwrap();
+}
+
+/// Draw only a message, for when there's nothing to visualize (eg. the track failed to load)
+pub fn draw_message(app: &App, frame: Frame, message: &str) {
+    let draw = app.draw();
+    draw.background().color(CORNFLOWERBLUE);
+    draw_message_text(&draw, app.window_rect(), message, BLACK);
+    draw.to_frame(app, &frame).unwrap();
+}
 
+/// Latency calibration: the window flashes white with each click
+pub fn draw_calibration(app: &App, frame: Frame, flash: bool, message: &str) {
+    let draw = app.draw();
+    let (background, text) = if flash { (WHITE, BLACK) } else { (BLACK, WHITE) };
+    draw.background().color(background);
+    draw_message_text(&draw, app.window_rect(), message, text);
     draw.to_frame(app, &frame).unwrap();
 }
+
+/// message text along the top of the window
+fn draw_message_text(draw: &Draw, win: Rect, message: &str, color: Srgb<u8>) {
+    draw.text(message)
+        .font_size(16)
+        .w(win.w() - 2. * ui::PADDING)
+        .x_y(0., win.top() - ui::PADDING)
+        .color(color);
+}
@@ -0,0 +1,35 @@
+//! The TOML files the app keeps for itself under `config::data_dir`, like bookmarks and
+//! calibrated latencies. Each store maps `TomlFileError` into its own error.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum TomlFileError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+}
+
+/// Read the file at `path`, the default if it doesn't exist yet
+pub fn read_toml<T: DeserializeOwned + Default>(path: &Path) -> Result<T, TomlFileError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => toml::from_str(&contents).map_err(TomlFileError::Parse),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(TomlFileError::Io(err)),
+    }
+}
+
+/// write to a temporary file and rename it over the old one, so a crash can't leave half a file
+pub fn write_toml_atomically<T: Serialize>(path: &Path, value: &T) -> Result<(), TomlFileError> {
+    let contents = toml::to_string_pretty(value).map_err(TomlFileError::Serialize)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(TomlFileError::Io)?;
+    }
+    let temp_path = path.with_extension("toml.tmp");
+    fs::write(&temp_path, contents).map_err(TomlFileError::Io)?;
+    fs::rename(&temp_path, path).map_err(TomlFileError::Io)
+}
@@ -0,0 +1,499 @@
+use rodio::source::{SeekError, UniformSourceIterator};
+use rodio::Source;
+use std::f32::consts::FRAC_PI_2;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::audio_buffer::{BufferSource, SampleStore};
+use crate::calibration;
+
+/// How many finished decks can wait to be dropped by the audio thread
+const RETIRED_DECKS: usize = 8;
+/// How many frames the mixer plays between checks for new commands
+const COMMAND_CHECK_INTERVAL: u64 = 256;
+/// A pause this long between checks is taken to mean the output has sent a buffer off and
+/// come back for the next one. A guess: rodio doesn't pass on the output callback's timestamps.
+const CALLBACK_GAP: Duration = Duration::from_millis(1);
+
+/// Tells the mixer what to play. Sent by the audio thread, picked up in the output callback.
+pub enum MixerCommand {
+    /// cut to this track now, dropping anything queued
+    Play(Deck),
+    /// play this track right after the current one, crossfaded or gapless
+    Queue(Deck),
+    /// drop the queued track, eg. when shuffle changes what comes next
+    ClearQueue,
+    /// length of the crossfade between tracks, zero for gapless
+    SetCrossfade(Duration),
+    /// play the latency calibration clicks instead of the tracks, which wait where they are
+    Calibrate(bool),
+}
+
+/// One track, converted to the mixer's output format.
+pub struct Deck {
+    id: u64,
+    store: Arc<SampleStore>,
+    source: UniformSourceIterator<BufferSource, f32>,
+    /// the mixer's sample rate
+    sample_rate: u32,
+    /// length in output frames, if the decoder knows it. Needed to start a crossfade on time.
+    total_frames: Option<u64>,
+}
+
+impl Deck {
+    /// `id` is how the mixer reports this track in `MixerStatus`, must not be 0
+    pub fn new(id: u64, store: Arc<SampleStore>, channels: u16, sample_rate: u32) -> Self {
+        let total_frames = store
+            .total_duration()
+            .map(|d| (d.as_secs_f64() * sample_rate as f64) as u64);
+        let source = UniformSourceIterator::new(
+            BufferSource::new(Arc::clone(&store)),
+            channels,
+            sample_rate,
+        );
+        Deck {
+            id,
+            store,
+            source,
+            sample_rate,
+            total_frames,
+        }
+    }
+
+    /// Where in the track playback is, in output frames. Taken from the store's playhead
+    /// rather than counted, so it follows seeks and A-B loops.
+    fn frames_played(&self) -> u64 {
+        let frame = self.store.playhead() as f64;
+        (frame * self.sample_rate as f64 / self.store.sample_rate() as f64) as u64
+    }
+
+    /// Fill `frame` with the next frame. Returns false at the end of the track.
+    fn read_frame(&mut self, frame: &mut [f32]) -> bool {
+        for sample in frame.iter_mut() {
+            match self.source.next() {
+                Some(s) => *sample = s,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// None if the length isn't known, or while an A-B loop keeps the track from ending
+    fn remaining_frames(&self) -> Option<u64> {
+        let looping = self
+            .store
+            .loop_region()
+            .map_or(false, |region| self.store.playhead() < region.end);
+        if looping {
+            return None;
+        }
+        self.total_frames
+            .map(|total| total.saturating_sub(self.frames_played()))
+    }
+}
+
+/// What the mixer is playing, for the audio thread to follow along. This is the playback
+/// clock: the position comes from the samples the output has taken from the mixer, so it
+/// stops when the sink pauses and never runs past the end of a track.
+#[derive(Default)]
+pub struct MixerStatus {
+    /// id of the track you hear, 0 when nothing is playing.
+    /// During a crossfade this switches half way through, when the new track gets louder.
+    audible_id: AtomicU64,
+    /// frames of the audible track played so far. Stays where the last track ended when
+    /// nothing is playing.
+    audible_frame: AtomicU64,
+    /// the mixer's, set when it's created
+    sample_rate: AtomicU32,
+    /// the average number of frames the output takes at a time, 0 until it's been measured
+    buffer_frames: AtomicU64,
+    /// the output latency being compensated for, in microseconds. Set by the audio thread.
+    latency_us: AtomicU64,
+    /// frames of the calibration click track played
+    calibration_frame: AtomicU64,
+}
+
+impl MixerStatus {
+    /// How far into the audible track playback is
+    pub fn position(&self) -> Duration {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        if sample_rate == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(self.audible_frame() as f64 / sample_rate as f64)
+    }
+
+    /// How far into the audible track you've heard, `position` less the output latency. The
+    /// one to show or mark, `position` is ahead by whatever's still on its way to the speakers.
+    pub fn heard_position(&self) -> Duration {
+        self.position().saturating_sub(self.latency())
+    }
+
+    /// The output latency as far as the mixer can tell: one buffer of the output stream,
+    /// rounded to COMMAND_CHECK_INTERVAL frames. Only a lower bound, the device and driver
+    /// buffer more than that. None until the output has taken a few buffers.
+    pub fn measured_latency(&self) -> Option<Duration> {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        let frames = self.buffer_frames.load(Ordering::Relaxed);
+        if sample_rate == 0 || frames == 0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(frames as f64 / sample_rate as f64))
+    }
+
+    /// How long after the mixer plays a sample it's heard
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency_us.load(Ordering::Relaxed))
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.latency_us
+            .store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// How far into the calibration click track the mixer is
+    pub fn calibration_position(&self) -> Duration {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        if sample_rate == 0 {
+            return Duration::ZERO;
+        }
+        let frame = self.calibration_frame.load(Ordering::Relaxed);
+        Duration::from_secs_f64(frame as f64 / sample_rate as f64)
+    }
+
+    /// a running average, so the odd late callback doesn't throw it off
+    fn record_buffer(&self, frames: u64) {
+        let average = self.buffer_frames.load(Ordering::Relaxed);
+        let average = if average == 0 {
+            frames
+        } else {
+            (average * 7 + frames) / 8
+        };
+        self.buffer_frames.store(average, Ordering::Relaxed);
+    }
+
+    /// the audible track's id, None when nothing is playing
+    pub fn audible_id(&self) -> Option<u64> {
+        Some(self.audible_id.load(Ordering::Acquire)).filter(|&id| id != 0)
+    }
+
+    /// frames of the audible track played so far, at the mixer's sample rate
+    pub fn audible_frame(&self) -> u64 {
+        self.audible_frame.load(Ordering::Relaxed)
+    }
+
+    fn set_audible(&self, deck: Option<&Deck>) {
+        if let Some(deck) = deck {
+            self.audible_frame
+                .store(deck.frames_played(), Ordering::Relaxed);
+        }
+        self.audible_id
+            .store(deck.map_or(0, |d| d.id), Ordering::Release);
+    }
+}
+
+/// A never ending source that plays one track after another, so the sink never runs dry
+/// between tracks. The next track is queued ahead of time and either butts right against
+/// the end of the current one (gapless) or fades in over it with an equal-power crossfade.
+///
+/// Every track is converted to one output format, so they can be mixed sample by sample.
+/// Plays silence when there's nothing to play.
+pub struct Mixer {
+    channels: u16,
+    sample_rate: u32,
+    commands: Receiver<MixerCommand>,
+    /// decks the mixer is done with, see `retire`
+    retired: SyncSender<Deck>,
+    status: Arc<MixerStatus>,
+    current: Option<Deck>,
+    next: Option<Deck>,
+    crossfade_frames: u64,
+    /// (frames into the fade, fade length) while crossfading into `next`
+    fade: Option<(u64, u64)>,
+    /// the frame being played, and which of its samples comes next
+    frame: Vec<f32>,
+    frame_pos: usize,
+    /// scratch frame for the incoming track during a fade
+    next_frame: Vec<f32>,
+    frames_until_check: u64,
+    /// when the last command check happened, and the frames taken since the output's
+    /// last buffer started
+    last_check: Option<Instant>,
+    frames_since_callback: u64,
+    /// frames into the calibration click track, while calibrating
+    calibration: Option<u64>,
+}
+
+impl Mixer {
+    /// A mixer playing `channels` channels at `sample_rate` and reporting to `status`,
+    /// with the sender to control it and the receiver for the decks it's finished with,
+    /// which the caller drops.
+    /// The status is made by the caller, so the UI can hold on to it before there's a mixer.
+    pub fn new(
+        channels: u16,
+        sample_rate: u32,
+        status: Arc<MixerStatus>,
+    ) -> (Self, Sender<MixerCommand>, Receiver<Deck>) {
+        let (sender, commands) = mpsc::channel();
+        // bounded, so sending never allocates
+        let (retired, retired_receiver) = mpsc::sync_channel(RETIRED_DECKS);
+        status.sample_rate.store(sample_rate, Ordering::Relaxed);
+        let mixer = Mixer {
+            channels,
+            sample_rate,
+            commands,
+            retired,
+            status,
+            current: None,
+            next: None,
+            crossfade_frames: 0,
+            fade: None,
+            frame: vec![0.0; channels as usize],
+            frame_pos: channels as usize,
+            next_frame: vec![0.0; channels as usize],
+            frames_until_check: 0,
+            last_check: None,
+            frames_since_callback: 0,
+            calibration: None,
+        };
+        (mixer, sender, retired_receiver)
+    }
+
+    /// Hand a deck that's finished back to the audio thread. Dropping it here would free its
+    /// decoder and samples in the output callback.
+    fn retire(&self, deck: Option<Deck>) {
+        if let Some(deck) = deck {
+            // only full if the audio thread has stopped collecting them, then it's dropped here
+            let _ = self.retired.try_send(deck);
+        }
+    }
+
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                MixerCommand::Play(deck) => {
+                    self.fade = None;
+                    let (next, current) = (self.next.take(), self.current.replace(deck));
+                    self.retire(next);
+                    self.retire(current);
+                    self.status.set_audible(self.current.as_ref());
+                }
+                MixerCommand::Queue(deck) => {
+                    self.cancel_fade();
+                    let next = self.next.replace(deck);
+                    self.retire(next);
+                }
+                MixerCommand::ClearQueue => {
+                    self.cancel_fade();
+                    let next = self.next.take();
+                    self.retire(next);
+                }
+                MixerCommand::SetCrossfade(duration) => {
+                    self.crossfade_frames =
+                        (duration.as_secs_f64() * self.sample_rate as f64) as u64;
+                }
+                MixerCommand::Calibrate(on) => {
+                    self.calibration = on.then_some(0);
+                    self.status.calibration_frame.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// A heuristic for the output's buffer size. The output takes frames in bursts, one
+    /// buffer's worth at a time, so a pause between checks is taken as a new buffer and the
+    /// frames since the last one as its size. Counted in COMMAND_CHECK_INTERVALs, so buffers
+    /// smaller than that come out as one, and a callback that's quick to come back is missed.
+    fn measure_buffer(&mut self) {
+        let now = Instant::now();
+        let new_buffer = self
+            .last_check
+            .map_or(false, |last| now.duration_since(last) > CALLBACK_GAP);
+        self.last_check = Some(now);
+        if new_buffer && self.frames_since_callback > 0 {
+            self.status.record_buffer(self.frames_since_callback);
+            self.frames_since_callback = 0;
+        }
+        self.frames_since_callback += COMMAND_CHECK_INTERVAL;
+    }
+
+    /// stop a fade half way, the current track goes back to full volume
+    fn cancel_fade(&mut self) {
+        if self.fade.take().is_some() {
+            if let Some(next) = self.next.as_mut() {
+                let _ = next.source.try_seek(Duration::ZERO);
+            }
+            self.status.set_audible(self.current.as_ref());
+        }
+    }
+
+    /// start crossfading once the current track is within the crossfade of its end
+    fn maybe_start_fade(&mut self) {
+        if self.fade.is_some() || self.crossfade_frames == 0 || self.next.is_none() {
+            return;
+        }
+        let remaining = self.current.as_ref().and_then(Deck::remaining_frames);
+        if let Some(remaining) = remaining.filter(|&r| r <= self.crossfade_frames) {
+            debug!("starting a {} frame crossfade", remaining);
+            // fade over whatever is left, so both tracks reach the end of the fade together
+            self.fade = Some((0, remaining.max(1)));
+        }
+    }
+
+    /// the queued track takes over
+    fn switch_to_next(&mut self) {
+        self.fade = None;
+        let finished = std::mem::replace(&mut self.current, self.next.take());
+        self.retire(finished);
+        self.status.set_audible(self.current.as_ref());
+        if self.current.is_some() {
+            info!("switched to the next track");
+        }
+    }
+
+    /// mix the next output frame into `self.frame`
+    fn mix_frame(&mut self) {
+        if self.frames_until_check == 0 {
+            self.handle_commands();
+            self.measure_buffer();
+            self.frames_until_check = COMMAND_CHECK_INTERVAL;
+        }
+        self.frames_until_check -= 1;
+
+        if let Some(click_frame) = self.calibration.as_mut() {
+            let sample = calibration::click_sample(*click_frame, self.sample_rate);
+            self.frame.iter_mut().for_each(|s| *s = sample);
+            *click_frame += 1;
+            self.status
+                .calibration_frame
+                .store(*click_frame, Ordering::Relaxed);
+            return;
+        }
+        self.maybe_start_fade();
+
+        let Some((fade_pos, fade_len)) = self.fade else {
+            let played = match self.current.as_mut() {
+                Some(current) => current.read_frame(&mut self.frame),
+                None => false,
+            };
+            if !played {
+                // gapless: the next track's first frame follows the current track's last one
+                self.switch_to_next();
+                let played = match self.current.as_mut() {
+                    Some(current) => current.read_frame(&mut self.frame),
+                    None => false,
+                };
+                if !played {
+                    let finished = self.current.take();
+                    self.retire(finished);
+                    self.status.set_audible(None);
+                    self.frame.iter_mut().for_each(|s| *s = 0.0);
+                }
+            }
+            if let Some(current) = &self.current {
+                self.status
+                    .audible_frame
+                    .store(current.frames_played(), Ordering::Relaxed);
+            }
+            return;
+        };
+
+        // equal-power: the gains' squares add up to 1, so the loudness stays level
+        let t = fade_pos as f32 / fade_len as f32;
+        let (gain_out, gain_in) = ((t * FRAC_PI_2).cos(), (t * FRAC_PI_2).sin());
+        let current_played = match self.current.as_mut() {
+            Some(current) => current.read_frame(&mut self.frame),
+            None => false,
+        };
+        if !current_played {
+            self.frame.iter_mut().for_each(|s| *s = 0.0);
+        }
+        let next_played = match self.next.as_mut() {
+            Some(next) => next.read_frame(&mut self.next_frame),
+            None => false,
+        };
+        if !next_played {
+            self.next_frame.iter_mut().for_each(|s| *s = 0.0);
+        }
+        for (out, incoming) in self.frame.iter_mut().zip(&self.next_frame) {
+            *out = *out * gain_out + incoming * gain_in;
+        }
+
+        let fade_pos = fade_pos + 1;
+        if fade_pos == fade_len / 2 {
+            // past half way the new track is the louder one, that's where the visuals switch
+            self.status.set_audible(self.next.as_ref());
+        }
+        if fade_pos >= fade_len || !current_played {
+            self.switch_to_next();
+        } else {
+            self.fade = Some((fade_pos, fade_len));
+            let audible = if fade_pos >= fade_len / 2 {
+                self.next.as_ref()
+            } else {
+                self.current.as_ref()
+            };
+            if let Some(audible) = audible {
+                self.status
+                    .audible_frame
+                    .store(audible.frames_played(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Iterator for Mixer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.frame_pos >= self.frame.len() {
+            self.mix_frame();
+            self.frame_pos = 0;
+        }
+        let sample = self.frame[self.frame_pos];
+        self.frame_pos += 1;
+        Some(sample)
+    }
+}
+
+impl Source for Mixer {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Seek within the audible track. A crossfade in progress is finished (past half way)
+    /// or cancelled, so the seek lands on the track the visuals are showing.
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        // a track sent just before the seek should be the one that seeks
+        self.handle_commands();
+        if let Some((fade_pos, fade_len)) = self.fade {
+            if fade_pos >= fade_len / 2 {
+                self.switch_to_next();
+            } else {
+                self.cancel_fade();
+            }
+        }
+        let Some(current) = self.current.as_mut() else {
+            return Ok(());
+        };
+        current.source.try_seek(pos)?;
+        self.frame_pos = self.frame.len();
+        self.status.set_audible(self.current.as_ref());
+        Ok(())
+    }
+}
@@ -1,5 +1,10 @@
+use crate::live_input::InputLevel;
+use crate::metadata::{self, TrackInfo};
+use crate::playlist::PlaybackMode;
 use crate::render_drawing::{DrawConfig, DrawVisual};
+use crate::volume::VolumeStatus;
 use nannou::prelude::*;
+use std::time::Duration;
 
 pub const PADDING: f32 = 50.;
 pub const BUTTON_W: f32 = 50.;
@@ -34,6 +39,10 @@ pub struct SeekLine {
     pub bbox: BBox,
     pub button: Button,
     width: f32,
+    /// bookmark positions, 0.0 to 1.0
+    ticks: Vec<f32>,
+    /// the A-B loop's start and end, 0.0 to 1.0. Both the same while only A is set.
+    loop_region: Option<(f32, f32)>,
 }
 
 #[derive(Debug)]
@@ -42,6 +51,9 @@ pub enum UIElem {
     SeekLine(SeekLine),
     SongInfo(SongInfo),
     TimeStamp(TimeStamp),
+    PlaybackModeInfo(PlaybackModeInfo),
+    VolumeInfo(VolumeInfo),
+    LevelMeter(LevelMeter),
 }
 
 impl DrawVisual for UIElem {
@@ -49,26 +61,276 @@ impl DrawVisual for UIElem {
         match self {
             UIElem::Button(button) => button.draw_visual(draw, win, config),
             UIElem::SeekLine(seekline) => seekline.draw_visual(draw, win, config),
-            UIElem::SongInfo(songinfo) => {}
-            UIElem::TimeStamp(timestamp) => {}
+            UIElem::SongInfo(songinfo) => songinfo.draw_visual(draw, win, config),
+            UIElem::TimeStamp(timestamp) => timestamp.draw_visual(draw, win, config),
+            UIElem::PlaybackModeInfo(mode_info) => mode_info.draw_visual(draw, win, config),
+            UIElem::VolumeInfo(volume_info) => volume_info.draw_visual(draw, win, config),
+            UIElem::LevelMeter(meter) => meter.draw_visual(draw, win, config),
         }
     }
     fn scale_visual(&mut self, win: Rect) {}
 }
 
 #[derive(Debug)]
-struct TimeStamp {
+pub struct TimeStamp {
+    pub bbox: BBox,
     pub time_elapsed: String,
     pub total_time: String,
 }
 
+/// shuffle / repeat state, bottom left above the seekline
+#[derive(Debug)]
+pub struct PlaybackModeInfo {
+    pub bbox: BBox,
+    pub text: String,
+}
+
+/// effective volume and sleep timer, bottom right above the time stamp
+#[derive(Debug)]
+pub struct VolumeInfo {
+    pub bbox: BBox,
+    pub text: String,
+}
+
+/// input level in live mode, a vertical bar on the right edge
+#[derive(Debug)]
+pub struct LevelMeter {
+    pub bbox: BBox,
+    /// None outside live mode, nothing is drawn then
+    level: Option<InputLevel>,
+}
+
 #[derive(Debug)]
-struct SongInfo {
+pub struct SongInfo {
+    pub bbox: BBox,
     pub title: String,
     pub artist: String,
     pub album: String,
     pub year: String,
     pub genre: String,
+    /// codec, sample rate etc. in one line
+    pub technical: String,
+    /// where the track is in the playlist
+    pub queue_position: String,
+}
+
+impl TimeStamp {
+    /// elapsed / total overlay, right above the right end of the seekline
+    pub fn new(win: Rect) -> Self {
+        let bbox = BBox::new(0., 0., 120., 20.)
+            .to_bottom_right(win)
+            .translate(-120., BUTTON_W + PADDING + 15.);
+        TimeStamp {
+            bbox,
+            time_elapsed: metadata::format_duration(Duration::ZERO),
+            total_time: metadata::format_duration(Duration::ZERO),
+        }
+    }
+
+    pub fn set(&mut self, elapsed: Duration, total: Duration) {
+        self.time_elapsed = metadata::format_duration(elapsed.min(total));
+        self.total_time = metadata::format_duration(total);
+    }
+}
+
+impl DrawVisual for TimeStamp {
+    fn draw_visual(&self, draw: &Draw, _win: Rect, config: &DrawConfig) {
+        let (x, y, w, h) = self.bbox.for_draw_visual().to_tuple();
+        draw.text(&format!("{} / {}", self.time_elapsed, self.total_time))
+            .x_y(x, y)
+            .w_h(w, h)
+            .right_justify()
+            .font_size(16)
+            .color(config.ui_colors().text);
+    }
+    fn scale_visual(&mut self, _win: Rect) {}
+}
+
+impl PlaybackModeInfo {
+    pub fn new(win: Rect) -> Self {
+        let bbox = BBox::new(0., 0., 240., 20.)
+            .to_bottom_left(win)
+            .translate(0., BUTTON_W + PADDING + 15.);
+        PlaybackModeInfo {
+            bbox,
+            text: PlaybackMode::default().to_string(),
+        }
+    }
+
+    pub fn set(&mut self, mode: PlaybackMode) {
+        self.text = mode.to_string();
+    }
+}
+
+impl DrawVisual for PlaybackModeInfo {
+    fn draw_visual(&self, draw: &Draw, _win: Rect, config: &DrawConfig) {
+        let (x, y, w, h) = self.bbox.for_draw_visual().to_tuple();
+        draw.text(&self.text)
+            .x_y(x, y)
+            .w_h(w, h)
+            .left_justify()
+            .font_size(16)
+            .color(config.ui_colors().text);
+    }
+    fn scale_visual(&mut self, _win: Rect) {}
+}
+
+impl VolumeInfo {
+    pub fn new(win: Rect) -> Self {
+        let bbox = BBox::new(0., 0., 240., 20.)
+            .to_bottom_right(win)
+            .translate(-240., BUTTON_W + PADDING + 40.);
+        VolumeInfo {
+            bbox,
+            text: String::new(),
+        }
+    }
+
+    pub fn set(&mut self, status: &VolumeStatus) {
+        self.text = status.to_string();
+    }
+}
+
+impl DrawVisual for VolumeInfo {
+    fn draw_visual(&self, draw: &Draw, _win: Rect, config: &DrawConfig) {
+        let (x, y, w, h) = self.bbox.for_draw_visual().to_tuple();
+        draw.text(&self.text)
+            .x_y(x, y)
+            .w_h(w, h)
+            .right_justify()
+            .font_size(16)
+            .color(config.ui_colors().text);
+    }
+    fn scale_visual(&mut self, _win: Rect) {}
+}
+
+/// the quietest level the meter shows, in dBFS
+const METER_FLOOR_DB: f32 = -60.;
+
+impl LevelMeter {
+    pub fn new(win: Rect) -> Self {
+        let height = win.h() / 3.;
+        let bbox = BBox::new(0., 0., 12., height)
+            .to_bottom_right(win)
+            .translate(0., (win.h() - height) / 2. - PADDING);
+        LevelMeter { bbox, level: None }
+    }
+
+    pub fn set(&mut self, level: Option<InputLevel>) {
+        self.level = level;
+    }
+
+    /// how full the meter is for this linear level, 0.0 at METER_FLOOR_DB to 1.0 at full scale
+    fn fill(level: f32) -> f32 {
+        let db = 20. * level.max(1e-6).log10();
+        (1. - db / METER_FLOOR_DB).clamp(0., 1.)
+    }
+}
+
+impl DrawVisual for LevelMeter {
+    fn draw_visual(&self, draw: &Draw, _win: Rect, config: &DrawConfig) {
+        let Some(level) = self.level else {
+            return;
+        };
+        let (x, y, w, h) = self.bbox.for_draw_visual().to_tuple();
+        let bottom = y - h / 2.;
+        draw.rect()
+            .x_y(x, y)
+            .w_h(w, h)
+            .no_fill()
+            .stroke_weight(1.)
+            .stroke(config.ui_colors().text);
+        // RMS as the bar, peak as a line above it
+        let rms = Self::fill(level.rms) * h;
+        draw.rect()
+            .x_y(x, bottom + rms / 2.)
+            .w_h(w, rms)
+            .color(config.ui_colors().level_meter);
+        draw.rect()
+            .x_y(x, bottom + Self::fill(level.peak) * h)
+            .w_h(w, 2.)
+            .color(config.ui_colors().level_meter);
+    }
+    fn scale_visual(&mut self, _win: Rect) {}
+}
+
+impl SongInfo {
+    /// empty song info in the top left corner, filled in by `set()` once a track loads
+    pub fn new(win: Rect) -> Self {
+        let bbox = BBox::new(0., 0., win.w() / 2., 100.)
+            .to_top_left(win)
+            .translate(0., -100.);
+        SongInfo {
+            bbox,
+            title: String::new(),
+            artist: String::new(),
+            album: String::new(),
+            year: String::new(),
+            genre: String::new(),
+            technical: String::new(),
+            queue_position: String::new(),
+        }
+    }
+
+    /// "track 3 / 12", `index` is 0 based
+    pub fn set_queue_position(&mut self, index: usize, len: usize) {
+        self.queue_position = format!("track {} / {}", index + 1, len);
+    }
+
+    pub fn set(&mut self, info: &TrackInfo) {
+        self.title = info.display_title();
+        self.artist = info.artist.clone().unwrap_or_default();
+        self.album = info.album.clone().unwrap_or_default();
+        self.year = info.year.map(|y| y.to_string()).unwrap_or_default();
+        self.genre = info.genre.clone().unwrap_or_default();
+
+        let mut technical = vec![info.codec.clone()];
+        if let Some(sample_rate) = info.sample_rate {
+            technical.push(format!("{:.1} kHz", sample_rate as f32 / 1000.));
+        }
+        if let Some(bit_depth) = info.bit_depth {
+            technical.push(format!("{} bit", bit_depth));
+        }
+        if let Some(channels) = info.channels {
+            technical.push(format!("{} ch", channels));
+        }
+        if let Some(bitrate) = info.bitrate {
+            technical.push(format!("{} kbps", bitrate));
+        }
+        self.technical = technical.join(" · ");
+    }
+}
+
+impl DrawVisual for SongInfo {
+    fn draw_visual(&self, draw: &Draw, _win: Rect, config: &DrawConfig) {
+        let (x, y, w, h) = self.bbox.for_draw_visual().to_tuple();
+        // skip the empty fields so the lines don't have gaps
+        let album_line = [&self.album, &self.year, &self.genre]
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_str())
+            .collect::<Vec<&str>>()
+            .join(" · ");
+        let lines: Vec<&str> = [
+            self.title.as_str(),
+            self.artist.as_str(),
+            album_line.as_str(),
+            self.technical.as_str(),
+            self.queue_position.as_str(),
+        ]
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect();
+
+        draw.text(&lines.join("\n"))
+            .x_y(x, y)
+            .w_h(w, h)
+            .left_justify()
+            .align_text_top()
+            .font_size(16)
+            .color(config.ui_colors().text);
+    }
+    fn scale_visual(&mut self, _win: Rect) {}
 }
 
 impl SeekLine {
@@ -88,13 +350,26 @@ impl SeekLine {
             bbox: line_bbox,
             button: Button::new(ButtonName::Play, scroller_bbox, || {}),
             width: line_width,
+            ticks: vec![],
+            loop_region: None,
         }
     }
 
+    /// show the A-B loop between these positions (0. to 1.)
+    pub fn set_loop(&mut self, region: Option<(f32, f32)>) {
+        self.loop_region = region.map(|(start, end)| (start.clamp(0., 1.), end.clamp(0., 1.)));
+    }
+
+    /// mark bookmarks at these positions (0. to 1.)
+    pub fn set_bookmarks(&mut self, positions: &[f32]) {
+        self.ticks.clear();
+        self.ticks.extend(positions.iter().map(|p| p.clamp(0., 1.)));
+    }
+
     /// set the position of scroller based on playback_pos (0. to 1.) 
     pub fn set_scroller_pos(&mut self, playback_pos: f32) {
       // set x position of scroller, based on start position of seekline and playback_pos
-        self.button.bbox.0 = self.bbox.0 + playback_pos * self.width;
+        self.button.bbox.0 = self.bbox.0 + playback_pos.clamp(0., 1.) * self.width;
     }
 
     /// get the playback position (0. to 1.) based on mouse x
@@ -114,7 +389,29 @@ impl DrawVisual for SeekLine {
     ) {
         let (x, y, w, h) = self.bbox.for_draw_visual().to_tuple();
 
-        draw.rect().x_y(x, y).w_h(w, h).color(BLUE);
+        // behind the bar and taller, so it frames the looped part
+        if let Some((start, end)) = self.loop_region {
+            let (x_start, x_end) = (
+                self.bbox.0 + start * self.width,
+                self.bbox.0 + end * self.width,
+            );
+            draw.rect()
+                .x_y((x_start + x_end) / 2., y)
+                .w_h((x_end - x_start).max(2.), h * 2.5)
+                .color(config.ui_colors().loop_region);
+        }
+
+        draw.rect()
+            .x_y(x, y)
+            .w_h(w, h)
+            .color(config.ui_colors().seek_bar);
+
+        for tick in &self.ticks {
+            draw.rect()
+                .x_y(self.bbox.0 + tick * self.width, y)
+                .w_h(2., h * 2.)
+                .color(config.ui_colors().bookmark);
+        }
 
         self.button.draw_visual(draw, win, config);
     }
@@ -157,6 +454,15 @@ impl BBox {
         Self(x, y, w, h)
     }
 
+    /// set x,y to the top left of the window
+    /// x,y is the bottom left of the box, so translate down by its height to fit it in the window
+    pub fn to_top_left(&self, win: Rect) -> Self {
+        let (_, _, w, h) = self.to_tuple();
+        let x = -win.w() / 2. + PADDING;
+        let y = win.h() / 2. - PADDING;
+        Self(x, y, w, h)
+    }
+
     /// set x,y to bottom center of the window
     pub fn to_bottom_center(&self, win: Rect) -> Self {
         let (_, _, w, h) = self.to_tuple();
@@ -191,13 +497,22 @@ impl DrawVisual for Button {
 
         match self.button_name {
             ButtonName::Play => {
-                draw.rect().x_y(x, y).w_h(w, w).color(RED);
+                draw.rect()
+                    .x_y(x, y)
+                    .w_h(w, w)
+                    .color(config.ui_colors().play_button);
             }
             ButtonName::FavPlay => {
-                draw.rect().x_y(x, y).w_h(w, w).color(RED);
+                draw.rect()
+                    .x_y(x, y)
+                    .w_h(w, w)
+                    .color(config.ui_colors().fav_play_button);
             }
             ButtonName::FavRecord => {
-                draw.rect().x_y(x, y).w_h(w, w).color(BLUE);
+                draw.rect()
+                    .x_y(x, y)
+                    .w_h(w, w)
+                    .color(config.ui_colors().fav_record_button);
             }
             ButtonName::Seek => {
                 let (x, y, w, h) = self.bbox.to_tuple();
@@ -282,11 +597,21 @@ pub fn create_ui_elements(win: Rect) -> Vec<UIElem> {
     );
 
     let seekline = SeekLine::new(win);
+    let song_info = SongInfo::new(win);
+    let time_stamp = TimeStamp::new(win);
+    let mode_info = PlaybackModeInfo::new(win);
+    let volume_info = VolumeInfo::new(win);
+    let level_meter = LevelMeter::new(win);
 
     vec![
         UIElem::Button(play_button),
         UIElem::Button(fav_record),
         UIElem::Button(fav_play),
         UIElem::SeekLine(seekline),
+        UIElem::SongInfo(song_info),
+        UIElem::TimeStamp(time_stamp),
+        UIElem::PlaybackModeInfo(mode_info),
+        UIElem::VolumeInfo(volume_info),
+        UIElem::LevelMeter(level_meter),
     ]
 }
\ No newline at end of file
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::metadata;
+
+/// How long the sleep timer fades out for before it pauses
+pub const SLEEP_FADE: Duration = Duration::from_secs(30);
+
+/// Sleep timer lengths the T key steps through
+pub const SLEEP_STEPS: [Duration; 4] = [
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(30 * 60),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(90 * 60),
+];
+
+/// Playback gain: the volume, mute and any fade in progress. Owned by the audio thread,
+/// which gives `effective()` to the sink, so the decoded samples (and the analysis) never
+/// see it.
+#[derive(Debug)]
+pub struct Gain {
+    /// 0.0 - 1.0, set by the user
+    volume: f32,
+    muted: bool,
+    /// how far faded in, 0.0 - 1.0, multiplies the volume
+    fade_level: f32,
+    fade: Option<Fade>,
+    /// when the sleep timer pauses playback, and the length it was set to
+    sleep: Option<(Instant, Duration)>,
+}
+
+#[derive(Debug)]
+struct Fade {
+    from: f32,
+    to: f32,
+    start: Instant,
+    length: Duration,
+    /// the sleep timer's fade, pauses when it's done
+    then_pause: bool,
+}
+
+impl Fade {
+    /// the fade level at `now`, and whether the fade is over
+    fn level_at(&self, now: Instant) -> (f32, bool) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.length {
+            return (self.to, true);
+        }
+        let t = elapsed.as_secs_f32() / self.length.as_secs_f32();
+        (self.from + (self.to - self.from) * t, false)
+    }
+}
+
+impl Gain {
+    pub fn new(volume: f32) -> Self {
+        Gain {
+            volume: volume.clamp(0.0, 1.0),
+            muted: false,
+            fade_level: 1.0,
+            fade: None,
+            sleep: None,
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    /// muting keeps the volume and any fade going, unmuting picks up where they are
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// fade from silence up to the volume
+    pub fn fade_in(&mut self, length: Duration, now: Instant) {
+        self.fade_level = 0.0;
+        self.start_fade(1.0, length, false, now);
+    }
+
+    /// fade from where it is down to silence, and stay there until a fade in or play
+    pub fn fade_out(&mut self, length: Duration, now: Instant) {
+        self.start_fade(0.0, length, false, now);
+    }
+
+    /// Pause after `after`, fading out over the last SLEEP_FADE of it. None cancels the timer
+    /// (and its fade, if it had started).
+    pub fn set_sleep_timer(&mut self, after: Option<Duration>, now: Instant) {
+        self.sleep = after.map(|after| (now + after, after));
+        if self.fade.as_ref().map_or(false, |fade| fade.then_pause) {
+            self.start_fade(1.0, Duration::ZERO, false, now);
+        }
+    }
+
+    /// back to full level, eg. when playback resumes after the sleep timer
+    pub fn reset_fade(&mut self) {
+        self.fade = None;
+        self.fade_level = 1.0;
+    }
+
+    /// Move the fades on to `now`. Returns true when the sleep timer says to pause.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        if let Some((sleep_at, _)) = self.sleep {
+            let sleep_fading = self.fade.as_ref().map_or(false, |fade| fade.then_pause);
+            if !sleep_fading && now + SLEEP_FADE >= sleep_at {
+                // fade over whatever is left, so it's silent right when the timer runs out
+                self.start_fade(0.0, sleep_at.saturating_duration_since(now), true, now);
+            }
+        }
+        let Some(fade) = &self.fade else {
+            return false;
+        };
+        let (level, done) = fade.level_at(now);
+        self.fade_level = level;
+        if !done {
+            return false;
+        }
+        let then_pause = fade.then_pause;
+        self.fade = None;
+        if then_pause {
+            self.sleep = None;
+        }
+        then_pause
+    }
+
+    /// what the sink should play at
+    pub fn effective(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume * self.fade_level
+        }
+    }
+
+    pub fn status(&self, now: Instant) -> VolumeStatus {
+        VolumeStatus {
+            volume: self.volume,
+            effective: self.effective(),
+            muted: self.muted,
+            sleep_timer: self.sleep.map(|(_, length)| length),
+            sleep_remaining: self
+                .sleep
+                .map(|(sleep_at, _)| sleep_at.saturating_duration_since(now)),
+            asleep: false,
+        }
+    }
+
+    fn start_fade(&mut self, to: f32, length: Duration, then_pause: bool, now: Instant) {
+        self.fade = Some(Fade {
+            from: self.fade_level,
+            to,
+            start: now,
+            length,
+            then_pause,
+        });
+    }
+}
+
+/// The gain as the UI shows it, published by the audio thread
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VolumeStatus {
+    pub volume: f32,
+    /// what's actually playing, with mute and fades applied
+    pub effective: f32,
+    pub muted: bool,
+    /// what the sleep timer was set to
+    pub sleep_timer: Option<Duration>,
+    /// time left on the sleep timer
+    pub sleep_remaining: Option<Duration>,
+    /// set when the sleep timer has paused playback, cleared by the UI once it's seen it
+    pub asleep: bool,
+}
+
+impl VolumeStatus {
+    /// the sleep timer length after this one in SLEEP_STEPS, None after the longest
+    pub fn next_sleep_timer(&self) -> Option<Duration> {
+        match self.sleep_timer {
+            None => SLEEP_STEPS.first().copied(),
+            Some(length) => SLEEP_STEPS.iter().copied().find(|&step| step > length),
+        }
+    }
+}
+
+impl fmt::Display for VolumeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.muted {
+            write!(f, "muted ({:.0}%)", self.volume * 100.0)?;
+        } else {
+            write!(f, "volume {:.0}%", self.effective * 100.0)?;
+        }
+        if let Some(remaining) = self.sleep_remaining {
+            write!(f, " · sleep in {}", metadata::format_duration(remaining))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//! Drives the audio thread through its commands, playing to the null output as fast as the
+//! mixer goes, over short generated tracks.
+
+use audio_vis::audio_thread::{create_audio_thread, AudioManager, AudioOptions, Command};
+use audio_vis::config::AnalysisSettings;
+use audio_vis::null_output::NullSpeed;
+use audio_vis::playlist_file::PlaylistEntry;
+use std::f32::consts::TAU;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SAMPLE_RATE: u32 = 8_000;
+/// how long to wait for the audio thread before failing
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A mono 16 bit WAV of a half scale sine
+fn write_sine(path: &Path, frequency: f32, seconds: f32) {
+    let frames = (seconds * SAMPLE_RATE as f32) as u32;
+    let data_len = frames * 2;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, mono
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for i in 0..frames {
+        let t = i as f32 / SAMPLE_RATE as f32;
+        let sample = 0.5 * (TAU * frequency * t).sin();
+        wav.extend_from_slice(&((sample * i16::MAX as f32) as i16).to_le_bytes());
+    }
+    fs::write(path, wav).unwrap();
+}
+
+fn start(tracks: &[PathBuf]) -> AudioManager {
+    create_audio_thread(AudioOptions {
+        tracks: tracks.iter().cloned().map(PlaylistEntry::new).collect(),
+        analysis: AnalysisSettings::default(),
+        start_at: None,
+        volume: 1.0,
+        seed: Some(0),
+        exit_at_end: true,
+        live: false,
+        null_output: Some(NullSpeed::Fast),
+        output_device: None,
+        input_device: None,
+    })
+}
+
+/// Poll until `done` is true, failing with `what` after TIMEOUT
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// index of the track the audio thread last published
+fn published_track(manager: &AudioManager) -> Option<usize> {
+    manager
+        .track_info
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|(index, _)| *index)
+}
+
+#[test]
+fn seek_analyse_and_advance() {
+    let dir = tempfile::tempdir().unwrap();
+    let tracks = [dir.path().join("a.wav"), dir.path().join("b.wav")];
+    write_sine(&tracks[0], 440.0, 30.0);
+    write_sine(&tracks[1], 660.0, 1.0);
+    let manager = start(&tracks);
+    let send = |command| manager.sender_to_audio.send(command).unwrap();
+
+    wait_for("the first track", || {
+        published_track(&manager) == Some(0) && manager.clock.audible_id().is_some()
+    });
+    let first_id = manager.clock.audible_id();
+
+    // a loop around the seek keeps the position there, however fast the output goes
+    let (loop_start, loop_end) = (Duration::from_secs(20), Duration::from_secs(21));
+    send(Command::SetLoop(Some((loop_start, loop_end))));
+    send(Command::Seek(Duration::from_millis(20_500)));
+    wait_for("the seek", || {
+        (loop_start..loop_end).contains(&manager.clock.position())
+    });
+    for _ in 0..50 {
+        let position = manager.clock.position();
+        assert!(
+            (loop_start..loop_end).contains(&position),
+            "position {:?} outside the loop after seeking into it",
+            position
+        );
+        assert_eq!(manager.clock.audible_id(), first_id);
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // the first track is a 440Hz sine, so that's the note that shows up
+    send(Command::CalculateFFT);
+    wait_for("the FFT", || {
+        manager
+            .fft_output
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|pair| pair.mag > 0.0)
+    });
+    let fft_output = manager.fft_output.lock().unwrap().clone();
+    let loudest = fft_output
+        .iter()
+        .max_by(|a, b| a.mag.total_cmp(&b.mag))
+        .unwrap();
+    assert_eq!(loudest.freq, 440.0);
+    assert!(loudest.mag > 0.4, "magnitude {}", loudest.mag);
+
+    send(Command::SetLoop(None));
+    wait_for("the next track", || {
+        published_track(&manager) == Some(1) && manager.clock.audible_id() != first_id
+    });
+
+    // the audio thread stops at the end of the playlist, closing the error channel
+    let errors: Vec<_> = manager.errors_from_audio.iter().collect();
+    assert!(errors.is_empty(), "{:?}", errors);
+}